@@ -0,0 +1,84 @@
+//! Times text mesh generation with an empty glyph cache, so every glyph is tessellated again.
+//!
+//! `cargo bench --bench tessellation`, with `--features rayon` it also reports how tessellation
+//! scales with the number of threads.
+
+use std::time::{Duration, Instant};
+use textrenderingstuff::{Font, FontSize, Span};
+
+const FONT_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fonts/NotoSansJP-Regular.ttf");
+const VIEWPORT: (u32, u32) = (1920, 1080);
+const SAMPLES: usize = 10;
+
+/// Median time of `SAMPLES` calls of `run`, after one call to warm up
+fn median(mut run: impl FnMut()) -> Duration {
+    run();
+    let mut times = (0..SAMPLES)
+        .map(|_| {
+            let start = Instant::now();
+            run();
+            start.elapsed()
+        })
+        .collect::<Vec<_>>();
+    times.sort_unstable();
+    times[SAMPLES / 2]
+}
+
+/// `lines` lines repeating `alphabet`
+fn document(alphabet: &str, lines: usize) -> String {
+    (0..lines).map(|line| format!("{line}: {alphabet}\n")).collect()
+}
+
+fn bench(name: &str, span: &Span) {
+    let triangles = span.generate_text_mesh(0, VIEWPORT).indices.len() / 3;
+    let time = median(|| {
+        span.generate_text_mesh(0, VIEWPORT);
+    });
+    println!("{name:<40} {triangles:>9} triangles {time:>12.2?}");
+}
+
+fn main() {
+    let font = Font::from_bytes(std::fs::read(FONT_PATH).unwrap()).unwrap();
+    let face = font.face();
+    // Latin glyphs have a few contours with some curves, kanji many contours made of short strokes
+    let latin = (0x21u8..0x7f).chain(0xa1..=0xff).map(char::from).collect::<String>();
+    let kanji = ('\u{4e00}'..='\u{9fff}')
+        .filter(|c| face.glyph_index(*c).is_some())
+        .take(200)
+        .collect::<String>();
+
+    for (complexity, alphabet) in [("latin", &latin), ("kanji", &kanji)] {
+        for lines in [10, 200] {
+            let text = document(alphabet, lines);
+            // Larger sizes subdivide curves more finely
+            for font_size in [FontSize::Pt(12), FontSize::Px(64), FontSize::Px(256)] {
+                let span = Span::new(face, &text, 0, 0).with_font_size(font_size).with_max_glyphs(usize::MAX);
+                bench(&format!("{complexity} {lines} lines {font_size:?}"), &span);
+            }
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    {
+        // Distinct glyphs are tessellated in parallel, the rest of mesh generation stays serial
+        let text = document(&kanji, 200);
+        let span = Span::new(face, &text, 0, 0).with_font_size(FontSize::Px(64)).with_max_glyphs(usize::MAX);
+        let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+        let mut counts = vec![1];
+        while counts.last().unwrap() * 2 <= threads {
+            counts.push(counts.last().unwrap() * 2);
+        }
+        if *counts.last().unwrap() != threads {
+            counts.push(threads);
+        }
+        let mut serial = None;
+        for count in counts {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(count).build().unwrap();
+            let time = pool.install(|| median(|| {
+                span.generate_text_mesh(0, VIEWPORT);
+            }));
+            let serial = *serial.get_or_insert(time);
+            println!("kanji 200 lines on {count:>3} threads {time:>12.2?} {:>6.2}x", serial.as_secs_f64() / time.as_secs_f64());
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use crate::mesh::TextMesh;
+use crate::renderer::{AAMode, TextureRenderer};
+use crate::text::Span;
+
+/// Text with its layout and tessellation already done.
+///
+/// Shaping and triangulating a span is by far the most expensive part of a render, so text that
+/// rarely changes can be laid out once and then queued into any number of renders cheaply. Call
+/// [`TextBlock::update`] only when the text or its style actually changed.
+#[derive(Clone, Debug)]
+pub struct TextBlock {
+    mesh: TextMesh,
+    colors: Vec<[f32; 4]>,
+    aa_mode: Option<AAMode>,
+}
+
+impl TextBlock {
+    /// Lays out `span` for a target of `viewport` pixels, the block has to be rendered into a
+    /// renderer of that size.
+    pub fn new(span: &Span, viewport: (u32, u32)) -> Self {
+        Self {
+            mesh: span.generate_text_mesh(0, viewport),
+            colors: span.palette(),
+            aa_mode: span.get_aa_mode(),
+        }
+    }
+
+    /// Re-runs layout and tessellation for `span`.
+    pub fn update(&mut self, span: &Span, viewport: (u32, u32)) {
+        *self = Self::new(span, viewport);
+    }
+
+    pub fn mesh(&self) -> &TextMesh {
+        &self.mesh
+    }
+
+    /// Queues the cached mesh into `renderer`, without shaping or tessellating again.
+    pub fn render(&self, renderer: &mut TextureRenderer) {
+        renderer.add_mesh(self.mesh.clone(), self.colors.clone(), self.aa_mode);
+    }
+}
@@ -0,0 +1,574 @@
+//! COLR color glyphs, both the version 0 layers and the version 1 paint graph, resolved into flat
+//! layers that each fill one glyph outline with a solid color or a gradient.
+//!
+//! ttf-parser 0.20 only reads version 0 tables and rejects the whole table once it is version 1,
+//! so both are parsed here.
+
+use std::f32::consts::PI;
+use log::warn;
+use ttf_parser::GlyphId;
+use crate::mesh::Transform;
+
+/// Palette index standing for the text color
+pub(crate) const FOREGROUND: u16 = 0xFFFF;
+/// Number of palette entries a gradient's colors are sampled into, evenly spaced over its color line
+pub(crate) const RAMP_LEN: usize = 32;
+/// Bits of a vertex's `metadata` holding its gradient, see [`Gradient::metadata`]
+pub(crate) const GRADIENT_BITS: i32 = 0b11111 << 4;
+/// Limit on nested paints, fonts can contain cycles
+const MAX_DEPTH: usize = 64;
+/// Limit on the layers of one glyph
+const MAX_LAYERS: usize = 1024;
+
+const IDENTITY: Transform = ([[1.0, 0.0], [0.0, 1.0]], (0.0, 0.0));
+
+/// Color of a palette entry, with its alpha multiplied by `alpha`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct ColorRef {
+    pub palette_index: u16,
+    pub alpha: f32,
+}
+
+/// How a gradient continues before its first and after its last color stop
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Extend {
+    Pad,
+    Repeat,
+    Reflect,
+}
+
+/// Color stops of a gradient, sorted by their offset
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ColorLine {
+    pub extend: Extend,
+    pub stops: Vec<(f32, ColorRef)>,
+}
+
+impl ColorLine {
+    /// Offsets of the first and last stop, apart by at least a tiny bit so they span a range
+    fn range(&self) -> (f32, f32) {
+        let first = self.stops.first().map_or(0.0, |stop| stop.0);
+        let last = self.stops.last().map_or(1.0, |stop| stop.0);
+        (first, last.max(first + 1e-3))
+    }
+
+    /// Colors of the line at [`RAMP_LEN`] evenly spaced offsets from its first to its last stop,
+    /// with the stop colors looked up by `resolve`
+    pub fn ramp(&self, resolve: impl Fn(ColorRef) -> [f32; 4]) -> Vec<[f32; 4]> {
+        let colors = self.stops.iter().map(|(offset, color)| (*offset, resolve(*color))).collect::<Vec<_>>();
+        let (first, last) = self.range();
+        (0..RAMP_LEN)
+            .map(|index| {
+                let offset = first + (last - first) * index as f32 / (RAMP_LEN - 1) as f32;
+                let next = colors.partition_point(|(stop, _)| *stop <= offset);
+                match (colors.get(next.wrapping_sub(1)), colors.get(next)) {
+                    (Some((a, from)), Some((b, to))) => {
+                        let t = (offset - a) / (b - a);
+                        [0, 1, 2, 3].map(|channel| from[channel] + (to[channel] - from[channel]) * t)
+                    }
+                    (Some((_, color)), None) | (None, Some((_, color))) => *color,
+                    (None, None) => [0.0; 4],
+                }
+            })
+            .collect()
+    }
+}
+
+/// What a layer's outline is filled with. Gradient geometry is in the fill's own space, see
+/// [`Layer::fill_transform`].
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Fill {
+    Solid(ColorRef),
+    /// Colors from `p0` at offset 0 to `p1` at offset 1, constant along lines parallel to `p0` to `p2`
+    Linear { p0: (f32, f32), p1: (f32, f32), p2: (f32, f32), line: ColorLine },
+    /// Colors on the circles interpolated from the one around `c0` with radius `r0` at offset 0 to
+    /// the one around `c1` with radius `r1` at offset 1
+    Radial { c0: (f32, f32), r0: f32, c1: (f32, f32), r1: f32, line: ColorLine },
+    /// Colors counter-clockwise around `center`, from the angle `start` at offset 0 to `end` at
+    /// offset 1, in radians
+    Sweep { center: (f32, f32), start: f32, end: f32, line: ColorLine },
+}
+
+/// A glyph outline filled with one paint
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Layer {
+    pub glyph_id: GlyphId,
+    /// Maps the outline into the color glyph's space
+    pub outline_transform: Transform,
+    pub fill: Fill,
+    /// Maps the fill's geometry into the color glyph's space
+    pub fill_transform: Transform,
+}
+
+/// A parsed COLR table
+pub(crate) struct Table<'a> {
+    data: &'a [u8],
+    base_glyph_count: usize,
+    base_glyphs: usize,
+    layers: usize,
+    /// Offsets of the version 1 base glyph and layer lists, 0 if absent
+    base_glyph_list: usize,
+    layer_list: usize,
+}
+
+impl<'a> Table<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        let version = read_u16(data, 0)?;
+        let (base_glyph_list, layer_list) = if version >= 1 {
+            (read_u32(data, 14)? as usize, read_u32(data, 18)? as usize)
+        } else {
+            (0, 0)
+        };
+        Some(Table {
+            data,
+            base_glyph_count: read_u16(data, 2)? as usize,
+            base_glyphs: read_u32(data, 4)? as usize,
+            layers: read_u32(data, 8)? as usize,
+            base_glyph_list,
+            layer_list,
+        })
+    }
+
+    /// The table of `face`, if it has one
+    pub fn of(face: &ttf_parser::Face<'a>) -> Option<Self> {
+        face.raw_face().table(ttf_parser::Tag::from_bytes(b"COLR")).and_then(Table::parse)
+    }
+
+    pub fn contains(&self, glyph_id: GlyphId) -> bool {
+        self.base_paint(glyph_id).is_some() || self.base_layers(glyph_id).is_some()
+    }
+
+    /// Layers of the color glyph `glyph_id`, bottom first. Its version 1 paint graph takes precedence
+    /// over version 0 layers. `None` if it isn't a color glyph or its paints are malformed.
+    ///
+    /// Composite modes other than source over and the trivial ones are drawn as source over, nested
+    /// glyph clips only clip to the innermost glyph, and variable paints use their default values.
+    pub fn layers(&self, glyph_id: GlyphId) -> Option<Vec<Layer>> {
+        let mut layers = vec![];
+        if let Some(paint) = self.base_paint(glyph_id) {
+            self.walk(paint, IDENTITY, None, 0, &mut layers)?;
+            return Some(layers);
+        }
+        let (first, count) = self.base_layers(glyph_id)?;
+        for index in first..first + count {
+            let record = self.layers + index * 4;
+            layers.push(Layer {
+                glyph_id: GlyphId(read_u16(self.data, record)?),
+                outline_transform: IDENTITY,
+                fill: Fill::Solid(ColorRef { palette_index: read_u16(self.data, record + 2)?, alpha: 1.0 }),
+                fill_transform: IDENTITY,
+            });
+        }
+        Some(layers)
+    }
+
+    /// First layer record and layer count of a version 0 base glyph
+    fn base_layers(&self, glyph_id: GlyphId) -> Option<(usize, usize)> {
+        let record = self.find(self.base_glyphs, self.base_glyph_count, glyph_id)?;
+        Some((read_u16(self.data, record + 2)? as usize, read_u16(self.data, record + 4)? as usize))
+    }
+
+    /// Offset of the root paint of a version 1 base glyph
+    fn base_paint(&self, glyph_id: GlyphId) -> Option<usize> {
+        if self.base_glyph_list == 0 {
+            return None;
+        }
+        let count = read_u32(self.data, self.base_glyph_list)? as usize;
+        let record = self.find(self.base_glyph_list + 4, count, glyph_id)?;
+        Some(self.base_glyph_list + read_u32(self.data, record + 2)? as usize)
+    }
+
+    /// Binary search for the 6 byte record starting with `glyph_id` among the `count` sorted ones
+    /// at `records`
+    fn find(&self, records: usize, count: usize, glyph_id: GlyphId) -> Option<usize> {
+        let (mut low, mut high) = (0, count);
+        while low < high {
+            let middle = (low + high) / 2;
+            let record = records + middle * 6;
+            match read_u16(self.data, record)?.cmp(&glyph_id.0) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return Some(record),
+            }
+        }
+        None
+    }
+
+    /// Appends the layers painted by the paint at `offset` to `layers`, with `transform` mapping its
+    /// space into the color glyph's and the outline it is clipped to in `clip`
+    fn walk(&self, offset: usize, transform: Transform, clip: Option<(GlyphId, Transform)>, depth: usize, layers: &mut Vec<Layer>) -> Option<()> {
+        if depth > MAX_DEPTH || layers.len() >= MAX_LAYERS {
+            warn!("COLR paint graph is nested too deeply or has too many layers");
+            return None;
+        }
+        let data = self.data;
+        let format = read_u8(data, offset)?;
+        // Variable paints follow their static format with the same fields, which is all that's read
+        let is_variable = matches!(format, 3 | 5 | 7 | 9) || (13..=31).contains(&format) && format % 2 == 1;
+        let format = if is_variable { format - 1 } else { format };
+        let child = |at: usize| Some(offset + read_u24(data, offset + at)? as usize);
+        let point = |at: usize| Some((read_i16(data, offset + at)? as f32, read_i16(data, offset + at + 2)? as f32));
+        let angle = |at: usize| Some(read_f2dot14(data, offset + at)? * PI);
+        let mut fill = |fill: Fill| {
+            match clip {
+                Some((glyph_id, outline_transform)) => layers.push(Layer { glyph_id, outline_transform, fill, fill_transform: transform }),
+                None => warn!("COLR fills outside of a glyph outline are not supported"),
+            }
+            Some(())
+        };
+        match format {
+            1 => {
+                let count = read_u8(data, offset + 1)? as usize;
+                let first = read_u32(data, offset + 2)? as usize;
+                if self.layer_list == 0 {
+                    return None;
+                }
+                for index in first..first + count {
+                    let paint = self.layer_list + read_u32(data, self.layer_list + 4 + index * 4)? as usize;
+                    self.walk(paint, transform, clip, depth + 1, layers)?;
+                }
+                Some(())
+            }
+            2 => fill(Fill::Solid(read_color(data, offset + 1)?)),
+            4 => fill(Fill::Linear {
+                line: self.color_line(child(1)?, is_variable)?,
+                p0: point(4)?,
+                p1: point(8)?,
+                p2: point(12)?,
+            }),
+            6 => fill(Fill::Radial {
+                line: self.color_line(child(1)?, is_variable)?,
+                c0: point(4)?,
+                r0: read_u16(data, offset + 8)? as f32,
+                c1: point(10)?,
+                r1: read_u16(data, offset + 14)? as f32,
+            }),
+            8 => fill(Fill::Sweep {
+                line: self.color_line(child(1)?, is_variable)?,
+                center: point(4)?,
+                start: angle(8)?,
+                end: angle(10)?,
+            }),
+            10 => {
+                if clip.is_some() {
+                    warn!("nested COLR glyph clips are not supported, clipping to the inner glyph only");
+                }
+                let glyph_id = GlyphId(read_u16(data, offset + 4)?);
+                self.walk(child(1)?, transform, Some((glyph_id, transform)), depth + 1, layers)
+            }
+            11 => {
+                let paint = self.base_paint(GlyphId(read_u16(data, offset + 1)?))?;
+                self.walk(paint, transform, clip, depth + 1, layers)
+            }
+            12..=31 => {
+                let affine = match format {
+                    12 => {
+                        let matrix = child(4)?;
+                        let value = |index: usize| Some(read_i32(data, matrix + index * 4)? as f32 / 65536.0);
+                        ([[value(0)?, value(2)?], [value(1)?, value(3)?]], (value(4)?, value(5)?))
+                    }
+                    14 => ([[1.0, 0.0], [0.0, 1.0]], point(4)?),
+                    16 | 18 => scale(read_f2dot14(data, offset + 4)?, read_f2dot14(data, offset + 6)?),
+                    20 | 22 => {
+                        let factor = read_f2dot14(data, offset + 4)?;
+                        scale(factor, factor)
+                    }
+                    24 | 26 => {
+                        let (sin, cos) = angle(4)?.sin_cos();
+                        ([[cos, -sin], [sin, cos]], (0.0, 0.0))
+                    }
+                    28 | 30 => ([[1.0, -angle(4)?.tan()], [angle(6)?.tan(), 1.0]], (0.0, 0.0)),
+                    _ => {
+                        warn!("unknown COLR paint format {format}");
+                        return None;
+                    }
+                };
+                let center = match format {
+                    18 | 30 => Some(point(8)?),
+                    22 | 26 => Some(point(6)?),
+                    _ => None,
+                };
+                let affine = match center {
+                    Some((x, y)) => then(then(translate(x, y), affine), translate(-x, -y)),
+                    None => affine,
+                };
+                self.walk(child(1)?, then(transform, affine), clip, depth + 1, layers)
+            }
+            32 => {
+                let source = child(1)?;
+                let backdrop = child(5)?;
+                match read_u8(data, offset + 4)? {
+                    // Clear
+                    0 => Some(()),
+                    // Source
+                    1 => self.walk(source, transform, clip, depth + 1, layers),
+                    // Destination
+                    2 => self.walk(backdrop, transform, clip, depth + 1, layers),
+                    // Destination over
+                    4 => {
+                        self.walk(source, transform, clip, depth + 1, layers)?;
+                        self.walk(backdrop, transform, clip, depth + 1, layers)
+                    }
+                    mode => {
+                        if mode != 3 {
+                            warn!("COLR composite mode {mode} is not supported, drawing source over");
+                        }
+                        self.walk(backdrop, transform, clip, depth + 1, layers)?;
+                        self.walk(source, transform, clip, depth + 1, layers)
+                    }
+                }
+            }
+            _ => {
+                warn!("unknown COLR paint format {format}");
+                None
+            }
+        }
+    }
+
+    fn color_line(&self, offset: usize, is_variable: bool) -> Option<ColorLine> {
+        let extend = match read_u8(self.data, offset)? {
+            1 => Extend::Repeat,
+            2 => Extend::Reflect,
+            _ => Extend::Pad,
+        };
+        let count = read_u16(self.data, offset + 1)? as usize;
+        // Variable stops end with a delta index
+        let stride = if is_variable { 10 } else { 6 };
+        let mut stops = (0..count)
+            .map(|index| {
+                let stop = offset + 3 + index * stride;
+                Some((read_f2dot14(self.data, stop)?, read_color(self.data, stop + 2)?))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Some(ColorLine { extend, stops })
+    }
+}
+
+/// How the vertices of a gradient layer evaluate it: the `metadata` bits naming its kind and
+/// extend mode, and the vertex `paint` coordinates as a function of the vertex position.
+///
+/// `paint` holds, by kind:
+/// 1. linear: the position on the ramp from 0 to 1, affine in the vertex position
+/// 2. radial: the position in a frame with the start circle at the origin and the end circle at
+///    (1, 0), then the start radius and the change in radius
+/// 3. concentric radial: like 2 with both circles at the origin
+/// 4. sweep: the position relative to the center, rotated so the gradient starts at the positive x
+///    axis, then the swept angle
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Gradient {
+    pub metadata: i32,
+    to_paint: Transform,
+    constants: [f32; 2],
+}
+
+impl Gradient {
+    /// The gradient of a layer's fill, `None` for solid fills and degenerate gradients, which draw
+    /// nothing
+    pub fn new(fill: &Fill, fill_transform: Transform) -> Option<Gradient> {
+        let to_fill = invert(fill_transform)?;
+        let (kind, line, to_paint, constants) = match fill {
+            Fill::Solid(_) => return None,
+            Fill::Linear { p0, p1, p2, line } => {
+                // The gradient runs perpendicular to p0-p2, to p1 projected onto that direction
+                let (dx, dy) = (p1.0 - p0.0, p1.1 - p0.1);
+                let normal = (p0.1 - p2.1, p2.0 - p0.0);
+                let normal_length = normal.0 * normal.0 + normal.1 * normal.1;
+                let (dx, dy) = if normal_length > 0.0 {
+                    let scale = (dx * normal.0 + dy * normal.1) / normal_length;
+                    (normal.0 * scale, normal.1 * scale)
+                } else {
+                    (dx, dy)
+                };
+                let length = dx * dx + dy * dy;
+                if length == 0.0 {
+                    return None;
+                }
+                let (first, last) = line.range();
+                let scale = 1.0 / (length * (last - first));
+                let to_ramp = ([[dx * scale, dy * scale], [0.0, 0.0]], (-(dx * p0.0 + dy * p0.1) * scale - first / (last - first), 0.0));
+                (1, line, to_ramp, [0.0, 0.0])
+            }
+            Fill::Radial { c0, r0, c1, r1, line } => {
+                // The circles at the first and last stop become the start and end circle
+                let (first, last) = line.range();
+                let circle = |t: f32| ((c0.0 + (c1.0 - c0.0) * t, c0.1 + (c1.1 - c0.1) * t), r0 + (r1 - r0) * t);
+                let ((x0, y0), r0) = circle(first);
+                let ((x1, y1), r1) = circle(last);
+                let (dx, dy) = (x1 - x0, y1 - y0);
+                let distance = (dx * dx + dy * dy).sqrt();
+                if distance > 1e-3 {
+                    let frame = then(scale(1.0 / distance, 1.0 / distance), then(rotate(-dy.atan2(dx)), translate(-x0, -y0)));
+                    (2, line, frame, [r0 / distance, (r1 - r0) / distance])
+                } else if (r1 - r0).abs() > 1e-3 {
+                    (3, line, translate(-x0, -y0), [r0, r1 - r0])
+                } else {
+                    return None;
+                }
+            }
+            Fill::Sweep { center, start, end, line } => {
+                let (first, last) = line.range();
+                let (start, end) = (start + (end - start) * first, start + (end - start) * last);
+                if start == end {
+                    return None;
+                }
+                (4, line, then(rotate(-start), translate(-center.0, -center.1)), [end - start, 0.0])
+            }
+        };
+        let extend = match line.extend {
+            Extend::Pad => 0,
+            Extend::Repeat => 1,
+            Extend::Reflect => 2,
+        };
+        Some(Gradient { metadata: (kind | extend << 3) << 4, to_paint: then(to_paint, to_fill), constants })
+    }
+
+    /// `paint` coordinates of a vertex at `position` in the color glyph's space
+    pub fn paint(&self, position: (f32, f32)) -> [f32; 4] {
+        let (x, y) = apply(self.to_paint, position);
+        [x, y, self.constants[0], self.constants[1]]
+    }
+}
+
+/// `metadata` of a vertex drawn in a plain color, without the gradient of the layer it was copied
+/// from. Bitmap quads keep their image index in the same bits.
+pub(crate) fn solid(metadata: i32) -> i32 {
+    if metadata & 12 != 0 {
+        metadata
+    } else {
+        metadata & !GRADIENT_BITS
+    }
+}
+
+/// The bits of `metadata` naming a gradient, 0 for vertices drawn in a plain color and bitmap quads
+pub(crate) fn gradient(metadata: i32) -> i32 {
+    metadata ^ solid(metadata)
+}
+
+/// Position on the ramp from 0 to 1 of a fragment with the interpolated `paint`, for vertices with
+/// a gradient in `metadata`. `None` for plain vertices and outside the cone of radial gradients.
+///
+/// Evaluated exactly like `glyph.wgsl` does.
+pub(crate) fn ramp_position(metadata: i32, paint: [f32; 4]) -> Option<f32> {
+    if metadata & 12 != 0 {
+        return None;
+    }
+    let [x, y, a, b] = paint;
+    let t = match metadata >> 4 & 7 {
+        1 => x,
+        kind @ (2 | 3) => radial_position((x, y), if kind == 2 { 1.0 } else { 0.0 }, a, b)?,
+        4 => {
+            let mut angle = y.atan2(x);
+            if a > 0.0 && angle < 0.0 {
+                angle += 2.0 * PI;
+            } else if a < 0.0 && angle > 0.0 {
+                angle -= 2.0 * PI;
+            }
+            angle / a
+        }
+        _ => return None,
+    };
+    Some(match metadata >> 7 & 3 {
+        1 => t - t.floor(),
+        2 => 1.0 - ((t * 0.5 - (t * 0.5).floor()) * 2.0 - 1.0).abs(),
+        _ => t.clamp(0.0, 1.0),
+    })
+}
+
+/// Largest `t` for which `point` lies on the circle around `(t * dx, 0)` with the non-negative
+/// radius `r0 + t * dr`
+fn radial_position(point: (f32, f32), dx: f32, r0: f32, dr: f32) -> Option<f32> {
+    let a = dx * dx - dr * dr;
+    let b = point.0 * dx + r0 * dr;
+    let c = point.0 * point.0 + point.1 * point.1 - r0 * r0;
+    let is_valid = |t: f32| r0 + t * dr >= 0.0;
+    if a.abs() < 1e-6 {
+        let t = c / (2.0 * b);
+        return (b != 0.0 && is_valid(t)).then_some(t);
+    }
+    let discriminant = b * b - a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    let (t0, t1) = ((b + root) / a, (b - root) / a);
+    [t0.max(t1), t0.min(t1)].into_iter().find(|t| is_valid(*t))
+}
+
+/// Color at `position` on the ramp starting at `palette[start]`, interpolated like `glyph.wgsl`
+pub(crate) fn ramp_color(palette: &[[f32; 4]], start: usize, position: f32) -> [f32; 4] {
+    let scaled = position * (RAMP_LEN - 1) as f32;
+    let index = (scaled.floor() as usize).min(RAMP_LEN - 1);
+    let (Some(from), Some(to)) = (palette.get(start + index), palette.get(start + (index + 1).min(RAMP_LEN - 1))) else {
+        return [0.0; 4];
+    };
+    let t = scaled - scaled.floor();
+    [0, 1, 2, 3].map(|channel| from[channel] + (to[channel] - from[channel]) * t)
+}
+
+/// `outer` applied after `inner`
+fn then(outer: Transform, inner: Transform) -> Transform {
+    let ([[a, b], [c, d]], _) = outer;
+    let ([[e, f], [g, h]], translate) = inner;
+    ([[a * e + b * g, a * f + b * h], [c * e + d * g, c * f + d * h]], apply(outer, translate))
+}
+
+fn apply((matrix, translate): Transform, (x, y): (f32, f32)) -> (f32, f32) {
+    (matrix[0][0] * x + matrix[0][1] * y + translate.0, matrix[1][0] * x + matrix[1][1] * y + translate.1)
+}
+
+fn invert((matrix, translate): Transform) -> Option<Transform> {
+    let [[a, b], [c, d]] = matrix;
+    let determinant = a * d - b * c;
+    if determinant.abs() < 1e-12 {
+        return None;
+    }
+    let inverse = [[d / determinant, -b / determinant], [-c / determinant, a / determinant]];
+    let (x, y) = apply((inverse, (0.0, 0.0)), translate);
+    Some((inverse, (-x, -y)))
+}
+
+fn translate(x: f32, y: f32) -> Transform {
+    ([[1.0, 0.0], [0.0, 1.0]], (x, y))
+}
+
+fn scale(x: f32, y: f32) -> Transform {
+    ([[x, 0.0], [0.0, y]], (0.0, 0.0))
+}
+
+fn rotate(angle: f32) -> Transform {
+    let (sin, cos) = angle.sin_cos();
+    ([[cos, -sin], [sin, cos]], (0.0, 0.0))
+}
+
+fn read_color(data: &[u8], offset: usize) -> Option<ColorRef> {
+    Some(ColorRef { palette_index: read_u16(data, offset)?, alpha: read_f2dot14(data, offset + 2)? })
+}
+
+fn read_u8(data: &[u8], offset: usize) -> Option<u8> {
+    data.get(offset).copied()
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn read_i16(data: &[u8], offset: usize) -> Option<i16> {
+    read_u16(data, offset).map(|value| value as i16)
+}
+
+fn read_u24(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 3).map(|b| u32::from_be_bytes([0, b[0], b[1], b[2]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn read_i32(data: &[u8], offset: usize) -> Option<i32> {
+    read_u32(data, offset).map(|value| value as i32)
+}
+
+fn read_f2dot14(data: &[u8], offset: usize) -> Option<f32> {
+    read_i16(data, offset).map(|value| value as f32 / 16384.0)
+}
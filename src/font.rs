@@ -0,0 +1,239 @@
+use ttf_parser::name_id;
+
+/// Naming information about a single face inside a font file.
+#[derive(Clone, Debug)]
+pub struct FaceInfo {
+    pub index: u32,
+    pub family: Option<String>,
+    pub style: Option<String>,
+}
+
+/// Returns the number of faces in `data`, which is 1 for plain TTF/OTF files.
+pub fn count_faces(data: &[u8]) -> u32 {
+    ttf_parser::fonts_in_collection(data).unwrap_or(1)
+}
+
+/// Parses the name table of the face at `index`, returning `None` if the face can't be parsed.
+pub fn face_info(data: &[u8], index: u32) -> Option<FaceInfo> {
+    let face = ttf_parser::Face::parse(data, index).ok()?;
+    let find_name = |ids: &[u16]| ids.iter().find_map(|id| {
+        face.names().into_iter()
+            .filter(|name| name.name_id == *id && name.is_unicode())
+            .find_map(|name| name.to_string())
+    });
+    Some(FaceInfo {
+        index,
+        family: find_name(&[name_id::TYPOGRAPHIC_FAMILY, name_id::FAMILY]),
+        style: find_name(&[name_id::TYPOGRAPHIC_SUBFAMILY, name_id::SUBFAMILY]),
+    })
+}
+
+/// Whether the face has any outline table (`glyf`, `CFF ` or `CFF2`). Bitmap-only fonts, like many
+/// emoji fonts, have none and can't be tessellated.
+pub fn has_outlines(face: &ttf_parser::Face) -> bool {
+    let tables = face.tables();
+    tables.glyf.is_some() || tables.cff.is_some() || tables.cff2.is_some()
+}
+
+/// Index of `face` inside the font collection it was parsed from, 0 for plain TTF/OTF files.
+///
+/// Faces of a collection share the file data, so the index is recovered by finding the face whose
+/// table directory matches.
+pub fn face_index(face: &ttf_parser::Face) -> u32 {
+    let raw = face.raw_face();
+    let same_tables = |other: &ttf_parser::RawFace| {
+        other.table_records.len() == raw.table_records.len()
+            && other.table_records.into_iter().zip(raw.table_records).all(|(a, b)| {
+                a.tag == b.tag && a.offset == b.offset && a.length == b.length
+            })
+    };
+    (0..count_faces(raw.data))
+        .find(|index| ttf_parser::RawFace::parse(raw.data, *index).is_ok_and(|other| same_tables(&other)))
+        .unwrap_or(0)
+}
+
+/// Lists all parsable faces in `data`.
+pub fn list_faces(data: &[u8]) -> Vec<FaceInfo> {
+    (0..count_faces(data)).filter_map(|index| face_info(data, index)).collect()
+}
+
+#[derive(Debug)]
+pub enum FontLoadError {
+    /// The data is WOFF/WOFF2 but the `woff` feature is disabled.
+    UnsupportedFormat,
+    /// The font container is truncated or otherwise malformed.
+    Malformed,
+    Decompression(String),
+    Parse(ttf_parser::FaceParsingError),
+}
+
+impl std::fmt::Display for FontLoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FontLoadError::UnsupportedFormat => write!(f, "WOFF/WOFF2 fonts require the `woff` feature"),
+            FontLoadError::Malformed => write!(f, "malformed font data"),
+            FontLoadError::Decompression(error) => write!(f, "failed to decompress font: {error}"),
+            FontLoadError::Parse(error) => write!(f, "failed to parse font: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for FontLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FontLoadError::Parse(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// A parsed face together with the font data it borrows from, for fonts that don't outlive a
+/// buffer the caller manages, like ones fetched over the network.
+///
+/// Spans and shapers take the face through [`Font::face`].
+pub struct Font {
+    // Declared before `data` so it is dropped first
+    face: ttf_parser::Face<'static>,
+    data: Box<[u8]>,
+}
+
+impl Font {
+    /// Parses the first face of `data`, see [`Font::from_bytes_with_index`].
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, FontLoadError> {
+        Self::from_bytes_with_index(data, 0)
+    }
+
+    /// Takes ownership of `data`, decompressing it with [`load_font`] if needed, and parses the face
+    /// at `index` of a font collection.
+    pub fn from_bytes_with_index(data: Vec<u8>, index: u32) -> Result<Self, FontLoadError> {
+        let data = match data.get(0..4) {
+            Some(b"wOFF" | b"wOF2") => load_font(&data)?,
+            _ => data,
+        }.into_boxed_slice();
+        // SAFETY: the boxed bytes never move or change while `self` exists, and `face` is dropped
+        // before them. The `'static` lifetime is never handed out, `face()` shortens it to `&self`.
+        let bytes: &'static [u8] = unsafe { std::slice::from_raw_parts(data.as_ptr(), data.len()) };
+        let face = ttf_parser::Face::parse(bytes, index).map_err(FontLoadError::Parse)?;
+        Ok(Self { face, data })
+    }
+
+    pub fn face(&self) -> &ttf_parser::Face<'_> {
+        &self.face
+    }
+
+    /// The decompressed font data the face was parsed from
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl std::fmt::Debug for Font {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Font").field("face", &self.face).field("len", &self.data.len()).finish()
+    }
+}
+
+/// Returns raw SFNT data (TTF/OTF/TTC) that `ttf_parser::Face::parse` understands, decompressing
+/// WOFF and WOFF2 web fonts when the `woff` feature is enabled. Other data is returned unchanged.
+pub fn load_font(data: &[u8]) -> Result<Vec<u8>, FontLoadError> {
+    match data.get(0..4) {
+        Some(b"wOFF") => decode_woff(data),
+        Some(b"wOF2") => decode_woff2(data),
+        _ => Ok(data.to_vec()),
+    }
+}
+
+#[cfg(feature = "woff")]
+fn decode_woff2(data: &[u8]) -> Result<Vec<u8>, FontLoadError> {
+    woff2::convert_woff2_to_ttf(&mut &data[..]).map_err(|error| FontLoadError::Decompression(error.to_string()))
+}
+
+#[cfg(not(feature = "woff"))]
+fn decode_woff2(_data: &[u8]) -> Result<Vec<u8>, FontLoadError> {
+    Err(FontLoadError::UnsupportedFormat)
+}
+
+#[cfg(not(feature = "woff"))]
+fn decode_woff(_data: &[u8]) -> Result<Vec<u8>, FontLoadError> {
+    Err(FontLoadError::UnsupportedFormat)
+}
+
+/// Rebuilds the SFNT from a WOFF 1.0 file, whose tables are individually zlib compressed.
+#[cfg(feature = "woff")]
+fn decode_woff(data: &[u8]) -> Result<Vec<u8>, FontLoadError> {
+    use std::io::Read;
+
+    let read_u16 = |offset: usize| data.get(offset..offset + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or(FontLoadError::Malformed);
+    let read_u32 = |offset: usize| data.get(offset..offset + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(FontLoadError::Malformed);
+
+    let flavor = read_u32(4)?;
+    let num_tables = read_u16(12)?;
+    // The SFNT offset table stores `num_tables * 16` in 16 bits
+    if num_tables > u16::MAX / 16 {
+        return Err(FontLoadError::Malformed);
+    }
+
+    // Decompress all tables first, records are (tag, checksum, data)
+    let mut tables = vec![];
+    for index in 0..num_tables as usize {
+        let entry = 44 + index * 20;
+        let (tag, offset, comp_length, orig_length, checksum) = (
+            read_u32(entry)?,
+            read_u32(entry + 4)? as usize,
+            read_u32(entry + 8)? as usize,
+            read_u32(entry + 12)? as usize,
+            read_u32(entry + 16)?,
+        );
+        let end = offset.checked_add(comp_length).ok_or(FontLoadError::Malformed)?;
+        let compressed = data.get(offset..end).ok_or(FontLoadError::Malformed)?;
+        let table = if comp_length < orig_length {
+            // `orig_length` comes from the file, so it only bounds the output instead of sizing it up
+            // front. Reading one byte past it catches tables that decompress to more.
+            let mut table = vec![];
+            flate2::read::ZlibDecoder::new(compressed).take(orig_length as u64 + 1).read_to_end(&mut table)
+                .map_err(|error| FontLoadError::Decompression(error.to_string()))?;
+            if table.len() != orig_length {
+                return Err(FontLoadError::Decompression(format!(
+                    "table {tag:08x} decompressed to {} bytes instead of {orig_length}",
+                    table.len(),
+                )));
+            }
+            table
+        } else if comp_length == orig_length {
+            compressed.to_vec()
+        } else {
+            return Err(FontLoadError::Malformed);
+        };
+        tables.push((tag, checksum, table));
+    }
+
+    // Offset table
+    let entry_selector = (num_tables.max(1) as f32).log2().floor() as u32;
+    let search_range = (1u32 << entry_selector) * 16;
+    let range_shift = (num_tables as u32 * 16).saturating_sub(search_range);
+    let mut sfnt = vec![];
+    sfnt.extend_from_slice(&flavor.to_be_bytes());
+    sfnt.extend_from_slice(&num_tables.to_be_bytes());
+    sfnt.extend_from_slice(&(search_range as u16).to_be_bytes());
+    sfnt.extend_from_slice(&(entry_selector as u16).to_be_bytes());
+    sfnt.extend_from_slice(&(range_shift as u16).to_be_bytes());
+
+    // Table records followed by the 4-byte aligned table data
+    let mut offset = 12 + tables.len() * 16;
+    for (tag, checksum, table) in &tables {
+        sfnt.extend_from_slice(&tag.to_be_bytes());
+        sfnt.extend_from_slice(&checksum.to_be_bytes());
+        sfnt.extend_from_slice(&(offset as u32).to_be_bytes());
+        sfnt.extend_from_slice(&(table.len() as u32).to_be_bytes());
+        offset += (table.len() + 3) & !3;
+    }
+    for (_, _, table) in &tables {
+        sfnt.extend_from_slice(table);
+        sfnt.resize((sfnt.len() + 3) & !3, 0);
+    }
+    Ok(sfnt)
+}
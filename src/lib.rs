@@ -0,0 +1,26 @@
+pub mod block;
+mod colr;
+pub mod font;
+pub mod mesh;
+pub mod raster;
+pub mod renderer;
+pub mod sdf;
+pub mod shaper;
+pub mod text;
+
+pub use font::Font;
+pub use mesh::{glyph_mesh, GlyphMesh, GlyphMeshBuilder, TextMesh};
+pub use renderer::{AAMode, AdapterOptions, RenderStats, RendererError, TextureRenderer};
+pub use text::{Alignment, FontSize, LineHeight, Span};
+
+/// A shaped glyph, with advances and offsets in font units
+#[derive(Copy, Clone, Debug)]
+pub struct GlyphData {
+    pub glyph_id: u32,
+    pub x_advance: i32,
+    pub y_advance: i32,
+    pub x_offset: i32,
+    pub y_offset: i32,
+    /// Byte offset of the first character of the glyph's cluster in the shaped text
+    pub cluster: u32,
+}
@@ -1,85 +1,244 @@
-use std::ops::Range;
-use log::{info, trace};
-use crate::{GlyphData, TEXTURE_SIZE};
+use std::collections::HashMap;
+use log::{trace, warn};
+use ttf_parser::OutlineBuilder;
+use crate::colr;
+use crate::GlyphData;
 use crate::renderer::GlyphVertex;
 use crate::text::FontSize;
 
+/// Triangulated outline of a single glyph.
+///
+/// Vertex positions are in font units, y-up with the origin on the glyph's baseline at the start
+/// of its advance. Filled regions are plain triangles with `metadata` 0. Curve triangles have bit 1
+/// of `metadata` set and `uv` coordinates for the `v < u²` test, with bit 0 inverting the test for
+/// curves bulging outwards. All triangles are counter-clockwise.
 #[derive(Clone, Debug)]
 pub struct GlyphMesh {
     pub glyph_id: ttf_parser::GlyphId,
     pub vertices: Vec<GlyphVertex>,
-    pub indices: Vec<u16>,
+    pub indices: Vec<u32>,
     pub bounds: ttf_parser::Rect,
+    /// Closed outline contours with curves flattened into line segments, in font units
+    pub contours: Vec<Vec<(f32, f32)>>,
 }
 
+impl GlyphMesh {
+    /// Applies the affine `transform` to the vertices, contours and bounds, keeping the triangles
+    /// counter-clockwise if it mirrors the glyph
+    pub(crate) fn transform(&mut self, transform: Transform) {
+        if transform == ([[1.0, 0.0], [0.0, 1.0]], (0.0, 0.0)) {
+            return;
+        }
+        let ([[a, b], [c, d]], (dx, dy)) = transform;
+        let apply = |(x, y): (f32, f32)| (a * x + b * y + dx, c * x + d * y + dy);
+        for v in &mut self.vertices {
+            (v.position[0], v.position[1]) = apply((v.position[0], v.position[1]));
+        }
+        for point in self.contours.iter_mut().flatten() {
+            *point = apply(*point);
+        }
+        if a * d - b * c < 0.0 {
+            self.indices.chunks_exact_mut(3).for_each(|triangle| triangle.swap(0, 2));
+        }
+        let bounds = self.bounds;
+        let corners = [(bounds.x_min, bounds.y_min), (bounds.x_max, bounds.y_min), (bounds.x_min, bounds.y_max), (bounds.x_max, bounds.y_max)]
+            .map(|(x, y)| apply((x as f32, y as f32)));
+        let (x_min, x_max) = corners.iter().fold((f32::MAX, f32::MIN), |(min, max), (x, _)| (min.min(*x), max.max(*x)));
+        let (y_min, y_max) = corners.iter().fold((f32::MAX, f32::MIN), |(min, max), (_, y)| (min.min(*y), max.max(*y)));
+        self.bounds = ttf_parser::Rect {
+            x_min: x_min.floor() as i16,
+            y_min: y_min.floor() as i16,
+            x_max: x_max.ceil() as i16,
+            y_max: y_max.ceil() as i16,
+        };
+    }
+}
+
+/// Presets trading outline fidelity for triangle count
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QualityTier {
+    /// For tiny UI text, where dropped slivers and coarse cubics are not visible
+    Low,
+    Medium,
+    /// For display text
+    High,
+}
+
+impl QualityTier {
+    /// Minimum contour and curve triangle area in square font units
+    pub fn area_epsilon(self) -> f32 {
+        match self {
+            QualityTier::Low => 4.0,
+            QualityTier::Medium => 1.0,
+            QualityTier::High => 0.0,
+        }
+    }
+
+    /// Number of pieces each cubic bezier is split into before approximating it with quadratics
+    pub fn cubic_subdivisions(self) -> u32 {
+        match self {
+            QualityTier::Low => 1,
+            QualityTier::Medium => 2,
+            QualityTier::High => 4,
+        }
+    }
+}
+
+/// Line segments each curve of [`GlyphMesh::contours`] is flattened into without a curve tolerance
+const CONTOUR_SEGMENTS: usize = 8;
+/// Upper bound on the pieces a single curve is split into for a curve tolerance
+const MAX_CURVE_PIECES: usize = 64;
+
+/// On-curve point of an outline with the control point of the quadratic curve ending in it
+type QuadraticPoint = ((f32, f32), Option<(f32, f32)>);
+
 pub struct GlyphMeshBuilder {
-    reverse_wind: bool,
-    polygons: Vec<Vec<(f32, f32)>>,
-    bezier_polygons: Vec<([(f32, f32); 3], bool)>,
+    area_epsilon: f32,
+    cubic_subdivisions: u32,
+    curve_tolerance: Option<f32>,
+    /// On-curve points of every contour
+    outlines: Vec<Vec<QuadraticPoint>>,
+    contours: Vec<Vec<(f32, f32)>>,
+}
+
+impl Default for GlyphMeshBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl GlyphMeshBuilder {
     pub fn new() -> Self {
         Self {
-            reverse_wind: false,
-            polygons: vec![],
-            bezier_polygons: vec![],
+            area_epsilon: 0.0,
+            cubic_subdivisions: 1,
+            curve_tolerance: None,
+            outlines: vec![],
+            contours: vec![],
         }
     }
 
+    /// Contours and curve triangles whose absolute area (in square font units) is below `epsilon`
+    /// are dropped, so degenerate slivers can't be misclassified as holes or fills.
+    pub fn with_area_epsilon(mut self, epsilon: f32) -> Self {
+        self.area_epsilon = epsilon;
+        self
+    }
+
+    /// Each cubic bezier is split into `subdivisions` pieces, which are approximated by two
+    /// quadratic curves each.
+    pub fn with_cubic_subdivisions(mut self, subdivisions: u32) -> Self {
+        self.cubic_subdivisions = subdivisions.max(1);
+        self
+    }
+
+    /// Subdivides curves adaptively so they deviate at most `tolerance` font units from the outline:
+    /// cubics are split into as many quadratic pieces and [`GlyphMesh::contours`] are flattened into
+    /// as many segments as needed. Overrides [`GlyphMeshBuilder::with_cubic_subdivisions`].
+    pub fn with_curve_tolerance(mut self, tolerance: f32) -> Self {
+        self.curve_tolerance = Some(tolerance.max(f32::EPSILON));
+        self
+    }
+
+    /// Sets area epsilon and cubic subdivisions from a preset
+    pub fn with_quality(self, tier: QualityTier) -> Self {
+        self.with_area_epsilon(tier.area_epsilon())
+            .with_cubic_subdivisions(tier.cubic_subdivisions())
+    }
+
     pub fn build(mut self, face: &ttf_parser::Face, glyph_id: ttf_parser::GlyphId) -> Option<GlyphMesh> {
-        // Check winding direction
-        self.reverse_wind = !face.tables().glyf.is_some();
-        trace!("font winding order: {}", if self.reverse_wind { "clockwise" } else { "counter-clockwise" });
+        trace!("outline tables:");
         trace!("    table glyf: {}", if face.tables().glyf.is_some() {"exists"} else {"absent"});
         trace!("    table cff: {}", if face.tables().cff.is_some() {"exists"} else {"absent"});
         trace!("    table cff2: {}", if face.tables().cff2.is_some() {"exists"} else {"absent"});
-        let Some(bounds) = face.outline_glyph(glyph_id, &mut self) else {
-            return None;
-        };
+        let bounds = face.outline_glyph(glyph_id, &mut self)?;
         let (vertices, indices) = self.triangulate();
         Some(GlyphMesh {
             glyph_id,
             vertices,
             indices,
             bounds,
+            contours: self.contours,
         })
     }
 
-    pub fn triangulate(&self) -> (Vec<GlyphVertex>, Vec<u16>) {
-        // check for holes
-        let is_polygon_hole = self.polygons.iter().map(|points| {
-            // Sum over edges
-            is_ccw_wind(&points) ^ self.reverse_wind
-        }).collect::<Vec<bool>>();
+    /// Triangulates the outline. Holes are found by nesting, a contour inside an odd number of
+    /// other contours is a hole, so contour directions don't have to follow any convention.
+    pub fn triangulate(&self) -> (Vec<GlyphVertex>, Vec<u32>) {
+        let kept = (0..self.contours.len())
+            .filter(|index| signed_area(&self.contours[*index]).abs() >= self.area_epsilon)
+            .collect::<Vec<_>>();
+        // Contours containing each contour, tested with one of its points on the flattened outlines
+        let containers = kept.iter().map(|index| {
+            let point = self.contours[*index][0];
+            kept.iter()
+                .filter(|other| *other != index && contains(&self.contours[**other], point))
+                .copied()
+                .collect::<Vec<_>>()
+        }).collect::<Vec<_>>();
+        let is_hole = containers.iter().map(|containers| containers.len() % 2 == 1).collect::<Vec<_>>();
+
+        // Build polygons as if outer contours were clockwise and holes counter-clockwise, the
+        // TrueType convention, flipping the curve classification of contours wound the other way
+        let mut polygons = vec![];
+        let mut bezier_polygons = vec![];
+        for (position, index) in kept.iter().enumerate() {
+            let flip = is_ccw_wind(&self.contours[*index]) != is_hole[position];
+            let outline = &self.outlines[*index];
+            let mut polygon = vec![];
+            let mut previous = outline[0].0;
+            for (point, control) in outline {
+                if let Some(control) = control {
+                    let points = [previous, *control, *point];
+                    let is_inverse = is_ccw_wind(&points) ^ flip;
+                    bezier_polygons.push((points, is_inverse, flip));
+                    if is_inverse {
+                        polygon.push(*control);
+                    }
+                }
+                polygon.push(*point);
+                previous = *point;
+            }
+            polygons.push(polygon);
+        }
 
-        // Group Polygons
+        // Group every hole with the innermost outer contour around it
         let mut polygon_with_holes: Vec<Vec<Vec<Vec<f32>>>> = vec![];
-        for (index, points) in self.polygons.iter().enumerate() {
-            if is_polygon_hole[index] {
-                let element = polygon_with_holes.last_mut().unwrap();
-                element.push(points.iter().map(|v| vec![v.0, v.1]).collect());
-            } else {
-                polygon_with_holes.push(vec![points.iter().map(|v| vec![v.0, v.1]).collect::<Vec<Vec<f32>>>()]);
+        let mut group_of = vec![None; kept.len()];
+        for position in (0..kept.len()).filter(|position| !is_hole[*position]) {
+            group_of[position] = Some(polygon_with_holes.len());
+            polygon_with_holes.push(vec![polygons[position].iter().map(|v| vec![v.0, v.1]).collect::<Vec<Vec<f32>>>()]);
+        }
+        for position in (0..kept.len()).filter(|position| is_hole[*position]) {
+            let parent = containers[position].iter()
+                .filter_map(|index| kept.iter().position(|candidate| candidate == index))
+                .find(|parent| containers[*parent].len() + 1 == containers[position].len());
+            if let Some(group) = parent.and_then(|parent| group_of[parent]) {
+                polygon_with_holes[group].push(polygons[position].iter().map(|v| vec![v.0, v.1]).collect());
             }
         }
         trace!("grouped {:?} meshes", polygon_with_holes.len());
 
         // triangulate
-        let mut indices: Vec<u16> = vec![];
+        let mut indices: Vec<u32> = vec![];
         let mut vertices: Vec<GlyphVertex> = vec![];
         for points in polygon_with_holes {
             // flatten
             let (points, holes, dimensions) = earcutr::flatten(&points);
 
             // Calculate indices
-            indices.append(&mut earcutr::earcut(
-                &points, &holes, dimensions,
-            ).unwrap()
-                .iter().map(|t| (vertices.len() + *t) as u16).collect());
+            let mut triangles = earcutr::earcut(&points, &holes, dimensions).unwrap();
+            let point_count = points.len() / dimensions;
+            if triangles.is_empty() && point_count >= 3 {
+                // Nearly degenerate contours can defeat earcut, a fan at least keeps the region filled
+                let outer_count = holes.first().copied().unwrap_or(point_count);
+                warn!("earcut produced no triangles for a contour with {} points, falling back to a fan", outer_count);
+                triangles = fan_triangulate(&points[..outer_count * dimensions]);
+            }
+            indices.append(&mut triangles.iter().map(|t| (vertices.len() + *t) as u32).collect());
 
             // Map point format
-            let (even, odd): (Vec<(usize, &f32)>, Vec<(usize, &f32)>) = points.iter().enumerate().partition(|(index, _v)| index % 2 == 0);
+            let (even, odd): (Vec<_>, Vec<_>) = points.iter().enumerate().partition(|(index, _v)| index % 2 == 0);
             let points = even.iter().map(|v| *v.1).zip(odd.iter().map(|v| *v.1)).collect::<Vec<(f32, f32)>>();
 
             // Map to vertices
@@ -88,94 +247,570 @@ impl GlyphMeshBuilder {
                 uv: [0.0, 0.0],
                 metadata: 0,
                 color_index: 0,
+                paint: [0.0; 4],
             }).collect());
         }
-        for (polygon, is_inverse) in &self.bezier_polygons {
-            let index = vertices.len() as u16;
-            let mut i = if *is_inverse ^ self.reverse_wind { vec![index, index + 1, index + 2] } else { vec![index + 2, index + 1, index] };
+        for (polygon, is_inverse, flip) in &bezier_polygons {
+            if signed_area(polygon).abs() < self.area_epsilon {
+                continue;
+            }
+            let index = vertices.len() as u32;
+            let mut i = if *is_inverse ^ *flip { vec![index, index + 1, index + 2] } else { vec![index + 2, index + 1, index] };
             indices.append(&mut i);
             vertices.append(&mut polygon.iter().enumerate().map(|(index, (x, y))| GlyphVertex {
                 position: [*x, *y, 0.0], // Only temp
                 uv: [[0.0, 0.0], [0.5, 0.0], [1.0, 1.0]][index],
                 metadata: 0b10 | *is_inverse as i32,
                 color_index: 0,
+                paint: [0.0; 4],
             }).collect());
         }
         trace!("finished triangulating");
         (vertices, indices)
     }
+
+    /// Adds a quadratic curve from the current point, classified once the whole outline is known.
+    fn push_quad(&mut self, control: (f32, f32), end: (f32, f32)) {
+        self.outlines.last_mut().unwrap().push((end, Some(control)));
+    }
+
+    /// Approximates a cubic from the current point with two quadratic curves
+    fn push_cubic(&mut self, c1: (f32, f32), c2: (f32, f32), end: (f32, f32)) {
+        let implied = (c1.0 + (c2.0 - c1.0) / 2.0, c1.1 + (c2.1 - c1.1) / 2.0); // Implied point by cubic bezier
+        self.push_quad(c1, implied);
+        self.push_quad(c2, end);
+    }
 }
 
 impl ttf_parser::OutlineBuilder for GlyphMeshBuilder {
     fn move_to(&mut self, x: f32, y: f32) {
-        self.polygons.push(Vec::new());
-        self.polygons.last_mut().unwrap().push((x, y))
+        self.outlines.push(vec![((x, y), None)]);
+        self.contours.push(vec![(x, y)]);
     }
 
     fn line_to(&mut self, x: f32, y: f32) {
-        self.polygons.last_mut().unwrap().push((x, y))
+        self.outlines.last_mut().unwrap().push(((x, y), None));
+        self.contours.last_mut().unwrap().push((x, y));
     }
 
     fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
-        let points = [*self.polygons.last().unwrap().last().unwrap(), (x1, y1), (x, y)];
-        let is_inverse = is_ccw_wind(&points) ^ self.reverse_wind;
-        self.bezier_polygons.push((points, is_inverse));
-        if is_inverse {
-            self.polygons.last_mut().unwrap().push((x1, y1));
-        }
-        self.polygons.last_mut().unwrap().push((x, y));
+        let p0 = *self.contours.last().unwrap().last().unwrap();
+        // A chord of 1/n of the curve deviates from it by a quarter of the second difference over n²
+        let segments = self.curve_tolerance.map_or(CONTOUR_SEGMENTS, |tolerance| {
+            let second_difference = length((p0.0 - 2.0 * x1 + x, p0.1 - 2.0 * y1 + y));
+            ((second_difference / (4.0 * tolerance)).sqrt().ceil() as usize).clamp(1, MAX_CURVE_PIECES)
+        });
+        self.contours.last_mut().unwrap().extend((1..=segments).map(|step| {
+            let t = step as f32 / segments as f32;
+            let (a, b, c) = ((1.0 - t) * (1.0 - t), 2.0 * (1.0 - t) * t, t * t);
+            (a * p0.0 + b * x1 + c * x, a * p0.1 + b * y1 + c * y)
+        }));
+        self.push_quad((x1, y1), (x, y));
     }
 
     fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
-        let (ix, iy) = (x1 + (x2 - x1) / 2.0, y1 + (y2 - y1) / 2.0);
-        let points = [*self.polygons.last().unwrap().last().unwrap(), (x1, y1), (ix, iy)];
-        let is_inverse = is_ccw_wind(&points) ^ self.reverse_wind;
-        self.bezier_polygons.push((points, is_inverse));
-        if is_inverse {
-            self.polygons.last_mut().unwrap().push((x1, y1));
-        }
-        self.polygons.last_mut().unwrap().push((ix, iy)); // Implied point by cubic bezier
-        let points = [(ix, iy), (x2, y2), (x, y)];
-        let is_inverse = is_ccw_wind(&points) ^ self.reverse_wind;
-        self.bezier_polygons.push((points, is_inverse));
-        if is_inverse {
-            self.polygons.last_mut().unwrap().push((x2, y2));
+        let p0 = *self.contours.last().unwrap().last().unwrap();
+        let (segments, subdivisions) = match self.curve_tolerance {
+            Some(tolerance) => {
+                // Chords deviate by at most 3/4 of the largest second difference over n², the
+                // quadratic approximation by √3/36 of the third difference over n³
+                let second_difference = length((p0.0 - 2.0 * x1 + x2, p0.1 - 2.0 * y1 + y2))
+                    .max(length((x1 - 2.0 * x2 + x, y1 - 2.0 * y2 + y)));
+                let third_difference = length((x - 3.0 * x2 + 3.0 * x1 - p0.0, y - 3.0 * y2 + 3.0 * y1 - p0.1));
+                (
+                    ((0.75 * second_difference / tolerance).sqrt().ceil() as usize).clamp(1, MAX_CURVE_PIECES),
+                    ((3f32.sqrt() / 36.0 * third_difference / tolerance).cbrt().ceil() as u32).clamp(1, MAX_CURVE_PIECES as u32),
+                )
+            }
+            None => (CONTOUR_SEGMENTS, self.cubic_subdivisions),
+        };
+        self.contours.last_mut().unwrap().extend((1..=segments).map(|step| {
+            let t = step as f32 / segments as f32;
+            let u = 1.0 - t;
+            let (a, b, c, d) = (u * u * u, 3.0 * u * u * t, 3.0 * u * t * t, t * t * t);
+            (a * p0.0 + b * x1 + c * x2 + d * x, a * p0.1 + b * y1 + c * y2 + d * y)
+        }));
+        // Split off pieces from the front with de Casteljau, each at the parameter that keeps
+        // the remaining pieces evenly spaced
+        let mut rest = [self.outlines.last().unwrap().last().unwrap().0, (x1, y1), (x2, y2), (x, y)];
+        for remaining in (2..=subdivisions).rev() {
+            let t = 1.0 / remaining as f32;
+            let lerp = |a: (f32, f32), b: (f32, f32)| (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t);
+            let [p0, p1, p2, p3] = rest;
+            let (p01, p12, p23) = (lerp(p0, p1), lerp(p1, p2), lerp(p2, p3));
+            let (p012, p123) = (lerp(p01, p12), lerp(p12, p23));
+            let split = lerp(p012, p123);
+            self.push_cubic(p01, p012, split);
+            rest = [split, p123, p23, p3];
         }
-        self.polygons.last_mut().unwrap().push((x, y));
+        self.push_cubic(rest[1], rest[2], rest[3]);
     }
 
     fn close(&mut self) {
-        
+        // Contours are implicitly closed, drop an explicit closing point
+        if let Some(contour) = self.contours.last_mut() {
+            if contour.len() > 1 && contour.first() == contour.last() {
+                contour.pop();
+            }
+        }
     }
 }
 
-fn is_ccw_wind(vertices: &[(f32, f32)]) -> bool {
+/// Tessellates a single glyph with default quality, `None` if the glyph has no outline.
+pub fn glyph_mesh(face: &ttf_parser::Face, glyph_id: ttf_parser::GlyphId) -> Option<GlyphMesh> {
+    GlyphMeshBuilder::new().build(face, glyph_id)
+}
+
+/// Tessellated glyphs by face and glyph id, so repeated glyphs are only triangulated once.
+///
+/// Faces are told apart by address, so a cache must not be used with faces other than the ones it
+/// was filled from once those are dropped.
+#[derive(Default)]
+pub struct GlyphCache {
+    meshes: HashMap<(usize, ttf_parser::GlyphId, Option<i32>), Option<GlyphMesh>>,
+    lookups: u64,
+    builds: u64,
+}
+
+impl GlyphCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the glyph's mesh, tessellating it only on first use. Glyphs without an outline are
+    /// cached as `None` as well.
+    pub fn get_or_build(&mut self, face: &ttf_parser::Face, glyph_id: ttf_parser::GlyphId) -> Option<GlyphMesh> {
+        self.lookups += 1;
+        self.meshes.entry((face as *const _ as usize, glyph_id, None))
+            .or_insert_with(|| {
+                self.builds += 1;
+                GlyphMeshBuilder::new().build(face, glyph_id)
+            })
+            .clone()
+    }
+
+    /// Like [`GlyphCache::get_or_build`], with curves subdivided for `tolerance` font units, see
+    /// [`GlyphMeshBuilder::with_curve_tolerance`]. Tolerances are rounded down to powers of two, so
+    /// meshes are shared between similar font sizes.
+    pub fn get_or_build_with_tolerance(&mut self, face: &ttf_parser::Face, glyph_id: ttf_parser::GlyphId, tolerance: f32) -> Option<GlyphMesh> {
+        let exponent = tolerance_exponent(tolerance);
+        self.lookups += 1;
+        self.meshes.entry((face as *const _ as usize, glyph_id, Some(exponent)))
+            .or_insert_with(|| {
+                self.builds += 1;
+                GlyphMeshBuilder::new().with_curve_tolerance(2f32.powi(exponent)).build(face, glyph_id)
+            })
+            .clone()
+    }
+
+    /// Tessellates all `glyph_ids` not cached yet for [`GlyphCache::get_or_build_with_tolerance`].
+    /// Glyphs are independent, so with the `rayon` feature they are tessellated in parallel.
+    ///
+    /// Only counts the tessellated glyphs as misses, the following lookups count the hits.
+    pub fn prepare_with_tolerance(&mut self, face: &ttf_parser::Face, glyph_ids: &[ttf_parser::GlyphId], tolerance: f32) {
+        let exponent = tolerance_exponent(tolerance);
+        let face_key = face as *const _ as usize;
+        let mut missing = glyph_ids.iter()
+            .copied()
+            .filter(|glyph_id| !self.meshes.contains_key(&(face_key, *glyph_id, Some(exponent))))
+            .collect::<Vec<_>>();
+        missing.sort_unstable();
+        missing.dedup();
+        self.builds += missing.len() as u64;
+        let build = |glyph_id| {
+            (glyph_id, GlyphMeshBuilder::new().with_curve_tolerance(2f32.powi(exponent)).build(face, glyph_id))
+        };
+        #[cfg(feature = "rayon")]
+        let meshes = {
+            use rayon::prelude::*;
+            missing.into_par_iter().map(build).collect::<Vec<_>>()
+        };
+        #[cfg(not(feature = "rayon"))]
+        let meshes = missing.into_iter().map(build).collect::<Vec<_>>();
+        for (glyph_id, mesh) in meshes {
+            self.meshes.insert((face_key, glyph_id, Some(exponent)), mesh);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.meshes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.meshes.is_empty()
+    }
+
+    /// Lookups answered without tessellating, since the cache was created
+    pub fn hits(&self) -> u64 {
+        self.lookups.saturating_sub(self.builds)
+    }
+
+    /// Glyphs tessellated since the cache was created
+    pub fn misses(&self) -> u64 {
+        self.builds
+    }
+
+    pub fn clear(&mut self) {
+        self.meshes.clear();
+    }
+}
+
+/// Cache bucket of a curve tolerance, the exponent of the next lower power of two
+fn tolerance_exponent(tolerance: f32) -> i32 {
+    tolerance.max(f32::EPSILON).log2().floor() as i32
+}
+
+/// Appends an axis aligned, counter-clockwise rectangle of solid fill.
+fn push_rect(vertices: &mut Vec<GlyphVertex>, indices: &mut Vec<u32>, min: (f32, f32), max: (f32, f32), color_index: u32) {
+    let index = vertices.len() as u32;
+    for (x, y) in [(min.0, min.1), (max.0, min.1), (max.0, max.1), (min.0, max.1)] {
+        vertices.push(GlyphVertex {
+            position: [x, y, 0.0],
+            uv: [0.0, 0.0],
+            metadata: 0,
+            color_index,
+            paint: [0.0; 4],
+        });
+    }
+    indices.extend_from_slice(&[index, index + 1, index + 2, index, index + 2, index + 3]);
+}
+
+/// Appends a stroke of `half_width` to both sides of each closed contour, with mitered joins.
+fn push_stroke(vertices: &mut Vec<GlyphVertex>, indices: &mut Vec<u32>, contours: &[Vec<(f32, f32)>], offset: (f32, f32), half_width: f32, color_index: u32) {
+    for contour in contours.iter().filter(|contour| contour.len() >= 2) {
+        let count = contour.len();
+        let normal = |from: (f32, f32), to: (f32, f32)| {
+            let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+            let length = (dx * dx + dy * dy).sqrt().max(f32::EPSILON);
+            (-dy / length, dx / length)
+        };
+        // Offset of each point along the averaged normal of its two edges, limited at sharp corners
+        let miters = (0..count).map(|index| {
+            let (previous, point, next) = (contour[(index + count - 1) % count], contour[index], contour[(index + 1) % count]);
+            let (n1, n2) = (normal(previous, point), normal(point, next));
+            let (mx, my) = (n1.0 + n2.0, n1.1 + n2.1);
+            let length = (mx * mx + my * my).sqrt();
+            if length < 1e-3 {
+                return (n2.0 * half_width, n2.1 * half_width);
+            }
+            let (mx, my) = (mx / length, my / length);
+            let scale = (half_width / (mx * n2.0 + my * n2.1).max(0.5)).min(half_width * 2.0);
+            (mx * scale, my * scale)
+        }).collect::<Vec<_>>();
+        for index in 0..count {
+            let next = (index + 1) % count;
+            let corner = |point: usize, side: f32| {
+                let (x, y) = contour[point];
+                (offset.0 + x + miters[point].0 * side, offset.1 + y + miters[point].1 * side)
+            };
+            let quad = [corner(index, 1.0), corner(index, -1.0), corner(next, -1.0), corner(next, 1.0)];
+            let base = vertices.len() as u32;
+            for (x, y) in quad {
+                vertices.push(GlyphVertex {
+                    position: [x, y, 0.0],
+                    uv: [0.0, 0.0],
+                    metadata: 0,
+                    color_index,
+                    paint: [0.0; 4],
+                });
+            }
+            // Back faces are culled, so emit the quad counter-clockwise whichever way the contour winds
+            if is_ccw_wind(&quad) {
+                indices.extend_from_slice(&[base, base + 1, base + 2, base, base + 2, base + 3]);
+            } else {
+                indices.extend_from_slice(&[base, base + 2, base + 1, base, base + 3, base + 2]);
+            }
+        }
+    }
+}
+
+/// Appends the outline of a rectangle as four solid bars of the given `thickness`.
+fn push_frame(vertices: &mut Vec<GlyphVertex>, indices: &mut Vec<u32>, min: (f32, f32), max: (f32, f32), thickness: f32, color_index: u32) {
+    push_rect(vertices, indices, min, (max.0, min.1 + thickness), color_index);
+    push_rect(vertices, indices, (min.0, max.1 - thickness), max, color_index);
+    push_rect(vertices, indices, min, (min.0 + thickness, max.1), color_index);
+    push_rect(vertices, indices, (max.0 - thickness, min.1), max, color_index);
+}
+
+/// Triangulates a flattened 2D ring as a fan around its first point, with counter-clockwise triangles.
+fn fan_triangulate(points: &[f32]) -> Vec<usize> {
+    let ring = points.chunks_exact(2).map(|p| (p[0], p[1])).collect::<Vec<_>>();
+    let is_ccw = is_ccw_wind(&ring);
+    (1..ring.len().saturating_sub(1)).flat_map(|i| {
+        if is_ccw { [0, i, i + 1] } else { [0, i + 1, i] }
+    }).collect()
+}
+
+fn signed_area(vertices: &[(f32, f32)]) -> f32 {
     let mut sum = 0.0;
     for index in 0..vertices.len() {
         let current = vertices[index];
         let next = vertices[(index + 1) % vertices.len()];
         sum += current.0 * next.1 - next.0 * current.1;
     }
-    sum >= 0.0
+    sum / 2.0
+}
+
+fn is_ccw_wind(vertices: &[(f32, f32)]) -> bool {
+    signed_area(vertices) >= 0.0
+}
+
+/// Even-odd point in polygon test
+fn contains(polygon: &[(f32, f32)], point: (f32, f32)) -> bool {
+    let mut inside = false;
+    for index in 0..polygon.len() {
+        let (a, b) = (polygon[index], polygon[(index + 1) % polygon.len()]);
+        if (a.1 > point.1) != (b.1 > point.1) && point.0 < a.0 + (point.1 - a.1) / (b.1 - a.1) * (b.0 - a.0) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+fn length(v: (f32, f32)) -> f32 {
+    (v.0 * v.0 + v.1 * v.1).sqrt()
+}
+
+fn cross(a: (f32, f32), b: (f32, f32)) -> f32 {
+    a.0 * b.1 - a.1 * b.0
+}
+
+/// Boolean union of the filled regions of `shapes`, each given by closed contours whose holes are
+/// found by nesting like in [`GlyphMeshBuilder::triangulate`]. Returns closed contours around the
+/// union, outer ones counter-clockwise and holes clockwise.
+fn union_contours(shapes: &[Vec<Vec<(f32, f32)>>]) -> Vec<Vec<(f32, f32)>> {
+    // With outer contours counter-clockwise and holes clockwise, the union is everything with a
+    // positive winding number
+    let mut edges = vec![];
+    for contours in shapes {
+        for (index, contour) in contours.iter().enumerate().filter(|(_, contour)| contour.len() >= 3) {
+            let depth = contours.iter()
+                .enumerate()
+                .filter(|(other, other_contour)| *other != index && other_contour.len() >= 3 && contains(other_contour, contour[0]))
+                .count();
+            let reverse = is_ccw_wind(contour) == (depth % 2 == 1);
+            for point in 0..contour.len() {
+                let (a, b) = (contour[point], contour[(point + 1) % contour.len()]);
+                if a != b {
+                    edges.push(if reverse { (b, a) } else { (a, b) });
+                }
+            }
+        }
+    }
+    if edges.is_empty() {
+        return vec![];
+    }
+
+    // Split every edge where others cross or touch it, at points computed once for both edges so
+    // the pieces share their end points exactly
+    const EPSILON: f32 = 1e-6;
+    let mut splits = vec![vec![]; edges.len()];
+    let x_range = |(a, b): ((f32, f32), (f32, f32))| (a.0.min(b.0), a.0.max(b.0));
+    let mut order = (0..edges.len()).collect::<Vec<_>>();
+    order.sort_by(|a, b| x_range(edges[*a]).0.total_cmp(&x_range(edges[*b]).0));
+    for (position, &i) in order.iter().enumerate() {
+        let (a0, a1) = edges[i];
+        for &j in &order[position + 1..] {
+            if x_range(edges[j]).0 > x_range(edges[i]).1 {
+                break;
+            }
+            let (b0, b1) = edges[j];
+            let (r, s, q) = ((a1.0 - a0.0, a1.1 - a0.1), (b1.0 - b0.0, b1.1 - b0.1), (b0.0 - a0.0, b0.1 - a0.1));
+            let denominator = cross(r, s);
+            if denominator.abs() > EPSILON * length(r) * length(s) {
+                let (t, u) = (cross(q, s) / denominator, cross(q, r) / denominator);
+                if !(-EPSILON..=1.0 + EPSILON).contains(&t) || !(-EPSILON..=1.0 + EPSILON).contains(&u) {
+                    continue;
+                }
+                let point = if t <= EPSILON {
+                    a0
+                } else if t >= 1.0 - EPSILON {
+                    a1
+                } else if u <= EPSILON {
+                    b0
+                } else if u >= 1.0 - EPSILON {
+                    b1
+                } else {
+                    (a0.0 + t * r.0, a0.1 + t * r.1)
+                };
+                if t > EPSILON && t < 1.0 - EPSILON {
+                    splits[i].push(point);
+                }
+                if u > EPSILON && u < 1.0 - EPSILON {
+                    splits[j].push(point);
+                }
+            } else if cross(q, r).abs() <= EPSILON * length(r) * length(q).max(1.0) {
+                // Collinear edges overlap where one's end points lie inside the other
+                let parameter = |point: (f32, f32), (start, end): ((f32, f32), (f32, f32))| {
+                    let direction = (end.0 - start.0, end.1 - start.1);
+                    ((point.0 - start.0) * direction.0 + (point.1 - start.1) * direction.1) / (direction.0 * direction.0 + direction.1 * direction.1)
+                };
+                for point in [b0, b1] {
+                    let t = parameter(point, edges[i]);
+                    if t > EPSILON && t < 1.0 - EPSILON {
+                        splits[i].push(point);
+                    }
+                }
+                for point in [a0, a1] {
+                    let u = parameter(point, edges[j]);
+                    if u > EPSILON && u < 1.0 - EPSILON {
+                        splits[j].push(point);
+                    }
+                }
+            }
+        }
+    }
+    let mut pieces = vec![];
+    for ((a, b), mut points) in edges.iter().copied().zip(splits) {
+        let direction = (b.0 - a.0, b.1 - a.1);
+        points.sort_by(|p, q| {
+            let along = |point: &(f32, f32)| (point.0 - a.0) * direction.0 + (point.1 - a.1) * direction.1;
+            along(p).total_cmp(&along(q))
+        });
+        points.insert(0, a);
+        points.push(b);
+        points.dedup();
+        pieces.extend(points.windows(2).map(|pair| (pair[0], pair[1])));
+    }
+
+    // Edges by the horizontal bands they span, so winding numbers only look at edges near the point
+    let (y_min, y_max) = edges.iter()
+        .flat_map(|(a, b)| [a.1, b.1])
+        .fold((f32::MAX, f32::MIN), |(min, max), y| (min.min(y), max.max(y)));
+    let band_count = (edges.len() as f32).sqrt().ceil() as usize;
+    let band_height = ((y_max - y_min) / band_count as f32).max(f32::EPSILON);
+    let band = |y: f32| (((y - y_min) / band_height) as usize).min(band_count - 1);
+    let mut bands = vec![vec![]; band_count];
+    for (index, (a, b)) in edges.iter().enumerate() {
+        for band_edges in &mut bands[band(a.1.min(b.1))..=band(a.1.max(b.1))] {
+            band_edges.push(index);
+        }
+    }
+    let winding = |point: (f32, f32)| {
+        bands[band(point.1)].iter().map(|index| {
+            let (a, b) = edges[*index];
+            let side = cross((b.0 - a.0, b.1 - a.1), (point.0 - a.0, point.1 - a.1));
+            match (a.1 <= point.1, b.1 <= point.1) {
+                (true, false) if side > 0.0 => 1,
+                (false, true) if side < 0.0 => -1,
+                _ => 0,
+            }
+        }).sum::<i32>()
+    };
+
+    // Pieces between a filled and an empty side form the outline, turned to keep the fill on the left
+    let mut boundary = pieces.into_iter().filter_map(|(a, b)| {
+        let direction = (b.0 - a.0, b.1 - a.1);
+        let middle = ((a.0 + b.0) / 2.0, (a.1 + b.1) / 2.0);
+        let nudge = (middle.0.abs().max(middle.1.abs()) * 1e-5).max(1e-3) / length(direction);
+        let left = winding((middle.0 - direction.1 * nudge, middle.1 + direction.0 * nudge)) > 0;
+        let right = winding((middle.0 + direction.1 * nudge, middle.1 - direction.0 * nudge)) > 0;
+        match (left, right) {
+            (true, false) => Some((a, b)),
+            (false, true) => Some((b, a)),
+            _ => None,
+        }
+    }).collect::<Vec<_>>();
+    // Overlapping edges of several contours leave duplicate pieces
+    let key = |point: (f32, f32)| (point.0.to_bits(), point.1.to_bits());
+    boundary.sort_by_key(|(a, b)| (key(*a), key(*b)));
+    boundary.dedup();
+
+    let mut outgoing: HashMap<(u32, u32), Vec<usize>> = HashMap::new();
+    for (index, (a, _)) in boundary.iter().enumerate() {
+        outgoing.entry(key(*a)).or_default().push(index);
+    }
+    // Angle of the turn from `incoming` into the piece, left turns positive. Turning back is least
+    // preferred
+    let turn = |incoming: (f32, f32), (a, b): ((f32, f32), (f32, f32))| {
+        let outgoing = (b.0 - a.0, b.1 - a.1);
+        let angle = cross(incoming, outgoing).atan2(incoming.0 * outgoing.0 + incoming.1 * outgoing.1);
+        if angle >= std::f32::consts::PI - EPSILON { -std::f32::consts::PI } else { angle }
+    };
+    let mut used = vec![false; boundary.len()];
+    let mut contours = vec![];
+    for start in 0..boundary.len() {
+        if used[start] {
+            continue;
+        }
+        let mut contour = vec![];
+        let mut current = start;
+        loop {
+            used[current] = true;
+            let (a, b) = boundary[current];
+            contour.push(a);
+            // Turning as far left as possible stays on the same region where regions touch in a point
+            let incoming = (b.0 - a.0, b.1 - a.1);
+            let next = outgoing.get(&key(b))
+                .into_iter()
+                .flatten()
+                .filter(|index| !used[**index] || **index == start)
+                .max_by(|x, y| turn(incoming, boundary[**x]).total_cmp(&turn(incoming, boundary[**y])));
+            match next {
+                Some(next) if *next != start => current = *next,
+                _ => break,
+            }
+        }
+        if contour.len() >= 3 {
+            contours.push(contour);
+        }
+    }
+    contours
 }
 
+#[derive(Clone, Debug)]
 pub struct TextMesh {
     pub vertices: Vec<GlyphVertex>,
-    pub indices: Vec<u16>,
+    pub indices: Vec<u32>,
 }
 
+/// Linear part and translation in pixels of an affine transform, see [`TextMeshBuilder::with_transform`]
+pub(crate) type Transform = ([[f32; 2]; 2], (f32, f32));
+
 pub struct TextMeshBuilder {
     mesh_data: Vec<(Option<GlyphMesh>, GlyphData)>,
+    line_starts: Vec<(usize, (f32, f32))>,
     font_size: FontSize,
-    position: (i32, i32)
+    dpi: f32,
+    position: (i32, i32),
+    advance_scale: f32,
+    start_pen: (f32, f32),
+    decorations: Vec<(f32, f32)>,
+    baseline_snapping: bool,
+    glyph_boxes: Option<(u32, u32)>,
+    pixel_scale: f32,
+    viewport: (u32, u32),
+    stroke: Option<(f32, u32)>,
+    shadow: Option<((f32, f32), u32)>,
+    merged_outline: bool,
+    transform: Option<Transform>,
+}
+
+impl Default for TextMeshBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TextMeshBuilder {
     pub fn new() -> Self {
         Self {
             mesh_data: vec![],
+            line_starts: vec![],
             font_size: FontSize::Pt(12),
+            dpi: FontSize::DEFAULT_DPI,
             position: (0, 0),
+            advance_scale: 1.0,
+            start_pen: (0.0, 0.0),
+            decorations: vec![],
+            baseline_snapping: false,
+            glyph_boxes: None,
+            pixel_scale: 1.0,
+            viewport: (1, 1),
+            stroke: None,
+            shadow: None,
+            merged_outline: false,
+            transform: None,
         }
     }
     
@@ -184,42 +819,343 @@ impl TextMeshBuilder {
         self
     }
 
+    /// Resolution point sizes are converted to pixels at
+    pub fn with_dpi(&mut self, dpi: f32) -> &mut Self {
+        self.dpi = dpi;
+        self
+    }
+
     pub fn with_position(&mut self, x: i32, y: i32) -> &mut Self {
         self.position.0 = x;
         self.position.1 = y;
         self
     }
 
+    pub fn with_advance_scale(&mut self, scale: f32) -> &mut Self {
+        self.advance_scale = scale;
+        self
+    }
+
+    /// Initial cursor position in font units
+    pub fn with_start_pen(&mut self, x: f32, y: f32) -> &mut Self {
+        self.start_pen = (x, y);
+        self
+    }
+
+    /// Rounds the baseline of every line to whole physical pixels, after the position and transform
+    /// are applied, while glyphs stay subpixel accurate relative to it. Transforms that tilt the
+    /// baseline leave it unsnapped.
+    pub fn with_baseline_snapping(&mut self, snap: bool) -> &mut Self {
+        self.baseline_snapping = snap;
+        self
+    }
+
+    /// Draws a one pixel frame around each glyph's advance box and ink bounds on top of the text,
+    /// using the given color indices
+    pub fn with_glyph_boxes(&mut self, advance_color_index: u32, ink_color_index: u32) -> &mut Self {
+        self.glyph_boxes = Some((advance_color_index, ink_color_index));
+        self
+    }
+
+    /// Size in physical pixels of the texture the mesh is drawn into, used to map pixel positions
+    /// to normalized device coordinates
+    pub fn with_viewport(&mut self, width: u32, height: u32) -> &mut Self {
+        self.viewport = (width.max(1), height.max(1));
+        self
+    }
+
+    /// Physical pixels per logical pixel. Positions and sizes stay logical, while rounding and
+    /// snapping happen on the physical pixel grid.
+    pub fn with_pixel_scale(&mut self, scale: f32) -> &mut Self {
+        self.pixel_scale = scale;
+        self
+    }
+
+    /// Strokes every glyph outline with `width` in font units, centered on the outline and drawn over
+    /// the fill with `color_index`, relative like the glyph color indices
+    pub fn with_stroke(&mut self, width: f32, color_index: u32) -> &mut Self {
+        self.stroke = Some((width, color_index));
+        self
+    }
+
+    /// Draws a copy of the text moved by `offset` in font units behind it with `color_index`,
+    /// relative like the glyph color indices
+    pub fn with_shadow(&mut self, offset: (f32, f32), color_index: u32) -> &mut Self {
+        self.shadow = Some((offset, color_index));
+        self
+    }
+
+    /// Fills glyphs whose outlines overlap as one region, the union of their contours, instead of
+    /// drawing them on top of each other. Their curves are flattened like [`GlyphMesh::contours`]
+    /// and the union is filled and stroked in the color of its first glyph.
+    pub fn with_merged_outline(&mut self, merged: bool) -> &mut Self {
+        self.merged_outline = merged;
+        self
+    }
+
+    /// Transforms the laid out text in pixel space around the span position, `x' = m[0][0] * x +
+    /// m[0][1] * y + translate.0` and likewise for `y'`, with `translate` in pixels
+    pub fn with_transform(&mut self, matrix: [[f32; 2]; 2], translate: (f32, f32)) -> &mut Self {
+        self.transform = Some((matrix, translate));
+        self
+    }
+
+    /// Adds a line below `position` (its top edge relative to the baseline) with the given
+    /// `thickness` across the whole run, both in font units
+    pub fn with_decoration(&mut self, position: f32, thickness: f32) -> &mut Self {
+        self.decorations.push((position, thickness));
+        self
+    }
+
+    /// Starts a new line with the next added glyph, moving the cursor to `(x, y)` in font units
+    pub fn new_line(&mut self, x: f32, y: f32) -> &mut Self {
+        self.line_starts.push((self.mesh_data.len(), (x, y)));
+        self
+    }
+
     pub fn add(&mut self, mesh: Option<GlyphMesh>, data: GlyphData) -> &mut Self {
         self.mesh_data.push((mesh, data));
         self
     }
 
-    pub fn build(mut self, face: &ttf_parser::Face, color_index: u32) -> TextMesh {
-        let size_factor = 1.0 / face.height() as f32;
+    /// Pen position in font units every glyph is drawn at
+    fn glyph_pens(&self) -> Vec<(f32, f32)> {
+        let mut cursor = self.start_pen;
+        self.mesh_data.iter().enumerate().map(|(glyph_index, (_, data))| {
+            if let Some((_, (x, y))) = self.line_starts.iter().rev().find(|(index, _)| *index == glyph_index) {
+                cursor = (*x, *y);
+            }
+            let pen = cursor;
+            cursor.0 += data.x_advance as f32 * self.advance_scale;
+            cursor.1 += data.y_advance as f32;
+            pen
+        }).collect()
+    }
+
+    /// Logical position of every glyph, its rank by cluster. Glyphs are added in visual order, which
+    /// runs backwards through right-to-left text.
+    fn logical_ranks(&self) -> Vec<usize> {
+        let mut order = (0..self.mesh_data.len()).collect::<Vec<_>>();
+        order.sort_by_key(|index| (self.mesh_data[*index].1.cluster, *index));
+        let mut ranks = vec![0; order.len()];
+        for (rank, index) in order.into_iter().enumerate() {
+            ranks[index] = rank;
+        }
+        ranks
+    }
+
+    /// Groups of at least two glyphs whose outline bounds overlap, directly or through other glyphs
+    /// of the group, each in glyph order
+    fn overlapping_glyphs(&self, pens: &[(f32, f32)]) -> Vec<Vec<usize>> {
+        let bounds = self.mesh_data.iter().zip(pens).map(|((mesh, _), pen)| {
+            let mesh = mesh.as_ref().filter(|mesh| !mesh.contours.is_empty())?;
+            Some(mesh.contours.iter().flatten().fold(
+                (f32::MAX, f32::MAX, f32::MIN, f32::MIN),
+                |(x_min, y_min, x_max, y_max), (x, y)| (x_min.min(pen.0 + x), y_min.min(pen.1 + y), x_max.max(pen.0 + x), y_max.max(pen.1 + y)),
+            ))
+        }).collect::<Vec<_>>();
+        let mut order = (0..bounds.len()).filter(|index| bounds[*index].is_some()).collect::<Vec<_>>();
+        order.sort_by(|a, b| bounds[*a].unwrap().0.total_cmp(&bounds[*b].unwrap().0));
+        // Union-find over the glyphs, sweeping along x
+        let mut parent = (0..bounds.len()).collect::<Vec<_>>();
+        fn root(parent: &mut [usize], mut index: usize) -> usize {
+            while parent[index] != index {
+                parent[index] = parent[parent[index]];
+                index = parent[index];
+            }
+            index
+        }
+        for (position, &a) in order.iter().enumerate() {
+            let (_, a_y_min, a_x_max, a_y_max) = bounds[a].unwrap();
+            for &b in &order[position + 1..] {
+                let (b_x_min, b_y_min, _, b_y_max) = bounds[b].unwrap();
+                if b_x_min > a_x_max {
+                    break;
+                }
+                if b_y_min <= a_y_max && a_y_min <= b_y_max {
+                    let (a_root, b_root) = (root(&mut parent, a), root(&mut parent, b));
+                    parent[a_root.max(b_root)] = a_root.min(b_root);
+                }
+            }
+        }
+        let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+        for index in order {
+            groups.entry(root(&mut parent, index)).or_default().push(index);
+        }
+        let mut groups = groups.into_values().filter(|group| group.len() > 1).collect::<Vec<_>>();
+        groups.iter_mut().for_each(|group| group.sort_unstable());
+        groups.sort_unstable();
+        groups
+    }
+
+    pub fn build(self, face: &ttf_parser::Face, color_index: u32) -> TextMesh {
+        // Scale from font units to physical pixels, kept separate from the NDC mapping below
+        let size_factor = self.font_size.units_to_px(face, self.dpi) * self.pixel_scale;
+        let physical_size = (self.viewport.0 as f32, self.viewport.1 as f32);
         let mut vertices: Vec<GlyphVertex> = vec![];
-        let mut indices: Vec<u16> = vec![];
-        let mut cursor = (0.0, 0.0);
-        for (mesh, data) in &mut self.mesh_data {
-            if let Some(mesh) = mesh {
-                indices.append(&mut mesh.indices.iter().map(|i| *i + (vertices.len() as u16)).collect());
-                vertices.append(&mut mesh.vertices.iter_mut().map(|v| {
-                    v.color_index = color_index;
+        let mut indices: Vec<u32> = vec![];
+        // Baseline in font units of the line every vertex belongs to, for baseline snapping
+        let mut baselines: Vec<f32> = vec![];
+        let mut cursor = self.start_pen;
+        let mut boxes = vec![];
+        // Start and end pen of every line, for decorations
+        let mut lines = vec![(cursor, cursor.0)];
+        let pens = self.glyph_pens();
+        let ranks = self.logical_ranks();
+        let merged_groups = if self.merged_outline { self.overlapping_glyphs(&pens) } else { vec![] };
+        let mut is_merged = vec![false; self.mesh_data.len()];
+        merged_groups.iter().flatten().for_each(|index| is_merged[*index] = true);
+        for (glyph_index, (mesh, data)) in self.mesh_data.iter().enumerate() {
+            // Empty lines start at the same glyph, only the last one matters
+            if let Some((_, (x, y))) = self.line_starts.iter().rev().find(|(index, _)| *index == glyph_index) {
+                cursor = (*x, *y);
+                lines.push((cursor, cursor.0));
+            }
+            if let Some((advance_color_index, ink_color_index)) = self.glyph_boxes {
+                let advance = data.x_advance as f32 * self.advance_scale;
+                boxes.push((
+                    (cursor.0, cursor.1 + face.descender() as f32),
+                    (cursor.0 + advance, cursor.1 + face.ascender() as f32),
+                    advance_color_index,
+                    cursor.1,
+                ));
+                if let Some(mesh) = mesh {
+                    boxes.push((
+                        (cursor.0 + mesh.bounds.x_min as f32, cursor.1 + mesh.bounds.y_min as f32),
+                        (cursor.0 + mesh.bounds.x_max as f32, cursor.1 + mesh.bounds.y_max as f32),
+                        ink_color_index,
+                        cursor.1,
+                    ));
+                }
+            }
+            if let (Some(mesh), false) = (mesh, is_merged[glyph_index]) {
+                indices.append(&mut mesh.indices.iter().map(|i| *i + (vertices.len() as u32)).collect());
+                vertices.extend(mesh.vertices.iter().map(|v| {
+                    let mut v = *v;
                     v.position[0] += cursor.0;
                     v.position[1] += cursor.1;
-                    v.position[0] = v.position[0] * size_factor * 1.254 * <FontSize as Into<f32>>::into(self.font_size);
-                    v.position[1] = v.position[1] * size_factor * 1.254 * <FontSize as Into<f32>>::into(self.font_size);
-                    v.position[0] = (10.0 * v.position[0]).round() / 10.0;
-                    v.position[1] = (10.0 * v.position[1]).round() / 10.0;
-                    v.position[0] = v.position[0] / TEXTURE_SIZE.0 as f32 * 2.0 - 1.0;
-                    v.position[1] = v.position[1] / TEXTURE_SIZE.1 as f32 * 2.0 - 1.0;
-                    v.position[0] += (self.position.0 as f32 / TEXTURE_SIZE.0 as f32) * 2.0;
-                    v.position[1] += (self.position.1 as f32 / TEXTURE_SIZE.1 as f32) * 2.0;
-                    *v
-                }).collect());
-            }
-            cursor.0 += data.x_advance as f32;
+                    // Logical order, turned into depth by the renderer so later characters end up on
+                    // top, also in right-to-left text
+                    v.position[2] = ranks[glyph_index] as f32;
+                    v
+                }));
+                if let Some((width, stroke_color_index)) = self.stroke {
+                    let stroke_start = vertices.len();
+                    push_stroke(&mut vertices, &mut indices, &mesh.contours, cursor, width / 2.0, stroke_color_index);
+                    vertices[stroke_start..].iter_mut().for_each(|v| v.position[2] = ranks[glyph_index] as f32);
+                }
+                baselines.resize(vertices.len(), cursor.1);
+            }
+            cursor.0 += data.x_advance as f32 * self.advance_scale;
             cursor.1 += data.y_advance as f32;
+            lines.last_mut().unwrap().1 = cursor.0;
+        }
+        for group in merged_groups {
+            let shapes = group.iter().map(|index| {
+                let (pen, mesh) = (pens[*index], self.mesh_data[*index].0.as_ref().unwrap());
+                mesh.contours.iter()
+                    .map(|contour| contour.iter().map(|(x, y)| (pen.0 + x, pen.1 + y)).collect())
+                    .collect()
+            }).collect::<Vec<_>>();
+            let contours = union_contours(&shapes);
+            let mut builder = GlyphMeshBuilder::new();
+            for contour in &contours {
+                builder.move_to(contour[0].0, contour[0].1);
+                contour[1..].iter().for_each(|(x, y)| builder.line_to(*x, *y));
+                builder.close();
+            }
+            let (mut merged_vertices, merged_indices) = builder.triangulate();
+            let first_mesh = self.mesh_data[group[0]].0.as_ref().unwrap();
+            let fill_color_index = first_mesh.vertices.first().map_or(0, |v| v.color_index);
+            for v in &mut merged_vertices {
+                v.color_index = fill_color_index;
+            }
+            let start = vertices.len();
+            indices.extend(merged_indices.iter().map(|i| *i + start as u32));
+            vertices.append(&mut merged_vertices);
+            if let Some((width, stroke_color_index)) = self.stroke {
+                push_stroke(&mut vertices, &mut indices, &contours, (0.0, 0.0), width / 2.0, stroke_color_index);
+            }
+            let rank = group.iter().map(|index| ranks[*index]).min().unwrap();
+            vertices[start..].iter_mut().for_each(|v| v.position[2] = rank as f32);
+            baselines.resize(vertices.len(), pens[group[0]].1);
+        }
+        let glyph_vertex_count = vertices.len();
+        // Decorations run from the start to the end of the pen on every line
+        for ((start_x, baseline), end_x) in &lines {
+            for (position, thickness) in &self.decorations {
+                let top = baseline + position;
+                push_rect(&mut vertices, &mut indices, (*start_x, top - thickness), (*end_x, top), 0);
+            }
+            baselines.resize(vertices.len(), *baseline);
+        }
+        // Decorations go above every glyph
+        for v in &mut vertices[glyph_vertex_count..] {
+            v.position[2] = self.mesh_data.len() as f32;
+        }
+        // The shadow copies everything drawn so far and is drawn first, at the depth of the first glyph
+        if let Some((offset, shadow_color_index)) = self.shadow {
+            let shadow_start = vertices.len();
+            vertices.extend_from_within(..shadow_start);
+            baselines.extend_from_within(..shadow_start);
+            for v in &mut vertices[shadow_start..] {
+                v.position[0] += offset.0;
+                v.position[1] += offset.1;
+                v.position[2] = 0.0;
+                v.color_index = shadow_color_index;
+                // Bitmaps cast a shadow of their alpha in the shadow color instead of a copy of their pixels,
+                // gradient layers one in the plain shadow color
+                if v.metadata & 4 != 0 {
+                    v.metadata = v.metadata & !4 | 8;
+                }
+                v.metadata = colr::solid(v.metadata);
+            }
+            let mut shadow_indices = indices.iter().map(|i| *i + shadow_start as u32).collect::<Vec<_>>();
+            shadow_indices.append(&mut indices);
+            indices = shadow_indices;
+        }
+        let text_vertex_count = vertices.len();
+        for (min, max, box_color_index, baseline) in boxes {
+            push_frame(&mut vertices, &mut indices, min, max, 1.0 / size_factor, box_color_index);
+            baselines.resize(vertices.len(), baseline);
+        }
+        // Debug boxes go above everything
+        for v in &mut vertices[text_vertex_count..] {
+            v.position[2] = self.mesh_data.len() as f32;
+        }
+        // Glyph color indices are relative to `color_index`, debug boxes use absolute ones
+        for v in &mut vertices[..text_vertex_count] {
+            v.color_index += color_index;
+        }
+        // Baselines stay horizontal unless the transform moves y with x
+        let snap_baselines = self.baseline_snapping && self.transform.is_none_or(|(m, _)| m[1][0] == 0.0);
+        let position = (self.position.0 as f32 * self.pixel_scale, self.position.1 as f32 * self.pixel_scale);
+        let round_to_tenth = |value: f32| (10.0 * value).round() / 10.0;
+        for (v, baseline) in vertices.iter_mut().zip(baselines) {
+            // Physical pixels from the bottom-left corner of the viewport
+            let (mut x, mut y) = (v.position[0] * size_factor, v.position[1] * size_factor);
+            let mut baseline = baseline * size_factor;
+            if let Some((m, translate)) = self.transform {
+                (x, y) = (
+                    m[0][0] * x + m[0][1] * y + translate.0 * self.pixel_scale,
+                    m[1][0] * x + m[1][1] * y + translate.1 * self.pixel_scale,
+                );
+                baseline = m[1][1] * baseline + translate.1 * self.pixel_scale;
+            }
+            let (x, y, baseline) = (x + position.0, y + position.1, baseline + position.1);
+            let y = if snap_baselines {
+                baseline.round() + round_to_tenth(y - baseline)
+            } else {
+                round_to_tenth(y)
+            };
+            v.position[0] = round_to_tenth(x) / physical_size.0 * 2.0 - 1.0;
+            v.position[1] = y / physical_size.1 * 2.0 - 1.0;
+        }
+        // Mirroring transforms flip the winding, which would get every triangle culled
+        if let Some((m, _)) = self.transform {
+            if m[0][0] * m[1][1] - m[0][1] * m[1][0] < 0.0 {
+                indices.chunks_exact_mut(3).for_each(|triangle| triangle.swap(1, 2));
+            }
         }
         trace!("constructed TextMesh with {} vertices and {} indices", vertices.len(), indices.len());
         TextMesh {
@@ -227,4 +1163,49 @@ impl TextMeshBuilder {
             indices,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contours_earcut_rejects_fall_back_to_a_fan() {
+        // A hairline like a zero-width stroke, where every corner is collinear so earcut finds no ear
+        let hairline = [(0.0, 0.0), (30.0, 0.0), (60.0, 0.0), (100.0, 0.0)];
+        let flat = hairline.iter().flat_map(|(x, y)| [*x, *y]).collect::<Vec<f32>>();
+        assert!(earcutr::earcut(&flat, &[], 2).unwrap().is_empty());
+
+        let mut builder = GlyphMeshBuilder::new();
+        builder.move_to(hairline[0].0, hairline[0].1);
+        for (x, y) in &hairline[1..] {
+            builder.line_to(*x, *y);
+        }
+        builder.close();
+        let (vertices, indices) = builder.triangulate();
+        assert_eq!(vertices.len(), 4);
+        assert_eq!(indices, [0, 1, 2, 0, 2, 3]);
+    }
+
+    #[test]
+    fn union_merges_overlaps_and_covered_holes() {
+        let square = |x: f32, y: f32, size: f32| vec![(x, y), (x + size, y), (x + size, y + size), (x, y + size)];
+        let union = union_contours(&[vec![square(0.0, 0.0, 2.0)], vec![square(1.0, 1.0, 2.0)]]);
+        assert_eq!(union.len(), 1);
+        assert_eq!(union[0].len(), 8);
+        assert!((signed_area(&union[0]) - 7.0).abs() < 1e-4);
+
+        // The hole of a frame is covered, clockwise input winds like counter-clockwise
+        let mut covering = square(0.5, 0.5, 3.0);
+        covering.reverse();
+        let union = union_contours(&[vec![square(0.0, 0.0, 4.0), square(1.0, 1.0, 2.0)], vec![covering]]);
+        assert_eq!(union.len(), 1);
+        assert!((signed_area(&union[0]) - 16.0).abs() < 1e-4);
+
+        // Partially covered, the rest of the hole stays a clockwise contour
+        let union = union_contours(&[vec![square(0.0, 0.0, 4.0), square(1.0, 1.0, 2.0)], vec![square(0.5, 0.5, 2.0)]]);
+        assert_eq!(union.len(), 2);
+        let area = union.iter().map(|contour| signed_area(contour)).sum::<f32>();
+        assert!((area - (16.0 - 4.0 + 2.25)).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,177 @@
+use std::ops::Range;
+use image::RgbaImage;
+use crate::colr;
+use crate::mesh::TextMesh;
+use crate::renderer::GlyphVertex;
+use crate::text::Span;
+
+/// Samples per pixel along each axis used for coverage anti-aliasing.
+const SAMPLE_GRID: usize = 4;
+
+/// Rasterizes `span` on the CPU and alpha-composites it onto `image`, with the span's origin moved
+/// to `position`.
+///
+/// Like the GPU path, `position` is measured in pixels from the bottom-left corner of the image, and
+/// every triangle is painted with its entry of [`Span::palette`]: glyph colors, color glyph layers,
+/// strokes and shadows, faded by the span's opacity. Gradient layers of color glyphs are evaluated
+/// at every pixel center. Triangles are blended in draw order.
+pub fn draw_text_into(image: &mut RgbaImage, span: &Span, position: (i32, i32)) {
+    let span = span.clone().with_position(position.0, position.1);
+    let (width, height) = (image.width(), image.height());
+    let mesh = span.generate_text_mesh(0, (width, height));
+    let palette = span.palette();
+    let mut samples = vec![0u16; width as usize * height as usize];
+    // Gradient coordinates at the pixel centers, only needed for gradient layers
+    let mut paints = vec![];
+    let triangle_paint = |triangle: usize| {
+        let v = &mesh.vertices[mesh.indices[triangle * 3] as usize];
+        (v.color_index, colr::gradient(v.metadata))
+    };
+    let triangle_count = mesh.indices.len() / 3;
+    let mut start = 0;
+    while start < triangle_count {
+        // Consecutive triangles of one color are covered together, so samples on the edges shared by
+        // the triangles of a glyph are only blended once, like within a single draw on the GPU
+        let (color_index, gradient) = triangle_paint(start);
+        let end = (start..triangle_count).find(|triangle| triangle_paint(*triangle) != (color_index, gradient)).unwrap_or(triangle_count);
+        if gradient != 0 && paints.is_empty() {
+            paints = vec![[0.0; 4]; samples.len()];
+        }
+        let touched = rasterize_triangles(&mesh, &mesh.indices[start * 3..end * 3], width, height, &mut samples, (gradient != 0).then_some(&mut paints[..]));
+        start = end;
+        let Some((columns, rows)) = touched else {
+            continue;
+        };
+        let solid = palette.get(color_index as usize).copied().unwrap_or(span.get_color());
+        for y in rows {
+            for x in columns.clone() {
+                let index = y * width as usize + x;
+                let coverage = samples[index].count_ones() as f32 / (SAMPLE_GRID * SAMPLE_GRID) as f32;
+                samples[index] = 0;
+                let color = match gradient {
+                    0 => solid,
+                    _ if coverage == 0.0 => continue,
+                    _ => match colr::ramp_position(gradient, paints[index]) {
+                        Some(position) => colr::ramp_color(&palette, color_index as usize, position),
+                        None => continue,
+                    },
+                };
+                blend_over(image.get_pixel_mut(x as u32, y as u32), color, coverage * color[3]);
+            }
+        }
+    }
+}
+
+/// Blends `color` with `alpha` over the straight alpha `pixel`
+fn blend_over(pixel: &mut image::Rgba<u8>, color: [f32; 4], alpha: f32) {
+    if alpha <= 0.0 {
+        return;
+    }
+    let dst_alpha = pixel[3] as f32 / 255.0;
+    let out_alpha = alpha + dst_alpha * (1.0 - alpha);
+    for channel in 0..3 {
+        let dst = pixel[channel] as f32 / 255.0;
+        let out = (color[channel] * alpha + dst * dst_alpha * (1.0 - alpha)) / out_alpha;
+        pixel[channel] = (out.clamp(0.0, 1.0) * 255.0).round() as u8;
+    }
+    pixel[3] = (out_alpha.clamp(0.0, 1.0) * 255.0).round() as u8;
+}
+
+/// Rasterizes a text mesh into a row-major coverage buffer of `width * height` values in `0..=1`,
+/// with row 0 at the top of the image. The mesh has to be generated for a `width` by `height` viewport.
+///
+/// Triangles are culled and curve triangles are evaluated exactly like `glyph.wgsl` does, so the
+/// result matches the GPU output up to anti-aliasing.
+pub fn rasterize(mesh: &TextMesh, width: u32, height: u32) -> Vec<f32> {
+    let mut samples = vec![0u16; width as usize * height as usize];
+    rasterize_triangles(mesh, &mesh.indices, width, height, &mut samples, None);
+    samples.iter()
+        .map(|mask| mask.count_ones() as f32 / (SAMPLE_GRID * SAMPLE_GRID) as f32)
+        .collect()
+}
+
+/// Sets the bits of the samples covered by `triangles`, indices into the mesh's vertices, in the
+/// row-major sample masks of every pixel. With `paints`, the vertex `paint` coordinates are
+/// interpolated to the center of every covered pixel there. Returns the columns and (top-down) rows
+/// touched, `None` if no triangle reaches into the image.
+fn rasterize_triangles(
+    mesh: &TextMesh,
+    triangles: &[u32],
+    width: u32,
+    height: u32,
+    samples: &mut [u16],
+    mut paints: Option<&mut [[f32; 4]]>,
+) -> Option<(Range<usize>, Range<usize>)> {
+    let to_pixels = |v: &GlyphVertex| (
+        (v.position[0] + 1.0) / 2.0 * width as f32,
+        (v.position[1] + 1.0) / 2.0 * height as f32,
+    );
+    let (width, height) = (width as usize, height as usize);
+    let mut touched: Option<(Range<usize>, Range<usize>)> = None;
+    for triangle in triangles.chunks_exact(3) {
+        let vertices = [
+            &mesh.vertices[triangle[0] as usize],
+            &mesh.vertices[triangle[1] as usize],
+            &mesh.vertices[triangle[2] as usize],
+        ];
+        let [a, b, c] = vertices.map(to_pixels);
+        // Back face culling, counter-clockwise triangles are front facing
+        let area = (b.0 - a.0) * (c.1 - a.1) - (c.0 - a.0) * (b.1 - a.1);
+        if area <= 0.0 {
+            continue;
+        }
+        let is_curve = vertices[0].metadata & 2 > 0;
+        let is_inverse = vertices[0].metadata & 1 > 0;
+
+        let x_min = a.0.min(b.0).min(c.0).floor().max(0.0) as usize;
+        let x_max = (a.0.max(b.0).max(c.0).ceil().max(0.0) as usize).min(width);
+        let y_min = a.1.min(b.1).min(c.1).floor().max(0.0) as usize;
+        let y_max = (a.1.max(b.1).max(c.1).ceil().max(0.0) as usize).min(height);
+        if x_min >= x_max || y_min >= y_max {
+            continue;
+        }
+        // Flipped into image rows, the mesh is y-up
+        let (columns, rows) = (x_min..x_max, height - y_max..height - y_min);
+        touched = Some(match touched {
+            None => (columns, rows),
+            Some((c, r)) => (c.start.min(columns.start)..c.end.max(columns.end), r.start.min(rows.start)..r.end.max(rows.end)),
+        });
+        for y in y_min..y_max {
+            for x in x_min..x_max {
+                let mut mask = 0u16;
+                for sample in 0..SAMPLE_GRID * SAMPLE_GRID {
+                    let p = (
+                        x as f32 + ((sample % SAMPLE_GRID) as f32 + 0.5) / SAMPLE_GRID as f32,
+                        y as f32 + ((sample / SAMPLE_GRID) as f32 + 0.5) / SAMPLE_GRID as f32,
+                    );
+                    let w0 = ((b.0 - p.0) * (c.1 - p.1) - (c.0 - p.0) * (b.1 - p.1)) / area;
+                    let w1 = ((c.0 - p.0) * (a.1 - p.1) - (a.0 - p.0) * (c.1 - p.1)) / area;
+                    let w2 = 1.0 - w0 - w1;
+                    if w0 < 0.0 || w1 < 0.0 || w2 < 0.0 {
+                        continue;
+                    }
+                    if is_curve {
+                        let u = w0 * vertices[0].uv[0] + w1 * vertices[1].uv[0] + w2 * vertices[2].uv[0];
+                        let v = w0 * vertices[0].uv[1] + w1 * vertices[1].uv[1] + w2 * vertices[2].uv[1];
+                        if (is_inverse && v < u * u) || (!is_inverse && v >= u * u) {
+                            continue;
+                        }
+                    }
+                    mask |= 1 << sample;
+                }
+                samples[(height - 1 - y) * width + x] |= mask;
+                if let (Some(paints), true) = (paints.as_deref_mut(), mask != 0) {
+                    // Gradient coordinates are affine over the whole layer, so the center may lie
+                    // outside the triangle
+                    let p = (x as f32 + 0.5, y as f32 + 0.5);
+                    let w0 = ((b.0 - p.0) * (c.1 - p.1) - (c.0 - p.0) * (b.1 - p.1)) / area;
+                    let w1 = ((c.0 - p.0) * (a.1 - p.1) - (a.0 - p.0) * (c.1 - p.1)) / area;
+                    let w2 = 1.0 - w0 - w1;
+                    paints[(height - 1 - y) * width + x] = [0, 1, 2, 3]
+                        .map(|i| w0 * vertices[0].paint[i] + w1 * vertices[1].paint[i] + w2 * vertices[2].paint[i]);
+                }
+            }
+        }
+    }
+    touched
+}
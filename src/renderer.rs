@@ -1,20 +1,36 @@
-use log::info;
+use log::{info, warn};
 use wgpu::util::DeviceExt;
-use crate::mesh::TextMesh;
-use crate::text::Span;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use crate::colr;
+use crate::mesh::{GlyphCache, TextMesh};
+use crate::sdf::pack_shelves;
+use crate::shaper::ShapeCache;
+use crate::text::{FontSize, Span};
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+#[derive(Copy, Clone, Debug)]
 pub struct GlyphVertex {
     pub position: [f32; 3],
     pub uv: [f32; 2],
     pub metadata: i32,
-    pub color_index: u32
+    pub color_index: u32,
+    /// Where the vertex lies in the gradient it is filled with, for color glyph layers with a
+    /// gradient in bits 4 to 8 of `metadata`
+    pub paint: [f32; 4],
 }
 
+// SAFETY: `repr(C)` with only 4-byte fields, so there is no padding and every bit pattern is valid.
+// Implemented by hand, the padding check the derive generates trips the `dead_code` lint.
+unsafe impl bytemuck::Zeroable for GlyphVertex {}
+unsafe impl bytemuck::Pod for GlyphVertex {}
+
 impl GlyphVertex {
-    const ATTRIBUTES: [wgpu::VertexAttribute; 4] =
-        wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32x2, 2 => Sint32, 3 => Uint32];
+    const ATTRIBUTES: [wgpu::VertexAttribute; 5] =
+        wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32x2, 2 => Sint32, 3 => Uint32, 4 => Float32x4];
 
     pub fn desc() -> wgpu::VertexBufferLayout<'static> {
         use std::mem;
@@ -34,6 +50,19 @@ pub enum AAMode {
     MSAAx2,
     MSAAx4,
     MSAAx8,
+    /// Renders at three times the horizontal resolution and gives every color channel the coverage
+    /// of its subpixel, for LCD screens with horizontal RGB stripes. The output only looks right
+    /// when displayed 1:1 on such a screen.
+    ///
+    /// Output that isn't shown directly, textures from [`TextureRenderer::render_to_texture`] and
+    /// renders on a transparent background, falls back to grayscale [`AAMode::SSAAx2`] so no color
+    /// fringes end up in masks or atlases, see [`TextureRenderer::with_subpixel_aa`].
+    SubpixelLCD,
+    /// Renders at twice the resolution on both axes and averages every 2x2 block of pixels. Unlike
+    /// MSAA this also smooths the curves evaluated in the fragment shader.
+    SSAAx2,
+    /// Like [`AAMode::SSAAx2`] with a 4x4 block per pixel
+    SSAAx4,
 }
 
 impl AAMode {
@@ -43,21 +72,312 @@ impl AAMode {
             AAMode::MSAAx2 => 2,
             AAMode::MSAAx4 => 4,
             AAMode::MSAAx8 => 8,
+            AAMode::SubpixelLCD | AAMode::SSAAx2 | AAMode::SSAAx4 => 1,
         }
     }
 
+    /// Factor the resolution is multiplied with on both axes for supersampling modes
+    pub fn supersampling_factor(&self) -> Option<u32> {
+        match self {
+            AAMode::SSAAx2 => Some(2),
+            AAMode::SSAAx4 => Some(4),
+            _ => None,
+        }
+    }
+
+    /// WebGPU only guarantees 1 and 4 samples, other sample counts depend on the adapter and need
+    /// `TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES`.
     pub fn needs_extra_feature(&self) -> bool {
         match self {
             AAMode::Disabled => false,
             AAMode::MSAAx2 => true,
             AAMode::MSAAx4 => false,
             AAMode::MSAAx8 => true,
+            AAMode::SubpixelLCD | AAMode::SSAAx2 | AAMode::SSAAx4 => false,
+        }
+    }
+}
+
+/// Filter supersampled spans are reduced to the output resolution with, see
+/// [`TextureRenderer::with_downsample_filter`]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum DownsampleFilter {
+    /// Averages the block of samples behind each pixel, the sharpest filter without ringing.
+    #[default]
+    Box,
+    /// Weights samples linearly by their distance to the pixel center, reaching into the neighbouring
+    /// pixels. Smoother edges, slightly blurrier stems.
+    Tent,
+    /// Two-lobed Lanczos, keeps stems crisp while reducing aliasing further than the box filter.
+    /// Can ring next to high contrast edges, results are clamped to valid colors.
+    Lanczos,
+}
+
+impl DownsampleFilter {
+    /// Index of the filter in `fs_downsample`
+    fn shader_index(&self) -> u32 {
+        match self {
+            DownsampleFilter::Box => 0,
+            DownsampleFilter::Tent => 1,
+            DownsampleFilter::Lanczos => 2,
+        }
+    }
+}
+
+/// Alpha representation of the pixels returned by [`TextureRenderer::render`]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum AlphaMode {
+    /// Color channels are independent of alpha, as expected by PNG and most image tools.
+    #[default]
+    Straight,
+    /// Color channels are already multiplied by alpha, as expected by GPU compositing.
+    Premultiplied,
+}
+
+/// Describes a texture returned by [`TextureRenderer::render_to_texture`], for binding it correctly
+/// in a consuming pipeline.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TextureInfo {
+    pub width: u32,
+    pub height: u32,
+    pub format: wgpu::TextureFormat,
+    /// Always 1, multisampled rendering is already resolved
+    pub sample_count: u32,
+    /// Whether the format decodes sRGB on sampling, otherwise values are stored as is
+    pub is_srgb: bool,
+    /// The texture always holds premultiplied alpha, independent of the configured alpha mode
+    pub alpha_mode: AlphaMode,
+}
+
+/// Which graphics adapter [`TextureRenderer::new_with_adapter`] renders with
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AdapterOptions {
+    backends: wgpu::Backends,
+    power_preference: wgpu::PowerPreference,
+    software: bool,
+}
+
+impl Default for AdapterOptions {
+    fn default() -> Self {
+        Self {
+            backends: wgpu::Backends::PRIMARY,
+            power_preference: wgpu::PowerPreference::None,
+            software: false,
+        }
+    }
+}
+
+impl AdapterOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Backends adapters are searched on, [`wgpu::Backends::PRIMARY`] by default
+    pub fn with_backends(mut self, backends: wgpu::Backends) -> Self {
+        self.backends = backends;
+        self
+    }
+
+    /// Prefers discrete GPUs for [`wgpu::PowerPreference::HighPerformance`] and integrated ones for
+    /// [`wgpu::PowerPreference::LowPower`], otherwise the first suitable adapter is used.
+    pub fn with_power_preference(mut self, power_preference: wgpu::PowerPreference) -> Self {
+        self.power_preference = power_preference;
+        self
+    }
+
+    /// Only considers software rasterizers like llvmpipe, for reproducible output in CI. Also
+    /// searches the GL backend, where llvmpipe is usually exposed.
+    pub fn with_software(mut self, software: bool) -> Self {
+        self.software = software;
+        self
+    }
+
+    /// Whether the options allow `adapter`, before checking its features
+    fn allows(&self, adapter: &wgpu::Adapter) -> bool {
+        !self.software || adapter.get_info().device_type == wgpu::DeviceType::Cpu
+    }
+
+    /// Ranks an adapter by the power preference, lower is better
+    fn rank(&self, adapter: &wgpu::Adapter) -> u8 {
+        match (self.power_preference, adapter.get_info().device_type) {
+            (wgpu::PowerPreference::HighPerformance, wgpu::DeviceType::DiscreteGpu) => 0,
+            (wgpu::PowerPreference::HighPerformance, wgpu::DeviceType::IntegratedGpu) => 1,
+            (wgpu::PowerPreference::LowPower, wgpu::DeviceType::IntegratedGpu) => 0,
+            (wgpu::PowerPreference::LowPower, wgpu::DeviceType::DiscreteGpu) => 1,
+            (wgpu::PowerPreference::None, _) => 0,
+            _ => 2,
+        }
+    }
+
+    fn search_backends(&self) -> wgpu::Backends {
+        if self.software { self.backends | wgpu::Backends::GL } else { self.backends }
+    }
+}
+
+/// Debug visualizations replacing the regular glyph output
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum DebugMode {
+    #[default]
+    None,
+    /// Every rasterized triangle adds a constant amount of brightness on a black background, so
+    /// areas covered by overlapping fill and bezier triangles show up brighter.
+    Overdraw,
+    /// Frames every glyph's advance box in blue and its ink bounds in red, drawn over the text.
+    /// Only applies to spans, meshes laid out ahead of time are drawn as they are.
+    GlyphBoxes,
+}
+
+impl DebugMode {
+    /// Brightness added per triangle covering a pixel in [`DebugMode::Overdraw`]
+    const OVERDRAW_STEP: f64 = 0.1;
+    /// Frame colors of the advance and ink boxes in [`DebugMode::GlyphBoxes`]
+    const ADVANCE_BOX_COLOR: [f32; 4] = [0.0, 0.0, 1.0, 0.7];
+    const INK_BOX_COLOR: [f32; 4] = [1.0, 0.0, 0.0, 0.7];
+}
+
+/// Cache statistics of a [`TextureRenderer`], counted since it was created
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RenderStats {
+    /// Glyph meshes reused from the glyph cache
+    pub glyph_hits: u64,
+    /// Glyphs tessellated
+    pub glyph_misses: u64,
+    /// Runs with the same text, face and shaping options as an earlier run of the same render
+    pub run_hits: u64,
+    /// Runs shaped
+    pub run_misses: u64,
+    /// HarfBuzz fonts created, one per distinct face
+    pub shapers: usize,
+}
+
+/// Failure to set up a [`TextureRenderer`]
+#[derive(Debug)]
+pub enum RendererError {
+    /// No graphics adapter is available at all.
+    NoAdapter,
+    /// None of the adapters matches the [`AdapterOptions`] and there is no software adapter to fall
+    /// back to, holds a description of each adapter found.
+    NoSuitableAdapter(Vec<String>),
+    DeviceRequest(wgpu::RequestDeviceError),
+    /// The adapter can't render with the requested sample count, holds the supported ones.
+    UnsupportedSampleCount { requested: u32, supported: Vec<u32> },
+}
+
+impl std::fmt::Display for RendererError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RendererError::NoAdapter => write!(f, "no graphics adapter found"),
+            RendererError::NoSuitableAdapter(adapters) => {
+                write!(f, "no adapter matches the options and no software adapter is available, found: {}", adapters.join(", "))
+            }
+            RendererError::DeviceRequest(error) => write!(f, "failed to request device: {error}"),
+            RendererError::UnsupportedSampleCount { requested, supported } => {
+                write!(f, "{requested}x multisampling is not supported, supported sample counts: {supported:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RendererError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RendererError::DeviceRequest(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Everything a render pipeline is built from besides the renderer's fixed shaders and layouts
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+enum PipelineKey {
+    Glyph {
+        format: wgpu::TextureFormat,
+        sample_count: u32,
+        debug_mode: DebugMode,
+        wireframe: bool,
+        depth: bool,
+    },
+    Composite {
+        format: wgpu::TextureFormat,
+        fragment_entry_point: &'static str,
+        blend: wgpu::BlendState,
+    },
+}
+
+/// Unmaps a mapped buffer when dropped, so a failure while reading it can't leave it mapped
+struct UnmapOnDrop<'b>(&'b wgpu::Buffer);
+
+impl Drop for UnmapOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.unmap();
+    }
+}
+
+/// Text queued for the next render
+enum QueuedText<'r> {
+    Span(Box<Span<'r>>),
+    /// Mesh laid out ahead of time, e.g. by a [`TextBlock`](crate::block::TextBlock)
+    Mesh {
+        mesh: TextMesh,
+        colors: Vec<[f32; 4]>,
+        aa_mode: Option<AAMode>,
+    },
+}
+
+impl<'r> QueuedText<'r> {
+    fn aa_mode(&self) -> Option<AAMode> {
+        match self {
+            QueuedText::Span(span) => span.get_aa_mode(),
+            QueuedText::Mesh { aa_mode, .. } => *aa_mode,
+        }
+    }
+
+    /// Layer set with [`Span::with_z`], meshes are on the default layer
+    fn z(&self) -> f32 {
+        match self {
+            QueuedText::Span(span) => span.get_z(),
+            QueuedText::Mesh { .. } => 0.0,
+        }
+    }
+
+    /// Colors the mesh's color indices refer to, relative to the index it is built with
+    fn palette(&self) -> Vec<[f32; 4]> {
+        match self {
+            QueuedText::Span(span) => span.palette(),
+            QueuedText::Mesh { colors, .. } => colors.clone(),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn text_mesh(
+        &self,
+        color_index: u32,
+        glyph_boxes: Option<(u32, u32)>,
+        viewport: (u32, u32),
+        pixel_scale: f32,
+        dpi: f32,
+        glyph_cache: &mut GlyphCache,
+        shape_cache: &mut ShapeCache<'r>,
+        bitmaps: &mut Vec<image::RgbaImage>,
+    ) -> TextMesh {
+        match self {
+            QueuedText::Span(span) if span.get_dpi().is_none() => {
+                span.clone().with_dpi(dpi).build_text_mesh(color_index, glyph_boxes, viewport, pixel_scale, glyph_cache, shape_cache, Some(bitmaps))
+            }
+            QueuedText::Span(span) => span.build_text_mesh(color_index, glyph_boxes, viewport, pixel_scale, glyph_cache, shape_cache, Some(bitmaps)),
+            QueuedText::Mesh { mesh, .. } => {
+                let mut mesh = mesh.clone();
+                mesh.vertices.iter_mut().for_each(|v| v.color_index += color_index);
+                mesh
+            }
         }
     }
 }
 
 /// Holds state for the render
 pub struct TextureRenderer<'r> {
+    /// Never used after creating the adapter, kept for as long as the device created from it
+    #[allow(dead_code)]
     instance: wgpu::Instance,
     adapter: wgpu::Adapter,
     device: wgpu::Device,
@@ -65,61 +385,99 @@ pub struct TextureRenderer<'r> {
     render_texture: wgpu::Texture,
     render_texture_view: wgpu::TextureView,
     output_buffer: wgpu::Buffer,
-    pipeline: wgpu::RenderPipeline,
+    shader: wgpu::ShaderModule,
+    composite_shader: wgpu::ShaderModule,
+    pipeline_layout: wgpu::PipelineLayout,
     color_bind_group_layout: wgpu::BindGroupLayout,
-    spans: Vec<Span<'r>>,
-    aa_mode: AAMode
+    spans: Vec<QueuedText<'r>>,
+    aa_mode: AAMode,
+    debug_mode: DebugMode,
+    wireframe: bool,
+    band: Option<(u32, u32)>,
+    background: [f32; 4],
+    alpha_mode: AlphaMode,
+    downsample_filter: DownsampleFilter,
+    subpixel_aa: Option<bool>,
+    device_pixel_ratio: f32,
+    dpi: f32,
+    // Faces are borrowed for the renderer's whole lifetime, so their addresses stay valid cache keys
+    glyph_cache: RefCell<GlyphCache>,
+    // Shapers per face for the same reason, shaped runs are only kept for one render
+    shape_cache: RefCell<ShapeCache<'r>>,
+    // Pipelines are created on first use and kept, renders only differ in a few of their settings
+    pipelines: RefCell<HashMap<PipelineKey, Arc<wgpu::RenderPipeline>>>,
 }
 
 impl<'r> TextureRenderer<'r> {
-    pub fn new(width: u32, height: u32, mode: AAMode) -> Self {
+    pub fn new(width: u32, height: u32, mode: AAMode) -> Result<Self, RendererError> {
+        Self::new_with_adapter(width, height, mode, AdapterOptions::default())
+    }
+
+    /// Like [`TextureRenderer::new`], choosing the adapter according to `options`
+    pub fn new_with_adapter(width: u32, height: u32, mode: AAMode, options: AdapterOptions) -> Result<Self, RendererError> {
         // Setup wgpu
+        // GL is always searched, it is where software rasterizers are usually found for the fallback
+        let backends = options.search_backends();
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-            backends: wgpu::Backends::PRIMARY,
+            backends: backends | wgpu::Backends::GL,
             ..Default::default()
         });
         let adapter = {
-            let mut all_adapters = instance.enumerate_adapters(wgpu::Backends::PRIMARY);
-            let adapter_index = all_adapters.iter()
-                .position(|adapter| adapter.features().contains(wgpu::Features::POLYGON_MODE_LINE)).unwrap();
+            let mut all_adapters = instance.enumerate_adapters(backends | wgpu::Backends::GL);
+            if all_adapters.is_empty() {
+                return Err(RendererError::NoAdapter);
+            }
+            let best = |allows: &dyn Fn(&wgpu::Adapter) -> bool| all_adapters.iter()
+                .enumerate()
+                .filter(|(_, adapter)| allows(adapter))
+                .min_by_key(|(_, adapter)| options.rank(adapter))
+                .map(|(index, _)| index);
+            let requested = best(&|adapter| {
+                backends.contains(adapter.get_info().backend.into()) && options.allows(adapter)
+            });
+            // Headless machines like CI runners often only have a software rasterizer
+            let fallback = || {
+                let index = best(&|adapter| adapter.get_info().device_type == wgpu::DeviceType::Cpu)?;
+                warn!("no adapter matches the options, falling back to software rendering");
+                Some(index)
+            };
+            let Some(adapter_index) = requested.or_else(fallback) else {
+                let found = all_adapters.iter().map(|adapter| {
+                    let info = adapter.get_info();
+                    format!("{} ({:?})", info.name, info.backend)
+                }).collect();
+                return Err(RendererError::NoSuitableAdapter(found));
+            };
             all_adapters.remove(adapter_index)
         };
+        let adapter_info = adapter.get_info();
+        info!("using adapter {} ({:?}, {:?})", adapter_info.name, adapter_info.backend, adapter_info.device_type);
         info!("{:?}", adapter.get_downlevel_capabilities());
+        let has_extra_feature = adapter.features().contains(wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES);
+        let supported = supported_sample_counts(&adapter, wgpu::TextureFormat::Rgba8Unorm, has_extra_feature);
+        info!("supported sample counts: {:?}", supported);
+        if !supported.contains(&mode.to_sample_count()) {
+            return Err(RendererError::UnsupportedSampleCount { requested: mode.to_sample_count(), supported });
+        }
         let (device, queue) = pollster::block_on(adapter
             .request_device(&wgpu::DeviceDescriptor {
                 label: None,
-                required_features: wgpu::Features::default() |
-                    wgpu::Features::POLYGON_MODE_LINE |
-                    wgpu::Features::POLYGON_MODE_POINT |
+                // Only needed by the wireframe view, which is unavailable without it
+                required_features: adapter.features() & wgpu::Features::POLYGON_MODE_LINE |
                     if mode.needs_extra_feature() {
                         wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES
-                    } else { wgpu::Features::empty() },
+                    } else {
+                        // Spans may still request other sample counts, so enable it whenever possible
+                        adapter.features() & wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES
+                    },
                 required_limits: Default::default(),
             }, None)
-        ).unwrap();
+        ).map_err(RendererError::DeviceRequest)?;
 
-        // Create texture to write to
-        let texture_desc = wgpu::TextureDescriptor {
-            size: wgpu::Extent3d {
-                width,
-                height,
-                depth_or_array_layers: 1,
-            },
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Rgba8Unorm,
-            usage: wgpu::TextureUsages::COPY_SRC
-                | wgpu::TextureUsages::RENDER_ATTACHMENT
-                | wgpu::TextureUsages::TEXTURE_BINDING
-            ,
-            label: None,
-            view_formats: &[wgpu::TextureFormat::Rgba8Unorm],
-        };
-        let texture = device.create_texture(&texture_desc);
-        let texture_view = texture.create_view(&Default::default());
+        let (texture, texture_view) = create_target(&device, width, height, wgpu::TextureFormat::Rgba8Unorm);
+        let output_buffer = create_output_buffer(&device, output_buffer_size(&texture));
 
-        // Create color storage layout
+        // Create color storage and bitmap atlas layout
         let color_bind_group_layout = device.create_bind_group_layout(
             &wgpu::BindGroupLayoutDescriptor {
                 label: Some("color_bind_group_layout"),
@@ -135,54 +493,256 @@ impl<'r> TextureRenderer<'r> {
                             min_binding_size: None,
                         },
                         count: None,
-                    }
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
                 ],
             }
         );
 
-        // Create the output buffer
-        let u32_size = std::mem::size_of::<u32>() as u32;
-        let output_buffer_size = ((u32_size * width) * height) as wgpu::BufferAddress;
-        let output_buffer_desc = wgpu::BufferDescriptor {
-            size: output_buffer_size,
-            usage: wgpu::BufferUsages::COPY_DST
-                // this tells wpgu that we want to read this buffer from the cpu
-                | wgpu::BufferUsages::MAP_READ,
-            label: None,
-            mapped_at_creation: false,
-        };
-        let output_buffer = device.create_buffer(&output_buffer_desc);
-
         // Compile and create shader modules
         let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
             label: None,
             source: wgpu::ShaderSource::Wgsl(include_str!("shader/glyph.wgsl").into()),
         });
 
-        // Create render pipeline
+        let composite_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Composite Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("shader/composite.wgsl").into()),
+        });
+
+        // Create render pipeline layout, pipelines are created per sample count at render time
         let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("Render Pipeline Layout"),
             bind_group_layouts: &[&color_bind_group_layout],
             push_constant_ranges: &[],
         });
 
-        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        Ok(Self {
+            instance,
+            adapter,
+            device,
+            queue,
+            render_texture: texture,
+            render_texture_view: texture_view,
+            output_buffer,
+            shader,
+            composite_shader,
+            pipeline_layout: render_pipeline_layout,
+            color_bind_group_layout,
+            spans: vec![],
+            aa_mode: mode,
+            debug_mode: DebugMode::default(),
+            wireframe: false,
+            band: None,
+            background: [1.0, 1.0, 1.0, 1.0],
+            alpha_mode: AlphaMode::default(),
+            downsample_filter: DownsampleFilter::default(),
+            subpixel_aa: None,
+            device_pixel_ratio: 1.0,
+            dpi: FontSize::DEFAULT_DPI,
+            glyph_cache: RefCell::new(GlyphCache::new()),
+            shape_cache: RefCell::new(ShapeCache::new()),
+            pipelines: RefCell::new(HashMap::new()),
+        })
+    }
+
+    pub fn with_debug_mode(mut self, debug_mode: DebugMode) -> Self {
+        self.debug_mode = debug_mode;
+        self
+    }
+
+    /// Draws only the edges of the glyph mesh triangles in their span's color, showing how outlines
+    /// were split into fill and bezier triangles. Combines with the debug modes.
+    ///
+    /// Ignored with a warning if the adapter doesn't support `POLYGON_MODE_LINE`.
+    pub fn with_wireframe(mut self, wireframe: bool) -> Self {
+        if wireframe && !self.device.features().contains(wgpu::Features::POLYGON_MODE_LINE) {
+            warn!("wireframe rendering needs POLYGON_MODE_LINE, which the adapter doesn't support");
+            return self;
+        }
+        self.wireframe = wireframe;
+        self
+    }
+
+    /// Sets the color the texture is cleared to, given as sRGB encoded values with straight alpha.
+    ///
+    /// The color is converted into the render texture's color space, so `[1.0, 1.0, 1.0, 1.0]` is
+    /// visual white whether the texture is linear or sRGB.
+    pub fn with_background(mut self, color: [f32; 4]) -> Self {
+        self.background = color;
+        self
+    }
+
+    /// Sets whether rendered pixels have straight or premultiplied alpha, see [`AlphaMode`].
+    pub fn with_alpha_mode(mut self, alpha_mode: AlphaMode) -> Self {
+        self.alpha_mode = alpha_mode;
+        self
+    }
+
+    /// Sets the filter spans drawn with [`AAMode::SSAAx2`] or [`AAMode::SSAAx4`] are reduced to the
+    /// output resolution with, see [`DownsampleFilter`] (box by default).
+    pub fn with_downsample_filter(mut self, filter: DownsampleFilter) -> Self {
+        self.downsample_filter = filter;
+        self
+    }
+
+    /// Forces [`AAMode::SubpixelLCD`] on or off for every output. By default it is only used for
+    /// output meant to be displayed as is: [`TextureRenderer::render`] on an opaque background and
+    /// [`TextureRenderer::render_with_encoder`].
+    pub fn with_subpixel_aa(mut self, enabled: bool) -> Self {
+        self.subpixel_aa = Some(enabled);
+        self
+    }
+
+    /// The mode `span` is drawn with, subpixel spans fall back to grayscale supersampling unless
+    /// `subpixel` is set
+    fn effective_aa_mode(&self, span: &QueuedText, subpixel: bool) -> AAMode {
+        match span.aa_mode().unwrap_or(self.aa_mode) {
+            AAMode::SubpixelLCD if !subpixel => AAMode::SSAAx2,
+            aa_mode => aa_mode,
+        }
+    }
+
+    /// Resolution point sizes of spans without their own DPI are converted to pixels at, see
+    /// [`Span::with_dpi`].
+    pub fn with_dpi(mut self, dpi: f32) -> Self {
+        self.dpi = dpi;
+        self
+    }
+
+    /// Background color converted into the render texture's native color space. The texture holds
+    /// premultiplied alpha, which is what alpha blending straight glyph colors onto it produces.
+    fn clear_color(&self) -> wgpu::Color {
+        let [r, g, b, a] = self.native_color(self.background);
+        let a = a as f64;
+        wgpu::Color { r: r as f64 * a, g: g as f64 * a, b: b as f64 * a, a }
+    }
+
+    /// Converts an sRGB encoded color with straight alpha into the render texture's color space
+    fn native_color(&self, [r, g, b, a]: [f32; 4]) -> [f32; 4] {
+        if self.render_texture.format().is_srgb() {
+            [srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b), a]
+        } else {
+            [r, g, b, a]
+        }
+    }
+
+    /// Renders into an `Rgba8UnormSrgb` texture, so glyph edges are blended in linear light and the
+    /// returned bytes are sRGB encoded.
+    ///
+    /// Span and background colors are sRGB encoded values either way, they are converted to linear
+    /// when the texture is sRGB.
+    pub fn with_srgb(mut self, srgb: bool) -> Self {
+        let format = if srgb { wgpu::TextureFormat::Rgba8UnormSrgb } else { wgpu::TextureFormat::Rgba8Unorm };
+        let (texture, texture_view) = create_target(&self.device, self.render_texture.width(), self.render_texture.height(), format);
+        self.render_texture = texture;
+        self.render_texture_view = texture_view;
+        self
+    }
+
+    /// Renders at `ratio` physical pixels per logical pixel, like a browser's `devicePixelRatio`.
+    ///
+    /// The size passed to [`TextureRenderer::new`], span positions and sizes, bands and all
+    /// measurements stay in logical pixels, while the texture and the returned image are sized in
+    /// physical pixels.
+    pub fn with_device_pixel_ratio(mut self, ratio: f32) -> Self {
+        let logical_size = (
+            self.render_texture.width() as f32 / self.device_pixel_ratio,
+            self.render_texture.height() as f32 / self.device_pixel_ratio,
+        );
+        self.device_pixel_ratio = ratio;
+        let (texture, texture_view) = create_target(
+            &self.device,
+            ((logical_size.0 * ratio).round() as u32).max(1),
+            ((logical_size.1 * ratio).round() as u32).max(1),
+            self.render_texture.format(),
+        );
+        self.render_texture = texture;
+        self.render_texture_view = texture_view;
+        self
+    }
+
+    /// The band in physical pixel rows
+    fn physical_band(&self) -> Option<(u32, u32)> {
+        self.band.map(|(y0, y1)| {
+            let scale = |y: u32| (y as f32 * self.device_pixel_ratio).round() as u32;
+            (scale(y0), scale(y1))
+        })
+    }
+
+    /// Renders only the rows `y0..y1` of the text, in the same bottom-up pixel coordinates spans are
+    /// positioned in. The band is moved to the bottom of the texture and everything outside of it is
+    /// scissored away, so the texture only needs to be as tall as the band. Spans entirely outside of
+    /// the band are skipped before upload.
+    pub fn with_band(mut self, y0: u32, y1: u32) -> Self {
+        self.band = Some((y0, y1.max(y0)));
+        self
+    }
+
+    /// The pipeline for `key`, created with `create` on first use
+    fn cached_pipeline(&self, key: PipelineKey, create: impl FnOnce() -> wgpu::RenderPipeline) -> Arc<wgpu::RenderPipeline> {
+        self.pipelines.borrow_mut().entry(key).or_insert_with(|| Arc::new(create())).clone()
+    }
+
+    /// The glyph pipeline for the render texture's format and the current debug settings, with a
+    /// depth test if `depth` is set
+    fn glyph_pipeline(&self, sample_count: u32, depth: bool) -> Arc<wgpu::RenderPipeline> {
+        let key = PipelineKey::Glyph {
+            format: self.render_texture.format(),
+            sample_count,
+            debug_mode: self.debug_mode,
+            wireframe: self.wireframe,
+            depth,
+        };
+        self.cached_pipeline(key, || self.create_pipeline(sample_count, depth))
+    }
+
+    fn create_pipeline(&self, sample_count: u32, depth: bool) -> wgpu::RenderPipeline {
+        let (fragment_entry_point, blend) = match self.debug_mode {
+            DebugMode::None | DebugMode::GlyphBoxes if self.wireframe => ("fs_wireframe", wgpu::BlendState::ALPHA_BLENDING),
+            DebugMode::None | DebugMode::GlyphBoxes => ("fs_main", wgpu::BlendState::ALPHA_BLENDING),
+            DebugMode::Overdraw => {
+                // Adds the blend constant for every fragment, regardless of coverage
+                let additive = wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::Constant,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                };
+                ("fs_overdraw", wgpu::BlendState { color: additive, alpha: additive })
+            }
+        };
+        self.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
             label: Some("Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
+            layout: Some(&self.pipeline_layout),
             vertex: wgpu::VertexState {
-                module: &shader,
+                module: &self.shader,
                 entry_point: "vs_main",
                 buffers: &[
                     GlyphVertex::desc(),
                 ],
             },
             fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: "fs_main",
+                module: &self.shader,
+                entry_point: fragment_entry_point,
                 targets: &[Some(wgpu::ColorTargetState {
-                    format: texture_desc.format,
+                    format: self.render_texture.format(),
                     write_mask: wgpu::ColorWrites::ALL,
-                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    blend: Some(blend),
                 })],
             }),
             primitive: wgpu::PrimitiveState {
@@ -191,54 +751,340 @@ impl<'r> TextureRenderer<'r> {
                 front_face: wgpu::FrontFace::Ccw,
                 cull_mode: Some(wgpu::Face::Back),
                 unclipped_depth: false,
-                polygon_mode: wgpu::PolygonMode::Fill,
+                polygon_mode: if self.wireframe { wgpu::PolygonMode::Line } else { wgpu::PolygonMode::Fill },
                 conservative: false,
             },
-            depth_stencil: None,
+            depth_stencil: depth.then(|| wgpu::DepthStencilState {
+                format: DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
             multisample: wgpu::MultisampleState {
-                count: mode.to_sample_count(),
+                count: sample_count,
                 mask: !0,
-                alpha_to_coverage_enabled: true,
+                alpha_to_coverage_enabled: false,
             },
             multiview: None,
-        });
+        })
+    }
 
-        Self {
-            instance,
-            adapter,
-            device,
-            queue,
-            render_texture: texture,
-            render_texture_view: texture_view,
-            output_buffer,
-            pipeline: render_pipeline,
-            color_bind_group_layout,
-            spans: vec![],
-            aa_mode: mode,
-        }
+    /// The composite pipeline for the render texture's format
+    fn composite_pipeline(&self, fragment_entry_point: &'static str, blend: wgpu::BlendState) -> Arc<wgpu::RenderPipeline> {
+        let key = PipelineKey::Composite {
+            format: self.render_texture.format(),
+            fragment_entry_point,
+            blend,
+        };
+        self.cached_pipeline(key, || self.create_composite_pipeline(fragment_entry_point, blend))
+    }
+
+    /// Creates a pipeline drawing a fullscreen triangle with the `composite.wgsl` `fragment_entry_point`
+    fn create_composite_pipeline(&self, fragment_entry_point: &str, blend: wgpu::BlendState) -> wgpu::RenderPipeline {
+        self.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Composite Pipeline"),
+            layout: None,
+            vertex: wgpu::VertexState {
+                module: &self.composite_shader,
+                entry_point: "vs_fullscreen",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &self.composite_shader,
+                entry_point: fragment_entry_point,
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: self.render_texture.format(),
+                    write_mask: wgpu::ColorWrites::ALL,
+                    blend: Some(blend),
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        })
     }
 
     pub fn add_span(&mut self, mesh: Span<'r>) -> &mut Self {
-        self.spans.push(mesh);
+        self.spans.push(QueuedText::Span(Box::new(mesh)));
         self
     }
 
-    /// Returns raw image data in RgbaU8 format
-    pub fn render(self) -> Vec<u8> {
+    /// Queues an already laid out mesh, drawn in the same order as spans
+    pub(crate) fn add_mesh(&mut self, mesh: TextMesh, colors: Vec<[f32; 4]>, aa_mode: Option<AAMode>) -> &mut Self {
+        self.spans.push(QueuedText::Mesh { mesh, colors, aa_mode });
+        self
+    }
+
+    /// Reports how often glyph meshes and shaped runs were reused between spans. Spans are shaped with
+    /// one shared HarfBuzz font per face, unless they bring their own [`Span::with_shaper`].
+    pub fn stats(&self) -> RenderStats {
+        let glyph_cache = self.glyph_cache.borrow();
+        let shape_cache = self.shape_cache.borrow();
+        RenderStats {
+            glyph_hits: glyph_cache.hits(),
+            glyph_misses: glyph_cache.misses(),
+            run_hits: shape_cache.run_hits(),
+            run_misses: shape_cache.run_misses(),
+            shapers: shape_cache.shaper_count(),
+        }
+    }
+
+    pub fn device(&self) -> &wgpu::Device {
+        &self.device
+    }
+
+    pub fn queue(&self) -> &wgpu::Queue {
+        &self.queue
+    }
+
+    /// Records drawing of all queued text into a caller supplied `encoder` and `target`, then clears
+    /// the queue. Nothing is submitted, so the caller can record its own commands before or after.
+    ///
+    /// `target` has to be single-sampled, of the renderer's size and texture format. The text is
+    /// drawn over the target's existing content, except with multisampling, where resolving replaces
+    /// the whole target; use [`AAMode::Disabled`] to compose with previously recorded drawing.
+    pub fn render_with_encoder(&mut self, encoder: &mut wgpu::CommandEncoder, target: &wgpu::TextureView) {
+        self.encode_text(encoder, target, false, self.subpixel_aa.unwrap_or(true));
+        self.spans.clear();
+    }
+
+    /// Creates a depth buffer of `size` for a pass with `sample_count` samples
+    fn create_depth_view(&self, size: wgpu::Extent3d, sample_count: u32) -> wgpu::TextureView {
+        let depth_texture = self.device.create_texture(&wgpu::TextureDescriptor {
+            size,
+            mip_level_count: 1,
+            sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            format: DEPTH_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            label: Some("Depth Texture"),
+            view_formats: &[],
+        });
+        depth_texture.create_view(&Default::default())
+    }
+
+    /// Packs the embedded glyph bitmaps into one texture in the render texture's format, returning it
+    /// with the `[x, y, width, height]` of every bitmap in texture coordinates
+    fn create_bitmap_atlas(&self, bitmaps: &[image::RgbaImage]) -> (wgpu::Texture, Vec<[f32; 4]>) {
+        // Every bitmap gets a transparent border, so filtering doesn't pick up its neighbours
+        let sizes = bitmaps.iter().map(|bitmap| (bitmap.width() + 2, bitmap.height() + 2)).collect::<Vec<_>>();
+        let mut order = (0..sizes.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&index| std::cmp::Reverse(sizes[index].1));
+        let area = sizes.iter().map(|(width, height)| width * height).sum::<u32>();
+        let widest = sizes.iter().map(|(width, _)| *width).max().unwrap_or(1);
+        let mut side = ((area as f32).sqrt().ceil() as u32).max(widest).max(1).next_power_of_two();
+        let positions = loop {
+            if let Some(positions) = pack_shelves(&sizes, &order, side) {
+                break positions;
+            }
+            side *= 2;
+        };
+        let size = wgpu::Extent3d { width: side, height: side, depth_or_array_layers: 1 };
+        let texture = self.device.create_texture(&wgpu::TextureDescriptor {
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: self.render_texture.format(),
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            label: Some("Bitmap Atlas"),
+            view_formats: &[],
+        });
+        let mut atlas = image::RgbaImage::new(side, side);
+        let rects = bitmaps.iter().zip(positions).map(|(bitmap, (x, y))| {
+            image::imageops::replace(&mut atlas, bitmap, x as i64 + 1, y as i64 + 1);
+            [
+                (x + 1) as f32 / side as f32,
+                (y + 1) as f32 / side as f32,
+                bitmap.width() as f32 / side as f32,
+                bitmap.height() as f32 / side as f32,
+            ]
+        }).collect();
+        self.queue.write_texture(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            &atlas,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * side),
+                rows_per_image: Some(side),
+            },
+            size,
+        );
+        (texture, rects)
+    }
+
+    /// Records render passes drawing all queued text into `target`, clearing it first if `clear` is set.
+    /// [`AAMode::SubpixelLCD`] spans are only drawn with subpixel coverage if `subpixel` is set.
+    fn encode_text(&self, encoder: &mut wgpu::CommandEncoder, target: &wgpu::TextureView, clear: bool, subpixel: bool) {
         let mut all_vertices = vec![];
-        let mut all_indices = vec![];
+        let mut msaa_indices = vec![];
+        let mut aliased_indices = vec![];
+        let mut ssaa_indices = vec![];
+        let mut lcd_indices = vec![];
+        // Coverage mask indices of every span with an inner shadow, with its offset, blur and color
+        let mut inner_shadows = vec![];
         let mut all_colors = vec![];
-        for span in self.spans {
-            if !all_colors.contains(&span.get_color()) {
-                all_colors.push(span.get_color())
-            }
-            let TextMesh { mut vertices, indices } = span.generate_text_mesh(
-                all_colors.iter().position(|c| *c == span.get_color()).unwrap_or(0) as u32
+        // Multisampled spans share one pass at the highest requested sample count the device supports
+        let requested_sample_count = self.spans.iter()
+            .map(|span| self.effective_aa_mode(span, subpixel).to_sample_count())
+            .max()
+            .unwrap_or(1);
+        let has_extra_feature = self.device.features().contains(wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES);
+        let msaa_sample_count = supported_sample_counts(&self.adapter, self.render_texture.format(), has_extra_feature)
+            .into_iter()
+            .filter(|count| *count <= requested_sample_count)
+            .max()
+            .unwrap_or(1);
+        if msaa_sample_count != requested_sample_count {
+            warn!("{}x multisampling is not supported, falling back to {}x", requested_sample_count, msaa_sample_count);
+        }
+        // Supersampled spans share one pass at the highest requested factor the texture size allows
+        let requested_factor = self.spans.iter()
+            .filter_map(|span| self.effective_aa_mode(span, subpixel).supersampling_factor())
+            .max()
+            .unwrap_or(1);
+        let max_dimension = self.device.limits().max_texture_dimension_2d;
+        let mut ssaa_factor = requested_factor;
+        while ssaa_factor > 1 && self.render_texture.width().max(self.render_texture.height()) * ssaa_factor > max_dimension {
+            ssaa_factor /= 2;
+        }
+        if ssaa_factor != requested_factor {
+            warn!("{}x supersampling exceeds the maximum texture size, falling back to {}x", requested_factor, ssaa_factor);
+        }
+        let glyph_boxes = if self.debug_mode == DebugMode::GlyphBoxes {
+            all_colors.push(DebugMode::ADVANCE_BOX_COLOR);
+            all_colors.push(DebugMode::INK_BOX_COLOR);
+            Some((0, 1))
+        } else {
+            None
+        };
+        let mut glyph_cache = self.glyph_cache.borrow_mut();
+        let mut shape_cache = self.shape_cache.borrow_mut();
+        shape_cache.clear_runs();
+        // Embedded bitmaps of all spans, packed into one atlas below
+        let mut bitmaps = vec![];
+        // Running glyph order over all spans, turned into depth below
+        let mut glyph_order = 0.0f32;
+        // Spans are emitted by layer, so later spans and with them higher layers get a smaller depth
+        let mut layered = self.spans.iter().collect::<Vec<_>>();
+        layered.sort_by(|a, b| a.z().total_cmp(&b.z()));
+        for span in layered {
+            // Spans with the same colors share their palette entries
+            let palette = span.palette();
+            let color_index = match all_colors.windows(palette.len()).position(|colors| colors == palette.as_slice()) {
+                Some(index) => index,
+                None => {
+                    all_colors.extend_from_slice(&palette);
+                    all_colors.len() - palette.len()
+                }
+            };
+            let TextMesh { mut vertices, indices } = span.text_mesh(
+                color_index as u32,
+                glyph_boxes,
+                (self.render_texture.width(), self.render_texture.height()),
+                self.device_pixel_ratio,
+                self.dpi,
+                &mut glyph_cache,
+                &mut shape_cache,
+                &mut bitmaps,
             );
-            let last_index = all_vertices.len() as u16;
-            all_indices.append(&mut indices.iter().map(|i| *i + last_index).collect());
+            if let Some((y0, y1)) = self.physical_band() {
+                let offset = y0 as f32 / self.render_texture.height() as f32 * 2.0;
+                let top = -1.0 + (y1 - y0) as f32 / self.render_texture.height() as f32 * 2.0;
+                vertices.iter_mut().for_each(|v| v.position[1] -= offset);
+                if vertices.iter().all(|v| v.position[1] < -1.0) || vertices.iter().all(|v| v.position[1] > top) {
+                    continue;
+                }
+            }
+            if let QueuedText::Span(span) = span {
+                if let Some(inner_shadow) = span.inner_shadow() {
+                    // The mask is drawn in opaque white, so its alpha is the text's coverage
+                    let mask_color_index = match all_colors.iter().position(|color| *color == [1.0; 4]) {
+                        Some(index) => index,
+                        None => {
+                            all_colors.push([1.0; 4]);
+                            all_colors.len() - 1
+                        }
+                    };
+                    // The mask is the span's own fill triangles, without its stroke and shadow, on
+                    // copies of their vertices in the mask color
+                    let fill_colors = color_index as u32..(color_index + span.fill_color_count()) as u32;
+                    let mut mask_vertex_indices = vec![None; vertices.len()];
+                    let mut mask_indices = vec![];
+                    for triangle in indices.chunks_exact(3).filter(|triangle| fill_colors.contains(&vertices[triangle[0] as usize].color_index)) {
+                        for &index in triangle {
+                            let mask_index = *mask_vertex_indices[index as usize].get_or_insert_with(|| {
+                                let v = vertices[index as usize];
+                                all_vertices.push(GlyphVertex { color_index: mask_color_index as u32, metadata: colr::solid(v.metadata), ..v });
+                                all_vertices.len() as u32 - 1
+                            });
+                            mask_indices.push(mask_index);
+                        }
+                    }
+                    inner_shadows.push((mask_indices, inner_shadow));
+                }
+            }
+            let span_order = vertices.iter().map(|v| v.position[2]).fold(0.0, f32::max);
+            vertices.iter_mut().for_each(|v| v.position[2] += glyph_order);
+            glyph_order += span_order + 1.0;
+            // The span's indices start at 0, offset them past the vertices of all previous spans
+            let base_vertex = u32::try_from(all_vertices.len()).expect("more than u32::MAX vertices");
+            let aa_mode = self.effective_aa_mode(span, subpixel);
+            // Overdraw is counted in the target itself, so every span is drawn into it directly
+            let target = if aa_mode == AAMode::SubpixelLCD && self.debug_mode != DebugMode::Overdraw {
+                &mut lcd_indices
+            } else if aa_mode.supersampling_factor().is_some() && self.debug_mode != DebugMode::Overdraw {
+                &mut ssaa_indices
+            } else if aa_mode.to_sample_count() == 1 && msaa_sample_count > 1 {
+                &mut aliased_indices
+            } else {
+                &mut msaa_indices
+            };
+            target.append(&mut indices.iter().map(|i| *i + base_vertex).collect());
             all_vertices.append(&mut vertices);
         }
+        // Later glyphs get a smaller depth, so overlapping glyphs keep their shaping order on every
+        // backend instead of relying on primitive ordering within the draw
+        for v in &mut all_vertices {
+            v.position[2] = 1.0 - (v.position[2] + 1.0) / (glyph_order + 1.0);
+        }
+        let (bitmap_atlas, bitmap_rects) = self.create_bitmap_atlas(&bitmaps);
+        // Bitmap quads and their shadows refer to their image by index, point them at its place in the
+        // atlas instead
+        for v in all_vertices.iter_mut().filter(|v| v.metadata & 12 != 0) {
+            let [x, y, width, height] = bitmap_rects[(v.metadata >> 4) as usize];
+            v.uv = [x + v.uv[0] * width, y + v.uv[1] * height];
+            v.metadata &= 12;
+        }
+        let bitmap_view = bitmap_atlas.create_view(&Default::default());
+        let bitmap_sampler = self.device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Bitmap Sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let msaa_index_count = msaa_indices.len() as u32;
+        let aliased_index_end = msaa_index_count + aliased_indices.len() as u32;
+        let mut all_indices = msaa_indices;
+        all_indices.append(&mut aliased_indices);
+        let ssaa_index_end = aliased_index_end + ssaa_indices.len() as u32;
+        all_indices.append(&mut ssaa_indices);
+        let lcd_index_end = ssaa_index_end + lcd_indices.len() as u32;
+        all_indices.append(&mut lcd_indices);
+        let inner_shadows = inner_shadows.into_iter()
+            .map(|(mut mask_indices, inner_shadow)| {
+                let start = all_indices.len() as u32;
+                all_indices.append(&mut mask_indices);
+                (start..all_indices.len() as u32, inner_shadow)
+            })
+            .collect::<Vec<_>>();
 
         // Create vertex buffer
         let vertex_buffer = self.device.create_buffer_init(
@@ -258,11 +1104,15 @@ impl<'r> TextureRenderer<'r> {
             }
         );
 
+        // Storage bindings can't be empty, which they would be with nothing queued
+        if all_colors.is_empty() {
+            all_colors.push([0.0; 4]);
+        }
         // Create color uniform
         let color_buffer = self.device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
                 label: Some("Color Buffer"),
-                contents: bytemuck::cast_slice(&all_colors),
+                contents: bytemuck::cast_slice(&all_colors.iter().map(|color| self.native_color(*color)).collect::<Vec<_>>()),
                 usage: wgpu::BufferUsages::STORAGE,
             }
         );
@@ -274,7 +1124,15 @@ impl<'r> TextureRenderer<'r> {
                 wgpu::BindGroupEntry {
                     binding: 0,
                     resource: color_buffer.as_entire_binding(),
-                }
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&bitmap_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&bitmap_sampler),
+                },
             ],
         });
 
@@ -287,58 +1145,363 @@ impl<'r> TextureRenderer<'r> {
                 depth_or_array_layers: 1,
             },
             mip_level_count: 1,
-            sample_count: self.aa_mode.to_sample_count(),
+            sample_count: msaa_sample_count,
             dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Rgba8Unorm,
-            usage: wgpu::TextureUsages::COPY_SRC
-                | wgpu::TextureUsages::RENDER_ATTACHMENT
-                | wgpu::TextureUsages::TEXTURE_BINDING
-            ,
+            format: self.render_texture.format(),
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
             label: None,
-            view_formats: &[wgpu::TextureFormat::Rgba8Unorm],
+            view_formats: &[],
         };
         let msaa_texture = self.device.create_texture(&msaa_texture_desc);
         let msaa_texture_view = msaa_texture.create_view(&Default::default());
+        // Passes whose triangles are drawn from back to front go without a depth buffer
+        let msaa_depth = depth_can_reject(&all_vertices, &all_indices[..msaa_index_count as usize]);
+        let msaa_depth_view = msaa_depth.then(|| self.create_depth_view(self.render_texture.size(), msaa_sample_count));
 
-        // Render encoder and pass
-        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
-            label: None,
+        let clear_color = match self.debug_mode {
+            DebugMode::None | DebugMode::GlyphBoxes => self.clear_color(),
+            DebugMode::Overdraw => wgpu::Color::BLACK,
+        };
+        // The band sits at the bottom of the texture, scissor rects are top-down
+        let scissor = self.physical_band().map(|(y0, y1)| {
+            let height = (y1 - y0).min(self.render_texture.height());
+            (0, self.render_texture.height() - height, self.render_texture.width(), height)
         });
+        let step = DebugMode::OVERDRAW_STEP;
+        let blend_constant = wgpu::Color { r: step, g: step, b: step, a: 1.0 };
 
         {
+            let is_multisampled = msaa_sample_count > 1;
             let render_pass_desc = wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
                 color_attachments: &[
                     Some(wgpu::RenderPassColorAttachment {
-                        view: if self.aa_mode != AAMode::Disabled { &msaa_texture_view } else { &self.render_texture_view },
-                        resolve_target: if self.aa_mode != AAMode::Disabled { Some(&self.render_texture_view) } else { None },
+                        view: if is_multisampled { &msaa_texture_view } else { target },
+                        resolve_target: if is_multisampled { Some(target) } else { None },
+                        ops: wgpu::Operations {
+                            load: if clear || is_multisampled { wgpu::LoadOp::Clear(clear_color) } else { wgpu::LoadOp::Load },
+                            store: wgpu::StoreOp::Store,
+                        },
+                    })
+                ],
+                depth_stencil_attachment: msaa_depth_view.as_ref().map(depth_attachment),
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            };
+            let pipeline = self.glyph_pipeline(msaa_sample_count, msaa_depth);
+            let mut render_pass = encoder.begin_render_pass(&render_pass_desc);
+
+            render_pass.set_pipeline(&pipeline);
+            if let Some((x, y, width, height)) = scissor {
+                render_pass.set_scissor_rect(x, y, width, height);
+            }
+            render_pass.set_blend_constant(blend_constant);
+            render_pass.set_bind_group(0, &color_buffer_group, &[]);
+            render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
+            render_pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+            render_pass.draw_indexed(0..msaa_index_count, 0, 0..1);
+        }
+
+        // Aliased spans are drawn on top of the resolved multisampled pass
+        if aliased_index_end > msaa_index_count {
+            let depth = depth_can_reject(&all_vertices, &all_indices[msaa_index_count as usize..aliased_index_end as usize]);
+            let depth_view = depth.then(|| self.create_depth_view(self.render_texture.size(), 1));
+            let render_pass_desc = wgpu::RenderPassDescriptor {
+                label: Some("Aliased Render Pass"),
+                color_attachments: &[
+                    Some(wgpu::RenderPassColorAttachment {
+                        view: target,
+                        resolve_target: None,
                         ops: wgpu::Operations {
-                            load: wgpu::LoadOp::Clear(wgpu::Color {
-                                r: 1.0,
-                                g: 1.0,
-                                b: 1.0,
-                                a: 1.0,
-                            }),
+                            load: wgpu::LoadOp::Load,
                             store: wgpu::StoreOp::Store,
                         },
                     })
                 ],
-                depth_stencil_attachment: None,
+                depth_stencil_attachment: depth_view.as_ref().map(depth_attachment),
                 timestamp_writes: None,
                 occlusion_query_set: None,
             };
+            let pipeline = self.glyph_pipeline(1, depth);
             let mut render_pass = encoder.begin_render_pass(&render_pass_desc);
 
-            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_pipeline(&pipeline);
+            if let Some((x, y, width, height)) = scissor {
+                render_pass.set_scissor_rect(x, y, width, height);
+            }
+            render_pass.set_blend_constant(blend_constant);
             render_pass.set_bind_group(0, &color_buffer_group, &[]);
             render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
-            render_pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint16);
-            render_pass.draw_indexed(0..(all_indices.len() as u32), 0, 0..1);
+            render_pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+            render_pass.draw_indexed(msaa_index_count..aliased_index_end, 0, 0..1);
         }
 
+        // Supersampled spans are drawn into a larger texture, using the same vertices since they are
+        // in normalized device coordinates, then filtered down over everything else
+        if ssaa_index_end > aliased_index_end {
+            let samples_view = self.encode_offscreen(
+                encoder,
+                (ssaa_factor, ssaa_factor),
+                (&vertex_buffer, &index_buffer, &color_buffer_group),
+                aliased_index_end..ssaa_index_end,
+                depth_can_reject(&all_vertices, &all_indices[aliased_index_end as usize..ssaa_index_end as usize]),
+                scissor,
+            );
+            let factor_buffer = self.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Supersampling Factor Buffer"),
+                contents: bytemuck::cast_slice(&[ssaa_factor, self.downsample_filter.shader_index(), 0, 0]),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+            self.encode_composite(
+                encoder,
+                target,
+                "fs_downsample",
+                wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING,
+                &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&samples_view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: factor_buffer.as_entire_binding(),
+                    },
+                ],
+                scissor,
+            );
+        }
+
+        // LCD spans are drawn three times wider and composited channel by channel
+        if lcd_index_end > ssaa_index_end {
+            let subpixel_view = self.encode_offscreen(
+                encoder,
+                (3, 1),
+                (&vertex_buffer, &index_buffer, &color_buffer_group),
+                ssaa_index_end..lcd_index_end,
+                depth_can_reject(&all_vertices, &all_indices[ssaa_index_end as usize..lcd_index_end as usize]),
+                scissor,
+            );
+
+            // Without dual source blending, every channel's coverage is applied in a pass of its own
+            // before the premultiplied color is added
+            let coverage_blend = wgpu::BlendState {
+                color: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::Zero,
+                    dst_factor: wgpu::BlendFactor::OneMinusSrc,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::Zero,
+                    dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                    operation: wgpu::BlendOperation::Add,
+                },
+            };
+            let additive = wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::One,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            };
+            let color_blend = wgpu::BlendState { color: additive, alpha: additive };
+            let entries = [
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&subpixel_view),
+                }
+            ];
+            self.encode_composite(encoder, target, "fs_coverage", coverage_blend, &entries, scissor);
+            self.encode_composite(encoder, target, "fs_color", color_blend, &entries, scissor);
+        }
+
+        // Inner shadows go over all text, each cut from the supersampled coverage of its span. They
+        // only recolor what is already there, so the target's alpha is kept and the premultiplied
+        // shadow is weighted by it
+        let atop_blend = wgpu::BlendState {
+            color: wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::DstAlpha,
+                dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                operation: wgpu::BlendOperation::Add,
+            },
+            alpha: wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::Zero,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            },
+        };
+        for (mask_indices, (offset, blur, color)) in inner_shadows {
+            let factor = INNER_SHADOW_SUPERSAMPLING;
+            let mask_view = self.encode_offscreen(
+                encoder,
+                (factor, factor),
+                (&vertex_buffer, &index_buffer, &color_buffer_group),
+                mask_indices.clone(),
+                depth_can_reject(&all_vertices, &all_indices[mask_indices.start as usize..mask_indices.end as usize]),
+                scissor,
+            );
+            let [r, g, b, a] = self.native_color(color);
+            let ratio = self.device_pixel_ratio;
+            let inner_shadow_buffer = self.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Inner Shadow Buffer"),
+                contents: bytemuck::cast_slice(&[r, g, b, a, offset.0 * ratio, offset.1 * ratio, blur * ratio, factor as f32]),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+            self.encode_composite(
+                encoder,
+                target,
+                "fs_inner_shadow",
+                atop_blend,
+                &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&mask_view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: inner_shadow_buffer.as_entire_binding(),
+                    },
+                ],
+                scissor,
+            );
+        }
+    }
+
+    /// Draws the `indices` range into a new single-sampled texture, `scale` times the render texture's
+    /// size, and returns its view. The triangles are depth tested if `depth` is set.
+    fn encode_offscreen(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        scale: (u32, u32),
+        (vertex_buffer, index_buffer, color_buffer_group): (&wgpu::Buffer, &wgpu::Buffer, &wgpu::BindGroup),
+        indices: Range<u32>,
+        depth: bool,
+        scissor: Option<(u32, u32, u32, u32)>,
+    ) -> wgpu::TextureView {
+        let size = wgpu::Extent3d {
+            width: self.render_texture.width() * scale.0,
+            height: self.render_texture.height() * scale.1,
+            depth_or_array_layers: 1,
+        };
+        let texture = self.device.create_texture(&wgpu::TextureDescriptor {
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: self.render_texture.format(),
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            label: Some("Offscreen Texture"),
+            view_formats: &[],
+        });
+        let view = texture.create_view(&Default::default());
+        let depth_view = depth.then(|| self.create_depth_view(size, 1));
+        let pipeline = self.glyph_pipeline(1, depth);
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Offscreen Render Pass"),
+            color_attachments: &[
+                Some(wgpu::RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })
+            ],
+            depth_stencil_attachment: depth_view.as_ref().map(depth_attachment),
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        render_pass.set_pipeline(&pipeline);
+        if let Some((x, y, width, height)) = scissor {
+            render_pass.set_scissor_rect(x * scale.0, y * scale.1, width * scale.0, height * scale.1);
+        }
+        render_pass.set_bind_group(0, color_buffer_group, &[]);
+        render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
+        render_pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        render_pass.draw_indexed(indices, 0, 0..1);
+        drop(render_pass);
+        view
+    }
+
+    /// Draws a fullscreen triangle with the `composite.wgsl` `fragment_entry_point` over `target`
+    fn encode_composite(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &wgpu::TextureView,
+        fragment_entry_point: &'static str,
+        blend: wgpu::BlendState,
+        entries: &[wgpu::BindGroupEntry],
+        scissor: Option<(u32, u32, u32, u32)>,
+    ) {
+        let pipeline = self.composite_pipeline(fragment_entry_point, blend);
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("composite_bind_group"),
+            layout: &pipeline.get_bind_group_layout(0),
+            entries,
+        });
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Composite Pass"),
+            color_attachments: &[
+                Some(wgpu::RenderPassColorAttachment {
+                    view: target,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: wgpu::StoreOp::Store,
+                    },
+                })
+            ],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        render_pass.set_pipeline(&pipeline);
+        if let Some((x, y, width, height)) = scissor {
+            render_pass.set_scissor_rect(x, y, width, height);
+        }
+        render_pass.set_bind_group(0, &bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+
+    /// Renders all queued text into the renderer's texture and clears the queue, for sampling the
+    /// texture in a custom pipeline on [`TextureRenderer::device`].
+    pub fn render_to_texture(&mut self) -> (&wgpu::Texture, TextureInfo) {
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: None,
+        });
+        self.encode_text(&mut encoder, &self.render_texture_view, true, self.subpixel_aa.unwrap_or(false));
         self.queue.submit(Some(encoder.finish()));
+        self.spans.clear();
 
-        // Copy texture to output buffer
+        let info = TextureInfo {
+            width: self.render_texture.width(),
+            height: self.render_texture.height(),
+            format: self.render_texture.format(),
+            sample_count: self.render_texture.sample_count(),
+            is_srgb: self.render_texture.format().is_srgb(),
+            alpha_mode: AlphaMode::Premultiplied,
+        };
+        (&self.render_texture, info)
+    }
+
+    /// Returns raw image data in RgbaU8 format, with alpha as configured by
+    /// [`TextureRenderer::with_alpha_mode`] (straight by default)
+    ///
+    /// The queue is cleared afterwards, while device, texture and buffers are kept for the next render.
+    pub fn render(&mut self) -> Vec<u8> {
+        // Render encoder and pass
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: None,
+        });
+        // Transparent backgrounds are for compositing, where LCD fringes would show
+        let subpixel = self.subpixel_aa.unwrap_or(self.background[3] >= 1.0);
+        self.encode_text(&mut encoder, &self.render_texture_view, true, subpixel);
+        self.queue.submit(Some(encoder.finish()));
+        self.spans.clear();
+
+        // Copy texture to output buffer, which is only reallocated when the texture size changed
+        let required_size = output_buffer_size(&self.render_texture);
+        if self.output_buffer.size() != required_size {
+            self.output_buffer = create_output_buffer(&self.device, required_size);
+        }
         let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
             label: None,
         });
@@ -354,7 +1517,7 @@ impl<'r> TextureRenderer<'r> {
                 buffer: &self.output_buffer,
                 layout: wgpu::ImageDataLayout {
                     offset: 0,
-                    bytes_per_row: Some(std::mem::size_of::<u32>() as u32 * self.render_texture.width()),
+                    bytes_per_row: Some(padded_bytes_per_row(&self.render_texture)),
                     rows_per_image: Some(self.render_texture.height()),
                 },
             },
@@ -363,21 +1526,175 @@ impl<'r> TextureRenderer<'r> {
 
         self.queue.submit(Some(encoder.finish()));
 
-        // Save image and unmap output buffer
-        let mut data = vec![];
-        {
-            let buffer_slice = self.output_buffer.slice(..);
+        // Read back the image, the guard unmaps the output buffer even if reading fails
+        let buffer_slice = self.output_buffer.slice(..);
+        let (tx, rx) = std::sync::mpsc::channel();
+        buffer_slice.map_async(wgpu::MapMode::Read, move |result| {
+            tx.send(result).unwrap();
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        rx.recv().unwrap().unwrap();
+        let _unmap = UnmapOnDrop(&self.output_buffer);
+        // Strip the row padding, bound to a local so the mapped view is dropped before the guard unmaps
+        let row_size = std::mem::size_of::<u32>() * self.render_texture.width() as usize;
+        let mut data = buffer_slice.get_mapped_range()
+            .chunks_exact(padded_bytes_per_row(&self.render_texture) as usize)
+            .flat_map(|row| &row[..row_size])
+            .copied()
+            .collect::<Vec<u8>>();
+        if self.alpha_mode == AlphaMode::Straight {
+            unpremultiply(&mut data, self.render_texture.format().is_srgb());
+        }
+        data
+    }
 
-            let (tx, rx) = std::sync::mpsc::channel();
-            buffer_slice.map_async(wgpu::MapMode::Read, move |result| {
-                tx.send(result).unwrap();
-            });
-            self.device.poll(wgpu::Maintain::Wait);
-            rx.recv().unwrap().unwrap();
+    /// Like [`TextureRenderer::render`], but returns the image with the texture's dimensions
+    pub fn render_image(&mut self) -> image::RgbaImage {
+        let data = self.render();
+        image::RgbaImage::from_raw(self.render_texture.width(), self.render_texture.height(), data)
+            .expect("rendered data matches the texture size")
+    }
 
-            data = buffer_slice.get_mapped_range().to_vec();
+    /// Renders the queued text and saves it as a PNG file at `path`, whatever its extension.
+    ///
+    /// Output of an sRGB render texture, see [`TextureRenderer::with_srgb`], is tagged with an `sRGB`
+    /// chunk and the `gAMA` and `cHRM` chunks it implies, so viewers don't have to guess its encoding.
+    pub fn render_to_png<P: AsRef<Path>>(&mut self, path: P) -> Result<(), png::EncodingError> {
+        let image = self.render_image();
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        let mut encoder = png::Encoder::new(file, image.width(), image.height());
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        if self.render_texture.format().is_srgb() {
+            encoder.set_srgb(png::SrgbRenderingIntent::Perceptual);
         }
-        self.output_buffer.unmap();
-        data
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&image)?;
+        writer.finish()
+    }
+}
+
+/// Depth of every glyph, derived from its position in the shaped runs
+const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+/// Samples per pixel along each axis of the glyph coverage inner shadows are cut from
+const INNER_SHADOW_SUPERSAMPLING: u32 = 4;
+
+/// Sample counts the adapter can render `format` with, only 1 and 4 unless the device has
+/// `TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES`.
+fn supported_sample_counts(adapter: &wgpu::Adapter, format: wgpu::TextureFormat, has_extra_feature: bool) -> Vec<u32> {
+    let flags = adapter.get_texture_format_features(format).flags;
+    [1, 2, 4, 8, 16].into_iter()
+        .filter(|count| has_extra_feature || matches!(count, 1 | 4))
+        .filter(|count| flags.sample_count_supported(*count))
+        .collect()
+}
+
+/// Depth attachment cleared to the far plane, the depth is only needed during the pass
+fn depth_attachment(view: &wgpu::TextureView) -> wgpu::RenderPassDepthStencilAttachment<'_> {
+    wgpu::RenderPassDepthStencilAttachment {
+        view,
+        depth_ops: Some(wgpu::Operations {
+            load: wgpu::LoadOp::Clear(1.0),
+            store: wgpu::StoreOp::Discard,
+        }),
+        stencil_ops: None,
     }
-}
\ No newline at end of file
+}
+
+/// Whether a depth test can reject any fragment of the `indices` triangles drawn in order, which is
+/// only the case if a triangle is further away than one drawn before it
+fn depth_can_reject(vertices: &[GlyphVertex], indices: &[u32]) -> bool {
+    let mut nearest = f32::MAX;
+    indices.chunks_exact(3).any(|triangle| {
+        let depth = triangle.iter().map(|i| vertices[*i as usize].position[2]).fold(f32::MIN, f32::max);
+        let rejects = depth > nearest;
+        nearest = nearest.min(triangle.iter().map(|i| vertices[*i as usize].position[2]).fold(f32::MAX, f32::min));
+        rejects
+    })
+}
+
+/// Creates the render texture and its view
+fn create_target(device: &wgpu::Device, width: u32, height: u32, format: wgpu::TextureFormat) -> (wgpu::Texture, wgpu::TextureView) {
+    // Create texture to write to
+    let texture_desc = wgpu::TextureDescriptor {
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::COPY_SRC
+            | wgpu::TextureUsages::RENDER_ATTACHMENT
+            | wgpu::TextureUsages::TEXTURE_BINDING
+        ,
+        label: None,
+        view_formats: &[format],
+    };
+    let texture = device.create_texture(&texture_desc);
+    let texture_view = texture.create_view(&Default::default());
+
+    (texture, texture_view)
+}
+
+/// Bytes per row of the output buffer, texture copies need rows aligned to
+/// [`wgpu::COPY_BYTES_PER_ROW_ALIGNMENT`]
+fn padded_bytes_per_row(texture: &wgpu::Texture) -> u32 {
+    let u32_size = std::mem::size_of::<u32>() as u32;
+    let alignment = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    (u32_size * texture.width()).div_ceil(alignment) * alignment
+}
+
+/// Size in bytes of the buffer the render texture is read back through
+fn output_buffer_size(texture: &wgpu::Texture) -> wgpu::BufferAddress {
+    (padded_bytes_per_row(texture) * texture.height()) as wgpu::BufferAddress
+}
+
+fn create_output_buffer(device: &wgpu::Device, size: wgpu::BufferAddress) -> wgpu::Buffer {
+    let output_buffer_desc = wgpu::BufferDescriptor {
+        size,
+        usage: wgpu::BufferUsages::COPY_DST
+            // this tells wpgu that we want to read this buffer from the cpu
+            | wgpu::BufferUsages::MAP_READ,
+        label: None,
+        mapped_at_creation: false,
+    };
+    device.create_buffer(&output_buffer_desc)
+}
+
+/// Converts premultiplied RgbaU8 pixels to straight alpha in place. sRGB encoded pixels were
+/// premultiplied in linear space, so they are divided in linear space as well.
+fn unpremultiply(data: &mut [u8], srgb: bool) {
+    for pixel in data.chunks_exact_mut(4) {
+        let alpha = pixel[3] as u32;
+        if alpha == 0 || alpha == 255 {
+            continue;
+        }
+        for channel in &mut pixel[..3] {
+            *channel = if srgb {
+                let linear = srgb_to_linear(*channel as f32 / 255.0) * 255.0 / alpha as f32;
+                (linear_to_srgb(linear.min(1.0)) * 255.0).round() as u8
+            } else {
+                ((*channel as u32 * 255 + alpha / 2) / alpha).min(255) as u8
+            };
+        }
+    }
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
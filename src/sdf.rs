@@ -0,0 +1,141 @@
+use crate::mesh::{GlyphMeshBuilder, TextMesh};
+use crate::raster::rasterize;
+
+/// Location of a single glyph inside an atlas from [`render_sdf_atlas`].
+#[derive(Copy, Clone, Debug)]
+pub struct AtlasEntry {
+    pub glyph_id: ttf_parser::GlyphId,
+    /// `[u_min, v_min, u_max, v_max]` of the glyph's cell, with v = 0 at the top row of the atlas
+    pub uv: [f32; 4],
+    /// Cell size in atlas texels, zero for glyphs without an outline
+    pub size: (u32, u32),
+    /// Offset of the cell's bottom-left corner from the glyph origin, in pixels at the rendered size
+    pub bearing: (f32, f32),
+    /// Horizontal advance in pixels at the rendered size
+    pub advance: f32,
+}
+
+/// Texels of distance the field covers on either side of the outline, relative to the pixel size
+fn spread(px: u32) -> u32 {
+    (px / 8).max(2)
+}
+
+/// Renders `glyphs` as signed distance fields at `px` pixels per em and packs them into one square,
+/// power of two R8 atlas, returned as row-major bytes with the atlas side being the square root of
+/// their length.
+///
+/// Texels store 128 on the outline, rising towards 255 inside and falling towards 0 outside, which
+/// is reached `px / 8` (at least 2) texels away from the outline. Cells are padded by that spread
+/// so the field does not get clipped.
+pub fn render_sdf_atlas(face: &ttf_parser::Face, glyphs: &[ttf_parser::GlyphId], px: u32) -> (Vec<u8>, Vec<AtlasEntry>) {
+    let scale = px as f32 / face.units_per_em() as f32;
+    let padding = spread(px);
+    let cells = glyphs.iter().map(|&glyph_id| {
+        let advance = face.glyph_hor_advance(glyph_id).unwrap_or(0) as f32 * scale;
+        let Some(mesh) = GlyphMeshBuilder::new().build(face, glyph_id) else {
+            return (glyph_id, advance, (0.0, 0.0), (0, 0), vec![]);
+        };
+        let origin = (
+            (mesh.bounds.x_min as f32 * scale).floor() - padding as f32,
+            (mesh.bounds.y_min as f32 * scale).floor() - padding as f32,
+        );
+        let width = (mesh.bounds.x_max as f32 * scale).ceil() as i32 - origin.0 as i32 + padding as i32;
+        let height = (mesh.bounds.y_max as f32 * scale).ceil() as i32 - origin.1 as i32 + padding as i32;
+        let (width, height) = (width.max(1) as u32, height.max(1) as u32);
+        // Place the outline inside the cell and map it to the cell's NDC for the rasterizer
+        let vertices = mesh.vertices.iter().map(|vertex| {
+            let mut vertex = *vertex;
+            vertex.position[0] = (vertex.position[0] * scale - origin.0) / width as f32 * 2.0 - 1.0;
+            vertex.position[1] = (vertex.position[1] * scale - origin.1) / height as f32 * 2.0 - 1.0;
+            vertex
+        }).collect();
+        let coverage = rasterize(&TextMesh { vertices, indices: mesh.indices }, width, height);
+        let field = distance_field(&coverage, width as usize, height as usize, padding as f32);
+        (glyph_id, advance, origin, (width, height), field)
+    }).collect::<Vec<_>>();
+
+    // Shelf packing, tallest cells first
+    let area = cells.iter().map(|(.., (width, height), _)| width * height).sum::<u32>();
+    let widest = cells.iter().map(|(.., (width, _), _)| *width).max().unwrap_or(1);
+    let mut order = (0..cells.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&index| std::cmp::Reverse(cells[index].3.1));
+    let mut side = ((area as f32).sqrt().ceil() as u32).max(widest).max(1).next_power_of_two();
+    let positions = loop {
+        if let Some(positions) = pack_shelves(&cells.iter().map(|cell| cell.3).collect::<Vec<_>>(), &order, side) {
+            break positions;
+        }
+        side *= 2;
+    };
+
+    let mut atlas = vec![0u8; (side * side) as usize];
+    let entries = cells.iter().zip(positions).map(|((glyph_id, advance, origin, (width, height), field), (x, y))| {
+        for row in 0..*height {
+            let start = ((y + row) * side + x) as usize;
+            atlas[start..start + *width as usize]
+                .copy_from_slice(&field[(row * width) as usize..((row + 1) * width) as usize]);
+        }
+        AtlasEntry {
+            glyph_id: *glyph_id,
+            uv: [
+                x as f32 / side as f32,
+                y as f32 / side as f32,
+                (x + width) as f32 / side as f32,
+                (y + height) as f32 / side as f32,
+            ],
+            size: (*width, *height),
+            bearing: *origin,
+            advance: *advance,
+        }
+    }).collect();
+    (atlas, entries)
+}
+
+/// Places cells of `sizes` in `order` on shelves of a `side` wide square, returning their top-left
+/// corners by index or `None` if they don't fit.
+pub(crate) fn pack_shelves(sizes: &[(u32, u32)], order: &[usize], side: u32) -> Option<Vec<(u32, u32)>> {
+    let mut positions = vec![(0, 0); sizes.len()];
+    let (mut x, mut y, mut shelf_height) = (0, 0, 0);
+    for &index in order {
+        let (width, height) = sizes[index];
+        if x + width > side {
+            x = 0;
+            y += shelf_height;
+            shelf_height = 0;
+        }
+        if y + height > side {
+            return None;
+        }
+        positions[index] = (x, y);
+        x += width;
+        shelf_height = shelf_height.max(height);
+    }
+    Some(positions)
+}
+
+/// Converts row-major coverage into 8 bit signed distances, searching up to `spread` texels for the
+/// nearest texel on the other side of the outline.
+fn distance_field(coverage: &[f32], width: usize, height: usize, spread: f32) -> Vec<u8> {
+    let inside = coverage.iter().map(|c| *c >= 0.5).collect::<Vec<_>>();
+    let radius = spread.ceil() as isize;
+    (0..width * height).map(|index| {
+        let (x, y) = ((index % width) as isize, (index / width) as isize);
+        let mut nearest = spread;
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                let (sx, sy) = (x + dx, y + dy);
+                // Everything outside the cell is outside the glyph
+                let other = if sx < 0 || sy < 0 || sx >= width as isize || sy >= height as isize {
+                    false
+                } else {
+                    inside[sy as usize * width + sx as usize]
+                };
+                if other != inside[index] {
+                    nearest = nearest.min(((dx * dx + dy * dy) as f32).sqrt());
+                }
+            }
+        }
+        // The outline lies halfway between two texel centers
+        let distance = if inside[index] { nearest - 0.5 } else { 0.5 - nearest };
+        (128.0 + distance / spread * 127.0).round().clamp(0.0, 255.0) as u8
+    }).collect()
+}
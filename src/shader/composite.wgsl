@@ -0,0 +1,160 @@
+// Composites text rendered offscreen at a higher resolution onto the target, either averaging
+// blocks of supersamples or, for LCD text at three times the horizontal resolution, with every color
+// channel taking the coverage of its own subpixel. Inner shadows are cut from a supersampled
+// coverage mask the same way.
+
+@group(0) @binding(0)
+var source: texture_2d<f32>;
+
+// Supersampling factor per axis in `x`, the downsample filter in `y`: 0 box, 1 tent, 2 Lanczos
+@group(0) @binding(1)
+var<uniform> factor: vec4<u32>;
+
+struct InnerShadow {
+    // Straight alpha
+    color: vec4<f32>,
+    // In pixels, y-up
+    offset: vec2<f32>,
+    // Blur radius in pixels
+    blur: f32,
+    // Supersampling factor per axis of the mask in `source`
+    factor: f32,
+}
+
+@group(0) @binding(2)
+var<uniform> inner_shadow: InnerShadow;
+
+@vertex
+fn vs_fullscreen(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {
+    // One triangle covering the whole target
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    return vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+}
+
+fn subpixel(x: i32, y: i32) -> vec4<f32> {
+    if x < 0 || x >= i32(textureDimensions(source).x) {
+        return vec4(0.0);
+    }
+    return textureLoad(source, vec2<i32>(x, y), 0);
+}
+
+// Premultiplied color of the subpixel `index` of `pixel`, low-pass filtered over its neighbours
+// to reduce color fringes
+fn channel(pixel: vec2<i32>, index: i32) -> vec4<f32> {
+    let x = pixel.x * 3 + index;
+    return (subpixel(x - 2, pixel.y)
+        + 2.0 * subpixel(x - 1, pixel.y)
+        + 3.0 * subpixel(x, pixel.y)
+        + 2.0 * subpixel(x + 1, pixel.y)
+        + subpixel(x + 2, pixel.y)) / 9.0;
+}
+
+// Blended with `dst * (1 - src)`, clearing the background behind every subpixel by its coverage
+@fragment
+fn fs_coverage(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+    let pixel = vec2<i32>(position.xy);
+    let alpha = vec3(channel(pixel, 0).a, channel(pixel, 1).a, channel(pixel, 2).a);
+    return vec4(alpha, (alpha.r + alpha.g + alpha.b) / 3.0);
+}
+
+// Blended additively after `fs_coverage`
+@fragment
+fn fs_color(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+    let pixel = vec2<i32>(position.xy);
+    let r = channel(pixel, 0);
+    let g = channel(pixel, 1);
+    let b = channel(pixel, 2);
+    return vec4(r.r, g.g, b.b, (r.a + g.a + b.a) / 3.0);
+}
+
+const PI: f32 = 3.14159265;
+
+fn sinc(x: f32) -> f32 {
+    if abs(x) < 1e-4 {
+        return 1.0;
+    }
+    return sin(PI * x) / (PI * x);
+}
+
+// Weight of a sample `distance` output pixels from the pixel center along one axis
+fn filter_weight(distance: f32) -> f32 {
+    let d = abs(distance);
+    switch factor.y {
+        case 1u: {
+            return max(1.0 - d, 0.0);
+        }
+        case 2u: {
+            return select(0.0, sinc(d) * sinc(d / 2.0), d < 2.0);
+        }
+        default: {
+            return select(0.0, 1.0, d < 0.5);
+        }
+    }
+}
+
+// Filters the premultiplied samples around the pixel, blended over the target. The box filter only
+// covers the `factor` x `factor` block behind the pixel, tent and Lanczos reach one and two pixels
+// further on every side.
+@fragment
+fn fs_downsample(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+    let scale = i32(factor.x);
+    let radius = scale * i32(min(factor.y, 2u));
+    let origin = vec2<i32>(position.xy) * scale;
+    let size = vec2<i32>(textureDimensions(source));
+    var sum = vec4(0.0);
+    var weights = 0.0;
+    for (var y = -radius; y < scale + radius; y++) {
+        for (var x = -radius; x < scale + radius; x++) {
+            // Sample center relative to the pixel center, in output pixels
+            let offset = (vec2(f32(x), f32(y)) + 0.5) / f32(scale) - 0.5;
+            let weight = filter_weight(offset.x) * filter_weight(offset.y);
+            let texel = origin + vec2(x, y);
+            // Samples outside the texture are transparent, they still count towards the weights
+            if all(texel >= vec2(0)) && all(texel < size) {
+                sum += weight * textureLoad(source, texel, 0);
+            }
+            weights += weight;
+        }
+    }
+    // Negative Lanczos lobes can overshoot
+    let alpha = clamp(sum.a / weights, 0.0, 1.0);
+    return vec4(clamp(sum.rgb / weights, vec3(0.0), vec3(alpha)), alpha);
+}
+
+// Coverage of `pixel` in the inner shadow mask, averaged over its block of supersamples
+fn mask_coverage(pixel: vec2<i32>) -> f32 {
+    let factor = i32(inner_shadow.factor);
+    let size = vec2<i32>(textureDimensions(source));
+    var sum = 0.0;
+    for (var y = 0; y < factor; y++) {
+        for (var x = 0; x < factor; x++) {
+            let texel = pixel * factor + vec2(x, y);
+            if all(texel >= vec2(0)) && all(texel < size) {
+                sum += textureLoad(source, texel, 0).a;
+            }
+        }
+    }
+    return sum / f32(factor * factor);
+}
+
+// Light is blocked where the text, moved by the offset, doesn't cover the pixel. Limited to the
+// text's own coverage and blended atop the target, keeping its alpha.
+@fragment
+fn fs_inner_shadow(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+    let pixel = vec2<i32>(position.xy);
+    // Texture rows go down
+    let shifted = pixel - vec2<i32>(round(inner_shadow.offset * vec2(1.0, -1.0)));
+    let radius = i32(ceil(inner_shadow.blur));
+    let sigma = max(inner_shadow.blur / 2.0, 0.5);
+    var lit = 0.0;
+    var weights = 0.0;
+    for (var y = -radius; y <= radius; y++) {
+        for (var x = -radius; x <= radius; x++) {
+            let weight = exp(-f32(x * x + y * y) / (2.0 * sigma * sigma));
+            lit += weight * mask_coverage(shifted + vec2(x, y));
+            weights += weight;
+        }
+    }
+    let alpha = inner_shadow.color.a * mask_coverage(pixel) * (1.0 - lit / weights);
+    return vec4(inner_shadow.color.rgb * alpha, alpha);
+}
@@ -4,6 +4,7 @@ struct VertexInput {
     @location(1) uv: vec2<f32>,
     @location(2) metadata: i32,
     @location(3) color_index: u32,
+    @location(4) paint: vec4<f32>,
 }
 
 struct VertexOutput {
@@ -11,6 +12,7 @@ struct VertexOutput {
     @location(0) uv: vec2<f32>,
     @location(1) metadata: i32,
     @location(2) color_index: u32,
+    @location(3) paint: vec4<f32>,
 }
 
 @vertex
@@ -20,22 +22,131 @@ fn vs_main(in: VertexInput) -> VertexOutput {
     out.uv = in.uv;
     out.metadata = in.metadata;
     out.color_index = in.color_index;
+    out.paint = in.paint;
     return out;
 }
 
 @group(0) @binding(0)
 var<storage> color: array<vec4<f32>>;
 
+// Embedded glyph bitmaps, drawn on quads with bit 2 of the metadata set, or bit 3 for their shadows
+@group(0) @binding(1)
+var bitmap_atlas: texture_2d<f32>;
+@group(0) @binding(2)
+var bitmap_sampler: sampler;
+
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     var is_inverse: bool = (in.metadata & 1) > 0;
     var is_curve: bool = (in.metadata & 2) > 0;
+    var is_bitmap: bool = (in.metadata & 4) > 0;
+    var is_bitmap_shadow: bool = (in.metadata & 8) > 0;
     var c: vec4<f32> = color[in.color_index];
+    // Sampled in uniform control flow, only bitmap quads use the result
+    var bitmap: vec4<f32> = textureSample(bitmap_atlas, bitmap_sampler, in.uv);
+    if is_bitmap | is_bitmap_shadow {
+        if bitmap.w == 0.0 {
+            discard;
+        }
+        if is_bitmap_shadow {
+            return vec4(c.xyz, c.w * bitmap.w);
+        }
+        // The span color's alpha fades bitmaps like outlined glyphs
+        return vec4(bitmap.xyz, bitmap.w * c.w);
+    }
     var curve_alpha: f32 = sample_curve(is_inverse, is_curve, in.uv.xy);
+    // Uncovered fragments would still write depth and hide parts of the glyphs drawn after them
+    if curve_alpha == 0.0 {
+        discard;
+    }
+    // Gradient layers of color glyphs index the ramp their gradient is sampled into
+    if ((in.metadata >> 4u) & 7) != 0 {
+        var position: vec2<f32> = ramp_position(in.metadata, in.paint);
+        if position.y == 0.0 {
+            discard;
+        }
+        c = ramp_color(in.color_index, position.x);
+    }
 
     return vec4(c.xyz, c.w * curve_alpha);
 }
 
+@fragment
+fn fs_overdraw(in: VertexOutput) -> @location(0) vec4<f32> {
+    // Scaled by the blend constant, so every covering triangle adds the same amount
+    return vec4(1.0);
+}
+
+@fragment
+fn fs_wireframe(in: VertexOutput) -> @location(0) vec4<f32> {
+    // Curve triangles are drawn in full, the curve only cuts through their inside
+    return color[in.color_index];
+}
+
 fn sample_curve(is_inverse: bool, is_curve: bool, uv: vec2<f32>) -> f32 {
     return 1.0 - f32(is_curve & ((is_inverse & (uv.y < uv.x*uv.x)) | (!is_inverse & (uv.y >= uv.x*uv.x))));
-}
\ No newline at end of file
+}
+
+// Palette entries every gradient ramp takes, `colr::RAMP_LEN`
+const RAMP_LEN: u32 = 32u;
+const PI: f32 = 3.14159265358979;
+
+// Position on the ramp from 0 to 1 of a gradient layer's fragment, and 0 in y outside the cone of
+// radial gradients, like `colr::ramp_position`
+fn ramp_position(metadata: i32, paint: vec4<f32>) -> vec2<f32> {
+    var kind: i32 = (metadata >> 4u) & 7;
+    var t: f32 = paint.x;
+    if kind == 2 || kind == 3 {
+        var radial: vec2<f32> = radial_position(paint.xy, f32(kind == 2), paint.z, paint.w);
+        if radial.y == 0.0 {
+            return radial;
+        }
+        t = radial.x;
+    } else if kind == 4 {
+        var angle: f32 = atan2(paint.y, paint.x);
+        if paint.z > 0.0 && angle < 0.0 {
+            angle += 2.0 * PI;
+        } else if paint.z < 0.0 && angle > 0.0 {
+            angle -= 2.0 * PI;
+        }
+        t = angle / paint.z;
+    }
+    var extend: i32 = (metadata >> 7u) & 3;
+    if extend == 1 {
+        t = t - floor(t);
+    } else if extend == 2 {
+        t = 1.0 - abs((t * 0.5 - floor(t * 0.5)) * 2.0 - 1.0);
+    } else {
+        t = clamp(t, 0.0, 1.0);
+    }
+    return vec2(t, 1.0);
+}
+
+// Largest t for which `p` lies on the circle around (t * dx, 0) with the non-negative radius
+// r0 + t * dr, and 0 in y if there is none
+fn radial_position(p: vec2<f32>, dx: f32, r0: f32, dr: f32) -> vec2<f32> {
+    var a: f32 = dx * dx - dr * dr;
+    var b: f32 = p.x * dx + r0 * dr;
+    var c: f32 = dot(p, p) - r0 * r0;
+    if abs(a) < 1e-6 {
+        var t: f32 = c / (2.0 * b);
+        return vec2(t, f32(b != 0.0 && r0 + t * dr >= 0.0));
+    }
+    var discriminant: f32 = b * b - a * c;
+    if discriminant < 0.0 {
+        return vec2(0.0);
+    }
+    var root: f32 = sqrt(discriminant);
+    var t0: f32 = (b + root) / a;
+    var t1: f32 = (b - root) / a;
+    if r0 + max(t0, t1) * dr >= 0.0 {
+        return vec2(max(t0, t1), 1.0);
+    }
+    return vec2(min(t0, t1), f32(r0 + min(t0, t1) * dr >= 0.0));
+}
+
+fn ramp_color(start: u32, position: f32) -> vec4<f32> {
+    var scaled: f32 = position * f32(RAMP_LEN - 1u);
+    var index: u32 = min(u32(floor(scaled)), RAMP_LEN - 1u);
+    return mix(color[start + index], color[start + min(index + 1u, RAMP_LEN - 1u)], scaled - floor(scaled));
+}
@@ -0,0 +1,340 @@
+use std::borrow::BorrowMut;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use log::warn;
+use crate::GlyphData;
+use crate::font::face_index;
+use crate::text::FontSize;
+
+/// How HarfBuzz merges characters into clusters, see `hb_buffer_cluster_level_t`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ClusterLevel {
+    /// Clusters follow grapheme boundaries, so combining sequences select as one character.
+    #[default]
+    MonotoneGraphemes,
+    /// Every character is its own cluster, but clusters stay in logical order.
+    MonotoneCharacters,
+    /// Every character is its own cluster, without ordering guarantees.
+    Characters,
+}
+
+/// Options passed to [`Shaper::shape`].
+#[derive(Clone, Default)]
+pub struct ShapingOptions<'a> {
+    /// OpenType features and their values, applied to the whole text
+    pub features: &'a [(ttf_parser::Tag, u32)],
+    /// Guessed from the text if `None`
+    pub direction: Option<harfbuzz::Direction>,
+    /// ISO 15924 script tag like `Arab` or `Latn`, guessed from the text if `None`
+    pub script: Option<ttf_parser::Tag>,
+    /// BCP 47 language tag like `ar` or `en-US`, the process locale's language if `None`
+    pub language: Option<&'a str>,
+    pub cluster_level: ClusterLevel,
+    /// Distance between tab stops in font units. Tabs advance the pen to the next stop measured
+    /// from the start of the text, `None` keeps the advance of the font's tab glyph.
+    pub tab_width: Option<i32>,
+}
+
+/// Shapes text with HarfBuzz, independent of any [`Span`](crate::text::Span).
+///
+/// The HarfBuzz face and font are created once per shaper and destroyed when it is dropped, so a
+/// shaper should be reused for all text shaped with the same face, see
+/// [`Span::with_shaper`](crate::text::Span::with_shaper).
+#[derive(Debug)]
+pub struct Shaper<'f> {
+    hb_face: *mut harfbuzz::sys::hb_face_t,
+    hb_font: *mut harfbuzz::sys::hb_font_t,
+    // HarfBuzz references the font data without copying it
+    font_data: PhantomData<&'f [u8]>,
+}
+
+impl<'f> Shaper<'f> {
+    /// Creates a shaper for `face`. For faces of a font collection, HarfBuzz is pointed at the same
+    /// face index, see [`face_index`], and the face's variation coordinates are applied.
+    pub fn new(face: &ttf_parser::Face<'f>) -> Self {
+        let hb_blob = harfbuzz::Blob::new_read_only(face.raw_face().data);
+        // The face keeps its own reference to the blob
+        let hb_face = unsafe { harfbuzz::sys::hb_face_create(hb_blob.as_raw(), face_index(face)) };
+        let hb_font = unsafe { harfbuzz::sys::hb_font_create(hb_face) };
+        // Both use normalized coordinates in 2.14 fixed point, so shaping matches the outlines of
+        // faces with variations set
+        let coords = face.variation_coordinates().iter().map(|coord| coord.get() as i32).collect::<Vec<_>>();
+        if !coords.is_empty() {
+            unsafe { harfbuzz::sys::hb_font_set_var_coords_normalized(hb_font, coords.as_ptr(), coords.len() as u32) };
+        }
+        // Outlines come from `face`, so shaping a different face would produce the right advances
+        // but render the wrong glyphs
+        let hb_glyph_count = unsafe { harfbuzz::sys::hb_face_get_glyph_count(hb_face) };
+        if hb_glyph_count != face.number_of_glyphs() as u32 {
+            warn!(
+                "harfbuzz face has {} glyphs but the outline face has {}, glyph ids will not match",
+                hb_glyph_count,
+                face.number_of_glyphs(),
+            );
+        }
+        Self {
+            hb_face,
+            hb_font,
+            font_data: PhantomData,
+        }
+    }
+
+    /// Shapes `text`, the clusters of the returned glyphs are byte offsets into it.
+    pub fn shape(&self, text: &str, options: &ShapingOptions) -> Vec<GlyphData> {
+        self.shape_directed(text, options).0
+    }
+
+    /// Shapes `text` like [`Shaper::shape`], also returning whether it was shaped right-to-left.
+    /// Right-to-left glyphs are in visual order, so the last character comes first.
+    pub fn shape_directed(&self, text: &str, options: &ShapingOptions) -> (Vec<GlyphData>, bool) {
+        let mut hb_buffer = harfbuzz::Buffer::with(text);
+        if let Some(direction) = options.direction {
+            hb_buffer.set_direction(direction);
+        }
+        let hb_buffer = hb_buffer.into_raw();
+        if let Some(script) = options.script {
+            unsafe { harfbuzz::sys::hb_buffer_set_script(hb_buffer, harfbuzz::sys::hb_script_from_iso15924_tag(script.0)) };
+        }
+        if let Some(language) = options.language {
+            let hb_language = unsafe { harfbuzz::sys::hb_language_from_string(language.as_ptr() as *const _, language.len() as i32) };
+            unsafe { harfbuzz::sys::hb_buffer_set_language(hb_buffer, hb_language) };
+        }
+        // Only fills in the properties that weren't set
+        unsafe { harfbuzz::sys::hb_buffer_guess_segment_properties(hb_buffer) };
+        let cluster_level = match options.cluster_level {
+            ClusterLevel::MonotoneGraphemes => 0,
+            ClusterLevel::MonotoneCharacters => 1,
+            ClusterLevel::Characters => 2,
+        };
+        unsafe { harfbuzz::sys::hb_buffer_set_cluster_level(hb_buffer, cluster_level as _) };
+        let hb_features = options.features.iter().map(|(tag, value)| harfbuzz::sys::hb_feature_t {
+            tag: tag.0,
+            value: *value,
+            start: 0,
+            end: u32::MAX,
+        }).collect::<Vec<_>>();
+        unsafe { harfbuzz::sys::hb_shape(self.hb_font, hb_buffer, hb_features.as_ptr(), hb_features.len() as u32) };
+
+        let is_rtl = unsafe { harfbuzz::sys::hb_buffer_get_direction(hb_buffer) } == harfbuzz::sys::HB_DIRECTION_RTL;
+        let mut glyph_data: Vec<GlyphData> = Vec::new();
+        let mut hb_glyph_count: u32 = 0;
+        let hb_glyph_infos = unsafe { harfbuzz::sys::hb_buffer_get_glyph_infos(hb_buffer, hb_glyph_count.borrow_mut() as *mut u32) };
+        let hb_glyph_positions = unsafe { harfbuzz::sys::hb_buffer_get_glyph_positions(hb_buffer, hb_glyph_count.borrow_mut() as *mut u32) };
+        for index in 0..(hb_glyph_count as usize) {
+            unsafe {
+                let hb_glyph_info = hb_glyph_infos.add(index);
+                let hb_glyph_position = hb_glyph_positions.add(index);
+                glyph_data.push(GlyphData {
+                    glyph_id: (*hb_glyph_info).codepoint as u32,
+                    x_advance: (*hb_glyph_position).x_advance as i32,
+                    y_advance: (*hb_glyph_position).y_advance as i32,
+                    x_offset: (*hb_glyph_position).x_offset as i32,
+                    y_offset: (*hb_glyph_position).y_offset as i32,
+                    cluster: (*hb_glyph_info).cluster,
+                })
+            }
+        }
+        unsafe { harfbuzz::sys::hb_buffer_destroy(hb_buffer) };
+        if let Some(tab_width) = options.tab_width.filter(|width| *width > 0) {
+            self.expand_tabs(text, &mut glyph_data, tab_width);
+        }
+        (glyph_data, is_rtl)
+    }
+
+    /// Stretches tab glyphs to the next tab stop. This is the only place tabs are expanded, so
+    /// anything measuring shaped glyphs agrees with what gets rendered.
+    fn expand_tabs(&self, text: &str, glyph_data: &mut [GlyphData], tab_width: i32) {
+        let mut space_glyph = 0;
+        unsafe { harfbuzz::sys::hb_font_get_nominal_glyph(self.hb_font, ' ' as u32, &mut space_glyph) };
+        let mut pen = 0i32;
+        for data in glyph_data {
+            if text.get(data.cluster as usize..).is_some_and(|rest| rest.starts_with('\t')) {
+                data.x_advance = tab_width - pen.rem_euclid(tab_width);
+                // Fonts without a tab glyph would otherwise draw .notdef
+                if data.glyph_id == 0 {
+                    data.glyph_id = space_glyph;
+                }
+            }
+            pen += data.x_advance;
+        }
+    }
+}
+
+// HarfBuzz faces and fonts are reference counted atomically and never modified after `Shaper::new`
+// sets them up, shaping only reads them, so HarfBuzz allows using them from any number of threads
+unsafe impl Send for Shaper<'_> {}
+unsafe impl Sync for Shaper<'_> {}
+
+impl<'f> Drop for Shaper<'f> {
+    fn drop(&mut self) {
+        unsafe {
+            harfbuzz::sys::hb_font_destroy(self.hb_font);
+            harfbuzz::sys::hb_face_destroy(self.hb_face);
+        }
+    }
+}
+
+/// Everything a shaped run depends on, the face by address
+#[derive(PartialEq, Eq, Hash)]
+struct RunKey {
+    face: usize,
+    text: String,
+    features: Vec<(ttf_parser::Tag, u32)>,
+    direction: Option<u8>,
+    script: Option<ttf_parser::Tag>,
+    language: Option<String>,
+    cluster_level: ClusterLevel,
+    tab_width: Option<i32>,
+}
+
+/// One [`Shaper`] per face and the runs shaped during a render, so spans sharing a face share the
+/// HarfBuzz font and repeated text is only shaped once.
+///
+/// Shaping happens in font units, so runs are shared between font sizes. Like the
+/// [`GlyphCache`](crate::mesh::GlyphCache), faces are told apart by address.
+#[derive(Default)]
+pub(crate) struct ShapeCache<'f> {
+    shapers: HashMap<usize, Shaper<'f>>,
+    runs: HashMap<RunKey, (Vec<GlyphData>, bool)>,
+    run_hits: u64,
+    run_misses: u64,
+}
+
+impl<'f> ShapeCache<'f> {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Like [`Shaper::shape_directed`], reusing a previous result for the same text and options.
+    /// Shapes with `shaper` if given, otherwise with the cached shaper of `face`.
+    pub(crate) fn shape_directed(
+        &mut self,
+        face: &'f ttf_parser::Face<'f>,
+        shaper: Option<&Shaper>,
+        text: &str,
+        options: &ShapingOptions,
+    ) -> (Vec<GlyphData>, bool) {
+        let face_key = face as *const _ as usize;
+        let key = RunKey {
+            face: face_key,
+            text: text.to_string(),
+            features: options.features.to_vec(),
+            direction: options.direction.map(|direction| direction as u8),
+            script: options.script,
+            language: options.language.map(str::to_string),
+            cluster_level: options.cluster_level,
+            tab_width: options.tab_width,
+        };
+        if let Some(run) = self.runs.get(&key) {
+            self.run_hits += 1;
+            return run.clone();
+        }
+        self.run_misses += 1;
+        let shaper = match shaper {
+            Some(shaper) => shaper,
+            None => &*self.shapers.entry(face_key).or_insert_with(|| Shaper::new(face)),
+        };
+        let run = shaper.shape_directed(text, options);
+        self.runs.insert(key, run.clone());
+        run
+    }
+
+    /// Forgets the shaped runs, keeping the shapers
+    pub(crate) fn clear_runs(&mut self) {
+        self.runs.clear();
+    }
+
+    pub(crate) fn shaper_count(&self) -> usize {
+        self.shapers.len()
+    }
+
+    pub(crate) fn run_hits(&self) -> u64 {
+        self.run_hits
+    }
+
+    pub(crate) fn run_misses(&self) -> u64 {
+        self.run_misses
+    }
+}
+
+/// Pixel metrics of a single run measured by [`measure_runs`].
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RunMetrics {
+    pub width: f32,
+    pub ascent: f32,
+    /// Distance below the baseline, positive
+    pub descent: f32,
+}
+
+/// Metrics of runs laid out next to each other on one baseline.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LayoutMetrics {
+    pub width: f32,
+    /// From the highest ascent to the lowest descent of all runs
+    pub height: f32,
+    pub runs: Vec<RunMetrics>,
+}
+
+/// A run of text measured by [`measure_runs`], with its own font, size, DPI and shaping options.
+#[derive(Clone)]
+pub struct MeasureRun<'a> {
+    pub text: &'a str,
+    pub face: &'a ttf_parser::Face<'a>,
+    pub font_size: FontSize,
+    /// Resolution point sizes are converted at, [`FontSize::DEFAULT_DPI`] by default
+    pub dpi: f32,
+    pub options: ShapingOptions<'a>,
+}
+
+impl<'a> MeasureRun<'a> {
+    pub fn new(text: &'a str, face: &'a ttf_parser::Face<'a>, font_size: FontSize) -> Self {
+        Self {
+            text,
+            face,
+            font_size,
+            dpi: FontSize::DEFAULT_DPI,
+            options: ShapingOptions::default(),
+        }
+    }
+
+    pub fn with_dpi(mut self, dpi: f32) -> Self {
+        self.dpi = dpi;
+        self
+    }
+
+    /// Shapes the run with `options` instead of the defaults, e.g. to set its features or direction
+    pub fn with_options(mut self, options: ShapingOptions<'a>) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+/// Measures a sequence of runs placed on one line, shaping all runs of the same face with a shared
+/// [`Shaper`].
+pub fn measure_runs(runs: &[MeasureRun]) -> LayoutMetrics {
+    let mut shapers: Vec<(*const ttf_parser::Face, Shaper)> = vec![];
+    let mut metrics = LayoutMetrics::default();
+    let (mut ascent, mut descent) = (0.0f32, 0.0f32);
+    for MeasureRun { text, face, font_size, dpi, options } in runs {
+        let index = match shapers.iter().position(|(key, _)| std::ptr::eq(*key, *face)) {
+            Some(index) => index,
+            None => {
+                shapers.push((*face as *const _, Shaper::new(face)));
+                shapers.len() - 1
+            }
+        };
+        let glyph_data = shapers[index].1.shape(text, options);
+        let units_to_px = font_size.units_to_px(face, *dpi);
+        let run = RunMetrics {
+            width: glyph_data.iter().map(|data| data.x_advance as f32).sum::<f32>() * units_to_px,
+            ascent: face.ascender() as f32 * units_to_px,
+            descent: -(face.descender() as f32) * units_to_px,
+        };
+        metrics.width += run.width;
+        ascent = ascent.max(run.ascent);
+        descent = descent.max(run.descent);
+        metrics.runs.push(run);
+    }
+    metrics.height = ascent + descent;
+    metrics
+}
@@ -1,6 +1,11 @@
-use std::borrow::BorrowMut;
-use crate::{GlyphData};
-use crate::mesh::{GlyphMeshBuilder, TextMesh, TextMeshBuilder};
+use std::ops::Range;
+use log::warn;
+use unicode_bidi::{BidiInfo, Level};
+use crate::{colr, GlyphData};
+use crate::font::has_outlines;
+use crate::mesh::{GlyphCache, GlyphMesh, GlyphMeshBuilder, TextMesh, TextMeshBuilder, Transform};
+use crate::renderer::{AAMode, GlyphVertex};
+use crate::shaper::{ClusterLevel, ShapeCache, Shaper, ShapingOptions};
 
 #[derive(Copy, Clone, Debug, Default)]
 pub enum Alignment {
@@ -8,6 +13,9 @@ pub enum Alignment {
     Start,
     Middle,
     End,
+    /// Vertically, puts the first line's baseline exactly on the span's y position, independent of
+    /// the `size` box, with further lines stacking downwards. Horizontally the same as `Start`.
+    Baseline,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -16,24 +24,91 @@ pub enum FontSize {
     Pt(usize)
 }
 
-impl Into<f32> for FontSize {
-    fn into(self) -> f32 {
+impl FontSize {
+    /// Resolution points are converted at unless a span or renderer sets one
+    pub const DEFAULT_DPI: f32 = 150.0;
+
+    /// Size in pixels at `dpi`, points are 1/72 inch.
+    pub fn to_px(self, dpi: f32) -> f32 {
         match self {
             FontSize::Px(x) => {x as f32}
-            FontSize::Pt(x) => {(x as f32 / 72.0) * 150.0 }
+            FontSize::Pt(x) => {(x as f32 / 72.0) * dpi }
         }
     }
+
+    /// Factor converting font units into pixels, so that one em is exactly the font size in pixels.
+    pub fn units_to_px(self, face: &ttf_parser::Face, dpi: f32) -> f32 {
+        self.to_px(dpi) / face.units_per_em() as f32
+    }
 }
 
-impl Into<i32> for FontSize {
-    fn into(self) -> i32 {
-        match self {
-            FontSize::Px(x) => {x as i32}
-            FontSize::Pt(x) => {((x as f32 / 72.0) * 150.0).round() as i32 }
+impl From<FontSize> for f32 {
+    fn from(size: FontSize) -> f32 {
+        size.to_px(FontSize::DEFAULT_DPI)
+    }
+}
+
+impl From<FontSize> for i32 {
+    fn from(size: FontSize) -> i32 {
+        size.to_px(FontSize::DEFAULT_DPI).round() as i32
+    }
+}
+
+/// Axis aligned rectangle in pixels, in the same bottom-up coordinates spans are positioned in.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Rect {
+    pub x_min: f32,
+    pub y_min: f32,
+    pub x_max: f32,
+    pub y_max: f32,
+}
+
+impl Rect {
+    pub fn union(self, other: Rect) -> Rect {
+        Rect {
+            x_min: self.x_min.min(other.x_min),
+            y_min: self.y_min.min(other.y_min),
+            x_max: self.x_max.max(other.x_max),
+            y_max: self.y_max.max(other.y_max),
         }
     }
 }
 
+/// Distance between the baselines of consecutive lines.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum LineHeight {
+    /// The font's ascender to descender height plus its line gap
+    #[default]
+    Normal,
+    /// Multiple of the font size, `Factor(1.5)` for one and a half spacing
+    Factor(f32),
+    Px(f32),
+}
+
+/// Digit style selected through the `lnum`/`onum` OpenType features.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FigureStyle {
+    /// Digits aligned to cap height
+    Lining,
+    /// Digits with ascenders and descenders, blending into running text
+    Oldstyle,
+}
+
+/// How C0/C1 control characters in span text are treated before shaping.
+///
+/// Newlines and tabs are never touched. Since text is always a `&str`, it is already valid UTF-8 and
+/// free of lone surrogates.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum ControlCharPolicy {
+    /// Remove control characters from the text.
+    Strip,
+    /// Replace control characters with U+FFFD REPLACEMENT CHARACTER.
+    Replace,
+    /// Hand control characters to the shaper as they are.
+    #[default]
+    Passthrough,
+}
+
 #[derive(Clone, Debug)]
 pub struct Span<'s> {
     text: &'s str,
@@ -43,10 +118,75 @@ pub struct Span<'s> {
     size: Option<(usize, usize)>,
     v_align: Alignment,
     h_align: Alignment,
-    color: [f32; 4]
+    color: [f32; 4],
+    opacity: f32,
+    aa_mode: Option<AAMode>,
+    z: f32,
+    features: Vec<(ttf_parser::Tag, u32)>,
+    control_char_policy: ControlCharPolicy,
+    advance_scale: f32,
+    line_height: LineHeight,
+    /// Space in pixels before and after every paragraph
+    paragraph_spacing: (f32, f32),
+    /// Maximum distance in pixels between tessellated and exact curves
+    curve_tolerance: f32,
+    /// Extra space after every cluster, in pixels and in ems
+    letter_spacing: (f32, f32),
+    start_pen: (f32, f32),
+    underline: bool,
+    underline_metrics: Option<(f32, f32)>,
+    strikethrough: bool,
+    shrink_to_fit: Option<FontSize>,
+    fit_width: Option<(f32, FontSize)>,
+    fallback_glyph: Option<ttf_parser::GlyphId>,
+    baseline_snapping: bool,
+    max_input_len: usize,
+    max_glyphs: usize,
+    cluster_level: ClusterLevel,
+    tab_width: Option<f32>,
+    wrap: bool,
+    shaper: Option<&'s Shaper<'s>>,
+    dpi: Option<f32>,
+    glyph_colors: Vec<[f32; 4]>,
+    stroke: Option<(f32, [f32; 4])>,
+    shadow: Option<((f32, f32), [f32; 4])>,
+    /// Offset in pixels, blur radius in pixels and color
+    inner_shadow: Option<((f32, f32), f32, [f32; 4])>,
+    merged_outline: bool,
+    transform: Option<Transform>,
+    direction: Option<harfbuzz::Direction>,
+    script: Option<ttf_parser::Tag>,
+    language: Option<String>,
+    /// Axis values set with `with_variations`, the last one per axis
+    variations: Vec<(ttf_parser::Tag, f32)>,
+    /// Copy of the face with the span's variation coordinates applied
+    instance: Option<ttf_parser::Face<'s>>,
+}
+
+/// A shaped line of a span
+struct Line {
+    glyph_data: Vec<GlyphData>,
+    /// Bytes of the span text the line was shaped from, without the line break
+    text_range: Range<usize>,
+    /// Pen position at the start of the line in font units, relative to the aligned span position
+    offset: (f32, f32),
+    /// The paragraph direction is right-to-left. The glyphs are in logical order until
+    /// [`Span::shape_lines`] returns, in visual order afterwards.
+    rtl: bool,
+    /// Bidi embedding level of every glyph, used for reordering into visual order
+    levels: Vec<Level>,
+    /// Index of the paragraph the line belongs to, lines wrapped from one paragraph share it
+    paragraph: usize,
 }
 
 impl<'s> Span<'s> {
+    /// Default limit on the text length in bytes handed to the shaper
+    pub const DEFAULT_MAX_INPUT_LEN: usize = 1 << 20;
+    /// Default maximum distance in pixels between tessellated and exact curves
+    pub const DEFAULT_CURVE_TOLERANCE: f32 = 0.2;
+    /// Default limit on the number of shaped glyphs that get tessellated
+    pub const DEFAULT_MAX_GLYPHS: usize = 100_000;
+
     pub fn new(font_face: &'s ttf_parser::Face<'s>, text: &'s str, x: i32, y: i32) -> Self {
         Self {
             text,
@@ -57,9 +197,68 @@ impl<'s> Span<'s> {
             v_align: Alignment::Start,
             h_align: Alignment::Start,
             color: [0.0, 0.0, 0.0, 1.0],
+            opacity: 1.0,
+            aa_mode: None,
+            z: 0.0,
+            features: vec![],
+            control_char_policy: ControlCharPolicy::default(),
+            advance_scale: 1.0,
+            line_height: LineHeight::default(),
+            paragraph_spacing: (0.0, 0.0),
+            curve_tolerance: Self::DEFAULT_CURVE_TOLERANCE,
+            letter_spacing: (0.0, 0.0),
+            start_pen: (0.0, 0.0),
+            underline: false,
+            underline_metrics: None,
+            strikethrough: false,
+            shrink_to_fit: None,
+            fit_width: None,
+            fallback_glyph: None,
+            baseline_snapping: false,
+            max_input_len: Self::DEFAULT_MAX_INPUT_LEN,
+            max_glyphs: Self::DEFAULT_MAX_GLYPHS,
+            cluster_level: ClusterLevel::default(),
+            tab_width: None,
+            wrap: false,
+            shaper: None,
+            dpi: None,
+            glyph_colors: vec![],
+            stroke: None,
+            shadow: None,
+            inner_shadow: None,
+            merged_outline: false,
+            transform: None,
+            direction: None,
+            script: None,
+            language: None,
+            variations: vec![],
+            instance: None,
         }
     }
 
+    pub fn with_position(mut self, x: i32, y: i32) -> Self {
+        self.position = (x, y);
+        self
+    }
+
+    /// Shapes with `shaper` instead of creating HarfBuzz objects for the span's face on every layout.
+    /// The shaper has to be created from the span's face.
+    pub fn with_shaper(mut self, shaper: &'s Shaper<'s>) -> Self {
+        self.shaper = Some(shaper);
+        self
+    }
+
+    /// Wraps lines at whitespace so they fit the width of the span's `size`. Words wider than the
+    /// box are broken between glyphs. Has no effect without a size.
+    pub fn with_wrap(mut self, wrap: bool) -> Self {
+        self.wrap = wrap;
+        self
+    }
+
+    /// Sets the box in pixels, with its bottom-left corner at the span position, that the text is
+    /// aligned and wrapped in. Without a size the alignment is ignored: the first line's baseline
+    /// sits on the span position and every further line stacks below it, so multi-line text
+    /// extends downwards past the position.
     pub fn with_size(mut self, width: usize, height: usize) -> Self {
         self.size = Some((width, height));
         self
@@ -85,25 +284,988 @@ impl<'s> Span<'s> {
         self
     }
     
+    /// Resolution `FontSize::Pt` sizes are converted to pixels at. Without it the renderer's DPI is
+    /// used, or [`FontSize::DEFAULT_DPI`] when measuring.
+    pub fn with_dpi(mut self, dpi: f32) -> Self {
+        self.dpi = Some(dpi);
+        self
+    }
+
+    pub fn get_dpi(&self) -> Option<f32> {
+        self.dpi
+    }
+
+    /// Colors glyphs by the index of the first character of their cluster, e.g. for syntax
+    /// highlighting. Characters past the end of `colors` use the span color.
+    pub fn with_glyph_colors(mut self, colors: Vec<[f32; 4]>) -> Self {
+        self.glyph_colors = colors;
+        self
+    }
+
+    /// Outlines every glyph with a stroke of `width` pixels in `color`, centered on the outline and
+    /// drawn over the fill. Use a fill color with zero alpha for outlined text only.
+    pub fn with_stroke(mut self, width: f32, color: [f32; 4]) -> Self {
+        self.stroke = Some((width, color));
+        self
+    }
+
+    /// Draws a hard shadow of the text, strokes and decorations in `color` behind it, moved by
+    /// `offset` pixels, e.g. `(2.0, -2.0)` for down and to the right.
+    ///
+    /// Blurred shadows need an offscreen blur pass the renderer doesn't have yet, a `blur` above zero
+    /// is ignored with a warning.
+    pub fn with_shadow(mut self, offset: (f32, f32), color: [f32; 4], blur: f32) -> Self {
+        if blur > 0.0 {
+            warn!("shadow blur is not supported yet, drawing a hard shadow");
+        }
+        self.shadow = Some((offset, color));
+        self
+    }
+
+    /// Darkens the inside of the glyphs with `color` along the edges facing away from `offset`, as if
+    /// the glyphs were cut into the surface, e.g. `(2.0, -2.0)` for light from the top left darkening
+    /// the top and left edges. The shadow's edge is blurred over `blur` pixels.
+    ///
+    /// The glyph coverage is rendered into a scratch texture, which is composited after every span
+    /// is drawn: the coverage times one minus its blurred and shifted copy. Only
+    /// [`TextureRenderer`](crate::TextureRenderer) draws inner shadows, [`crate::raster`] leaves
+    /// them out.
+    pub fn with_inner_shadow(mut self, offset: (f32, f32), blur: f32, color: [f32; 4]) -> Self {
+        self.inner_shadow = Some((offset, blur.max(0.0), color));
+        self
+    }
+
+    /// Offset, blur and color of the inner shadow, its alpha multiplied by the span's opacity
+    pub(crate) fn inner_shadow(&self) -> Option<((f32, f32), f32, [f32; 4])> {
+        self.inner_shadow.map(|(offset, blur, [r, g, b, a])| (offset, blur, [r, g, b, a * self.opacity]))
+    }
+
+    /// Number of leading [`Span::palette`] entries the text itself is drawn with, the stroke and
+    /// shadow colors come after them. Triangles in these colors cover exactly the text the inner
+    /// shadow is clipped to.
+    pub(crate) fn fill_color_count(&self) -> usize {
+        self.palette().len() - self.stroke.is_some() as usize - self.shadow.is_some() as usize
+    }
+
+    /// Fills overlapping glyphs as one shape, the union of their outlines, so they merge without
+    /// internal edges, e.g. for stencils or text with negative letter spacing. Overlapping glyphs
+    /// lose their own colors to the color of the first one, and their curves are flattened at the
+    /// curve tolerance.
+    pub fn with_merged_outline(mut self, merged: bool) -> Self {
+        self.merged_outline = merged;
+        self
+    }
+
+    /// Applies an affine transform in pixel space around the aligned span position after layout:
+    /// `x' = matrix[0][0] * x + matrix[0][1] * y + translate.0`, likewise for `y'`.
+    ///
+    /// With y pointing up, `[[c, -s], [s, c]]` rotates counter-clockwise and `[[1.0, k], [0.0, 1.0]]`
+    /// slants upright glyphs like a faux italic.
+    pub fn with_transform(mut self, matrix: [[f32; 2]; 2], translate: (f32, f32)) -> Self {
+        self.transform = Some((matrix, translate));
+        self
+    }
+
+    /// Multiplies the alpha of everything the span draws by `opacity`, clamped to `0.0..=1.0`. At 0
+    /// nothing is visible, at 1 (the default) the colors are used as they are.
+    pub fn with_opacity(mut self, opacity: f32) -> Self {
+        self.opacity = opacity.clamp(0.0, 1.0);
+        self
+    }
+
+    /// The span color followed by the glyph colors, the colors and gradient ramps of the color glyph
+    /// layers in the text, the stroke and the shadow color, as indexed by the generated mesh's color indices relative to the index it
+    /// was generated with. Alphas are multiplied by the span's opacity.
+    pub fn palette(&self) -> Vec<[f32; 4]> {
+        let mut palette = vec![self.color];
+        palette.extend_from_slice(&self.glyph_colors);
+        palette.extend(self.layer_colors());
+        if let Some((_, color)) = self.stroke {
+            palette.push(color);
+        }
+        if let Some((_, color)) = self.shadow {
+            palette.push(color);
+        }
+        palette.iter_mut().for_each(|color| color[3] *= self.opacity);
+        palette
+    }
+
     pub fn get_color(&self) -> [f32; 4] {
         self.color
     }
 
-    pub fn generate_text_mesh(&self, color_index: u32) -> TextMesh {
-        let glyph_data = self.shape_glyph_data();
+    /// Colors the layers of the COLR color glyphs in the text are painted with: the colors of solid
+    /// layers and the [`colr::RAMP_LEN`] colors sampled from the color line of every gradient layer.
+    /// Empty for fonts without color glyphs, otherwise the text is shaped to find them.
+    fn layer_colors(&self) -> Vec<[f32; 4]> {
+        match colr::Table::of(self.face()) {
+            Some(colr) => self.layer_colors_of(&colr, &self.shape_lines()),
+            None => vec![],
+        }
+    }
+
+    /// [`Span::layer_colors`] of the color glyphs in `lines`, in the order they first appear
+    fn layer_colors_of(&self, colr: &colr::Table, lines: &[Line]) -> Vec<[f32; 4]> {
+        let cpal = self.cpal();
+        let mut colors: Vec<[f32; 4]> = vec![];
+        let mut seen = vec![];
+        for data in lines.iter().flat_map(|line| &line.glyph_data) {
+            let glyph_id = ttf_parser::GlyphId(data.glyph_id as u16);
+            if seen.contains(&glyph_id) {
+                continue;
+            }
+            seen.push(glyph_id);
+            for layer in colr.layers(glyph_id).unwrap_or_default() {
+                let entries = self.layer_entries(&layer.fill, cpal.as_ref());
+                if !entries.is_empty() && !colors.windows(entries.len()).any(|window| window == entries.as_slice()) {
+                    colors.extend(entries);
+                }
+            }
+        }
+        colors
+    }
+
+    /// The face's CPAL table, which the layers of COLR color glyphs pick their colors from
+    fn cpal(&self) -> Option<ttf_parser::cpal::Table<'_>> {
+        // The parsed tables don't expose CPAL, it is parsed from the raw table
+        self.face().raw_face().table(ttf_parser::Tag::from_bytes(b"CPAL")).and_then(ttf_parser::cpal::Table::parse)
+    }
+
+    /// Palette entries a layer filled with `fill` is painted with: its color, or the ramp of its
+    /// gradient. Empty for layers in the plain text color, which use palette entry 0.
+    fn layer_entries(&self, fill: &colr::Fill, cpal: Option<&ttf_parser::cpal::Table>) -> Vec<[f32; 4]> {
+        // Entries of the first palette, the text color for the foreground index
+        let resolve = |color: colr::ColorRef| {
+            let [r, g, b, a] = match color.palette_index {
+                colr::FOREGROUND => self.color,
+                index => cpal.and_then(|cpal| cpal.get(0, index))
+                    .map_or(self.color, |c| [c.red, c.green, c.blue, c.alpha].map(|c| c as f32 / 255.0)),
+            };
+            [r, g, b, a * color.alpha]
+        };
+        match fill {
+            colr::Fill::Solid(color) if color.palette_index == colr::FOREGROUND && color.alpha == 1.0 => vec![],
+            colr::Fill::Solid(color) => vec![resolve(*color)],
+            colr::Fill::Linear { line, .. } | colr::Fill::Radial { line, .. } | colr::Fill::Sweep { line, .. } => line.ramp(resolve),
+        }
+    }
+
+    /// Quad showing the embedded bitmap of `glyph_id` from the strike closest to the rendered size,
+    /// `None` if the glyph has none or it isn't a PNG.
+    ///
+    /// The image is pushed to `bitmaps`. The quad's vertices have bit 2 of `metadata` set and the
+    /// image's index in `bitmaps` from bit 4 up, their `uv` coordinates span the image from the
+    /// top-left corner at (0, 0). Bit 3 is left for shadow copies, which only use the image's alpha.
+    fn bitmap_glyph_mesh(&self, glyph_id: ttf_parser::GlyphId, pixel_scale: f32, bitmaps: &mut Vec<image::RgbaImage>) -> Option<GlyphMesh> {
+        let pixels_per_em = (self.font_size.to_px(self.dpi()) * pixel_scale).round().clamp(1.0, u16::MAX as f32) as u16;
+        let raster = self.face().glyph_raster_image(glyph_id, pixels_per_em)?;
+        if !matches!(raster.format, ttf_parser::RasterImageFormat::PNG) {
+            warn!("glyph {} has an embedded bitmap in {:?}, only PNG is supported", glyph_id.0, raster.format);
+            return None;
+        }
+        let image = match image::load_from_memory_with_format(raster.data, image::ImageFormat::Png) {
+            Ok(image) => image.to_rgba8(),
+            Err(error) => {
+                warn!("failed to decode the embedded bitmap of glyph {}: {error}", glyph_id.0);
+                return None;
+            }
+        };
+        // The offsets are in pixels of the strike, from the glyph origin to the bottom-left corner
+        let scale = self.face().units_per_em() as f32 / raster.pixels_per_em as f32;
+        let min = (raster.x as f32 * scale, raster.y as f32 * scale);
+        let max = (
+            (raster.x as f32 + raster.width as f32) * scale,
+            (raster.y as f32 + raster.height as f32) * scale,
+        );
+        let metadata = 4 | (bitmaps.len() as i32) << 4;
+        bitmaps.push(image);
+        let vertices = [((min.0, min.1), (0.0, 1.0)), ((max.0, min.1), (1.0, 1.0)), ((max.0, max.1), (1.0, 0.0)), ((min.0, max.1), (0.0, 0.0))]
+            .map(|((x, y), uv)| GlyphVertex {
+                position: [x, y, 0.0],
+                uv: [uv.0, uv.1],
+                metadata,
+                color_index: 0,
+                paint: [0.0; 4],
+            });
+        Some(GlyphMesh {
+            glyph_id,
+            vertices: vertices.to_vec(),
+            indices: vec![0, 1, 2, 0, 2, 3],
+            bounds: ttf_parser::Rect {
+                x_min: min.0.floor() as i16,
+                y_min: min.1.floor() as i16,
+                x_max: max.0.ceil() as i16,
+                y_max: max.1.ceil() as i16,
+            },
+            contours: vec![],
+        })
+    }
+
+    /// Tessellates every layer of a COLR color glyph into one mesh, layers painted with the text
+    /// color keep color index 0, the others index into [`Span::layer_colors`] after the glyph colors.
+    /// Gradient layers index the first color of their ramp, see [`colr::Gradient`].
+    fn color_glyph_mesh(
+        &self,
+        colr: &colr::Table,
+        glyph_id: ttf_parser::GlyphId,
+        layer_colors: &[[f32; 4]],
+        curve_tolerance: f32,
+        glyph_cache: &mut GlyphCache,
+    ) -> Option<GlyphMesh> {
+        let cpal = self.cpal();
+        let mut color_mesh: Option<GlyphMesh> = None;
+        for layer in colr.layers(glyph_id)? {
+            let gradient = colr::Gradient::new(&layer.fill, layer.fill_transform);
+            if gradient.is_none() && !matches!(layer.fill, colr::Fill::Solid(_)) {
+                continue;
+            }
+            let mesh = match self.instance {
+                Some(ref face) => GlyphMeshBuilder::new().with_curve_tolerance(curve_tolerance).build(face, layer.glyph_id),
+                None => glyph_cache.get_or_build_with_tolerance(self.font_face, layer.glyph_id, curve_tolerance),
+            };
+            let Some(mut layer_mesh) = mesh else { continue };
+            layer_mesh.transform(layer.outline_transform);
+            let entries = self.layer_entries(&layer.fill, cpal.as_ref());
+            let color_index = (!entries.is_empty())
+                .then(|| layer_colors.windows(entries.len()).position(|window| window == entries.as_slice()))
+                .flatten()
+                .map_or(0, |entry| (self.glyph_colors.len() + 1 + entry) as u32);
+            for v in &mut layer_mesh.vertices {
+                v.color_index = color_index;
+                if let Some(gradient) = gradient {
+                    v.metadata |= gradient.metadata;
+                    v.paint = gradient.paint((v.position[0], v.position[1]));
+                }
+            }
+            // Later layers are painted over earlier ones, they keep their order within the glyph
+            match color_mesh {
+                None => color_mesh = Some(GlyphMesh { glyph_id, ..layer_mesh }),
+                Some(ref mut mesh) => {
+                    let base = mesh.vertices.len() as u32;
+                    mesh.indices.extend(layer_mesh.indices.iter().map(|i| *i + base));
+                    mesh.vertices.extend(layer_mesh.vertices);
+                    mesh.contours.extend(layer_mesh.contours);
+                    mesh.bounds = ttf_parser::Rect {
+                        x_min: mesh.bounds.x_min.min(layer_mesh.bounds.x_min),
+                        y_min: mesh.bounds.y_min.min(layer_mesh.bounds.y_min),
+                        x_max: mesh.bounds.x_max.max(layer_mesh.bounds.x_max),
+                        y_max: mesh.bounds.y_max.max(layer_mesh.bounds.y_max),
+                    };
+                }
+            }
+        }
+        color_mesh
+    }
+
+    /// Overrides the renderer's anti-aliasing mode for this span.
+    ///
+    /// Mixing modes in one render costs an extra render pass: all multisampled spans share a single
+    /// pass at the highest requested sample count, after which aliased spans are drawn directly onto
+    /// the resolved texture. Aliased spans therefore always end up on top of multisampled ones.
+    pub fn with_aa_mode(mut self, aa_mode: AAMode) -> Self {
+        self.aa_mode = Some(aa_mode);
+        self
+    }
+
+    pub fn get_aa_mode(&self) -> Option<AAMode> {
+        self.aa_mode
+    }
+
+    /// Layer of the span. Within a render pass, spans with a higher `z` are drawn over ones with a
+    /// lower `z` regardless of the order they were added in, and spans on the same layer keep their
+    /// order. The default is 0.
+    ///
+    /// Spans in different render passes, see [`Span::with_aa_mode`], don't share layers: multisampled
+    /// spans are drawn first, then aliased, supersampled and subpixel ones, whatever their `z`.
+    pub fn with_z(mut self, z: f32) -> Self {
+        self.z = z;
+        self
+    }
+
+    pub fn get_z(&self) -> f32 {
+        self.z
+    }
+
+    pub fn with_control_char_policy(mut self, policy: ControlCharPolicy) -> Self {
+        self.control_char_policy = policy;
+        self
+    }
+
+    /// Scales every glyph advance by `scale`, e.g. `1.05` for loose or `0.95` for tight typesetting.
+    pub fn with_advance_scale(mut self, scale: f32) -> Self {
+        self.advance_scale = scale;
+        self
+    }
+
+    /// Sets how far in pixels tessellated curves may deviate from the exact outline at the rendered
+    /// size. Larger text is subdivided more finely, lower values trade triangles for smoothness.
+    pub fn with_curve_tolerance(mut self, px: f32) -> Self {
+        self.curve_tolerance = px;
+        self
+    }
+
+    /// Sets the distance between the baselines of consecutive lines.
+    pub fn with_line_height(mut self, line_height: LineHeight) -> Self {
+        self.line_height = line_height;
+        self
+    }
+
+    /// Adds `before` pixels above and `after` pixels below every paragraph, on top of the line
+    /// height. Paragraphs are separated by `\n`, lines wrapped from one paragraph keep the line height.
+    ///
+    /// The spacing is part of the block height used for vertical alignment, [`Span::measure`] and
+    /// fitting, so the first baseline is `before` pixels below the top of the block.
+    pub fn with_paragraph_spacing(mut self, before: f32, after: f32) -> Self {
+        self.paragraph_spacing = (before, after);
+        self
+    }
+
+    /// Adds `px` pixels between glyph clusters, independent of the font size. Nothing is added after
+    /// the last cluster of a line.
+    pub fn with_letter_spacing_px(mut self, px: f32) -> Self {
+        self.letter_spacing = (px, 0.0);
+        self
+    }
+
+    /// Adds `em` times the font size between glyph clusters, see [`Span::with_letter_spacing_px`].
+    pub fn with_letter_spacing_em(mut self, em: f32) -> Self {
+        self.letter_spacing = (0.0, em);
+        self
+    }
+
+    /// Starts the pen at `pen` pixels relative to the span position instead of at its origin.
+    ///
+    /// Together with [`Span::end_pen`] this lets differently styled runs continue on the same line.
+    pub fn with_start_pen(mut self, pen: (f32, f32)) -> Self {
+        self.start_pen = pen;
+        self
+    }
+
+    /// Pen position in pixels relative to the span position after the last glyph, to be passed to
+    /// [`Span::with_start_pen`] of the following run. It includes the offset of the alignment inside
+    /// `size` and is measured at the fitted font size.
+    pub fn end_pen(&self) -> (f32, f32) {
+        if let Some(span) = self.fitted() {
+            return span.end_pen();
+        }
+        let units_to_px = self.units_to_px();
+        let (lines, origin) = self.layout();
+        let line = lines.last().unwrap();
+        let x = line.offset.0 + self.advance_width(&line.glyph_data);
+        let y = line.offset.1 + line.glyph_data.iter().map(|data| data.y_advance as f32).sum::<f32>();
+        (
+            (origin.0 - self.position.0) as f32 + x * units_to_px,
+            (origin.1 - self.position.1) as f32 + y * units_to_px,
+        )
+    }
+
+    /// Shrinks the font size until the text fits into the span's `size`, but not below `min_size`.
+    /// The configured font size is used as is if it already fits or no size is set.
+    pub fn with_shrink_to_fit(mut self, min_size: FontSize) -> Self {
+        self.shrink_to_fit = Some(min_size);
+        self
+    }
+
+    /// Shrinks the font size until the widest line is at most `max_px` wide, but not below
+    /// `min_size`. The configured font size is used as is if it already fits, see
+    /// [`Span::fitted_font_size`] for the size that is used.
+    ///
+    /// Unlike [`Span::with_shrink_to_fit`] this doesn't need a `size` and ignores the height. Both
+    /// can be combined, the text then satisfies both limits.
+    pub fn with_fit_width(mut self, max_px: f32, min_size: FontSize) -> Self {
+        self.fit_width = Some((max_px, min_size));
+        self
+    }
+
+    /// The span with the fitted font size and fitting turned off, `None` if no fitting is set
+    fn fitted(&self) -> Option<Span<'s>> {
+        if self.shrink_to_fit.is_none() && self.fit_width.is_none() {
+            return None;
+        }
+        let mut span = self.clone();
+        span.font_size = self.fitted_font_size();
+        span.shrink_to_fit = None;
+        span.fit_width = None;
+        Some(span)
+    }
+
+    /// Font size the span is rendered at: the largest pixel size between the minimum and the
+    /// configured size that fits the text into `size` with [`Span::with_shrink_to_fit`] and into the
+    /// width of [`Span::with_fit_width`], found by binary search. The configured size otherwise.
+    pub fn fitted_font_size(&self) -> FontSize {
+        let shrink_to_fit = self.shrink_to_fit.zip(self.size);
+        let (max_width, max_height, min_size) = match (shrink_to_fit, self.fit_width) {
+            (None, None) => return self.font_size,
+            (Some((min_size, size)), None) => (size.0 as f32, size.1 as f32, min_size),
+            (None, Some((max_px, min_size))) => (max_px, f32::INFINITY, min_size),
+            (Some((min_size, size)), Some((max_px, fit_min_size))) => {
+                // The larger floor wins, neither limit may shrink the text below its own floor
+                let min_size = if min_size.to_px(self.dpi()) >= fit_min_size.to_px(self.dpi()) { min_size } else { fit_min_size };
+                ((size.0 as f32).min(max_px), size.1 as f32, min_size)
+            }
+        };
+        let lines = self.shape_lines();
+        let advance_width = lines.iter().map(|line| self.advance_width(&line.glyph_data)).fold(0.0, f32::max);
+        let block_height = self.face().ascender() as f32 - self.face().descender() as f32
+            + (lines.len() - 1) as f32 * self.line_height();
+        // Paragraph spacing is in pixels, it doesn't shrink with the font size
+        let spacing = self.paragraph_spacing_total(&lines);
+        let units_per_em = self.face().units_per_em() as f32;
+        let fits = |px: usize| {
+            let scale = px as f32 / units_per_em;
+            advance_width * scale <= max_width && block_height * scale + spacing <= max_height
+        };
+        let max_px = self.font_size.to_px(self.dpi()).floor() as usize;
+        if fits(max_px) {
+            return self.font_size;
+        }
+        let (mut low, mut high) = (min_size.to_px(self.dpi()).ceil() as usize, max_px);
+        while low < high {
+            let middle = (low + high).div_ceil(2);
+            if fits(middle) { low = middle } else { high = middle - 1 }
+        }
+        FontSize::Px(low.min(max_px))
+    }
+
+    /// Draws `glyph_id` (with its own advance) wherever shaping falls back to `.notdef`.
+    pub fn with_fallback_glyph(mut self, glyph_id: ttf_parser::GlyphId) -> Self {
+        self.fallback_glyph = Some(glyph_id);
+        self
+    }
+
+    /// Like [`Span::with_fallback_glyph`], using the glyph the font maps `c` to.
+    pub fn with_fallback_char(self, c: char) -> Self {
+        match self.face().glyph_index(c) {
+            Some(glyph_id) => self.with_fallback_glyph(glyph_id),
+            None => {
+                warn!("ignoring fallback character {c:?}, the font has no glyph for it");
+                self
+            }
+        }
+    }
+
+    /// Snaps only the baseline of every line to a whole physical pixel, leaving the shaped horizontal
+    /// positions untouched. Line heights are rounded to whole pixels along with it.
+    pub fn with_baseline_snapping(mut self, snap: bool) -> Self {
+        self.baseline_snapping = snap;
+        self
+    }
+
+    /// Limits the text handed to the shaper to `max_input_len` bytes, truncating at the last
+    /// character boundary before it with a warning. Protects against pathological untrusted input.
+    pub fn with_max_input_len(mut self, max_input_len: usize) -> Self {
+        self.max_input_len = max_input_len;
+        self
+    }
+
+    /// Limits the number of shaped glyphs, dropping any further glyphs with a warning.
+    pub fn with_max_glyphs(mut self, max_glyphs: usize) -> Self {
+        self.max_glyphs = max_glyphs;
+        self
+    }
+
+    /// Sets how characters are merged into the clusters reported in [`GlyphData::cluster`], use
+    /// [`ClusterLevel::MonotoneGraphemes`] for selection and hit-testing by user-perceived character.
+    pub fn with_cluster_level(mut self, cluster_level: ClusterLevel) -> Self {
+        self.cluster_level = cluster_level;
+        self
+    }
+
+    /// Places tab stops every `spaces` space advances instead of using the font's tab advance.
+    /// Measurement and rendering both go through the same expansion.
+    pub fn with_tab_width(mut self, spaces: f32) -> Self {
+        self.tab_width = Some(spaces);
+        self
+    }
+
+    /// Draws an underline in the span color, placed using the font's `post` table metrics.
+    pub fn with_underline(mut self, underline: bool) -> Self {
+        self.underline = underline;
+        self
+    }
+
+    /// Overrides the font's underline metrics with `position` (top edge relative to the baseline,
+    /// negative is below) and `thickness`, both in pixels.
+    pub fn with_underline_metrics(mut self, position: f32, thickness: f32) -> Self {
+        self.underline_metrics = Some((position, thickness));
+        self
+    }
+
+    /// Underline position and thickness in font units.
+    fn underline_metrics(&self) -> (f32, f32) {
+        if let Some((position, thickness)) = self.underline_metrics {
+            let units_to_px = self.units_to_px();
+            return (position / units_to_px, thickness / units_to_px);
+        }
+        match self.face().underline_metrics() {
+            Some(metrics) => (metrics.position as f32, metrics.thickness as f32),
+            // Fonts without a post table, fall back to typical proportions
+            None => {
+                let units_per_em = self.face().units_per_em() as f32;
+                (-0.1 * units_per_em, 0.05 * units_per_em)
+            }
+        }
+    }
+
+    /// Draws a line through the text in the span color, placed using the font's `OS/2` table
+    /// strikeout metrics.
+    pub fn with_strikethrough(mut self, strikethrough: bool) -> Self {
+        self.strikethrough = strikethrough;
+        self
+    }
+
+    /// Strikethrough position (top edge relative to the baseline) and thickness in font units.
+    fn strikethrough_metrics(&self) -> (f32, f32) {
+        match self.face().strikeout_metrics() {
+            Some(metrics) => (metrics.position as f32, metrics.thickness as f32),
+            // Fonts without an OS/2 table, fall back to typical proportions around half the x-height
+            None => {
+                let units_per_em = self.face().units_per_em() as f32;
+                (0.3 * units_per_em, 0.05 * units_per_em)
+            }
+        }
+    }
+
+    /// Position and thickness in font units of every enabled decoration line
+    fn decorations(&self) -> Vec<(f32, f32)> {
+        let mut decorations = vec![];
+        if self.underline {
+            decorations.push(self.underline_metrics());
+        }
+        if self.strikethrough {
+            decorations.push(self.strikethrough_metrics());
+        }
+        decorations
+    }
+
+    /// Selects lining or oldstyle figures. If the font doesn't provide the style, a warning is
+    /// logged and the font's default digits are used.
+    pub fn with_figure_style(mut self, style: FigureStyle) -> Self {
+        let (tag, other) = match style {
+            FigureStyle::Lining => (ttf_parser::Tag::from_bytes(b"lnum"), ttf_parser::Tag::from_bytes(b"onum")),
+            FigureStyle::Oldstyle => (ttf_parser::Tag::from_bytes(b"onum"), ttf_parser::Tag::from_bytes(b"lnum")),
+        };
+        let is_supported = self.face().tables().gsub
+            .is_some_and(|gsub| gsub.features.find(tag).is_some());
+        if !is_supported {
+            warn!("font has no {} feature, using its default figures", tag);
+            return self;
+        }
+        self.features.retain(|(t, _)| *t != other);
+        self.set_feature(tag, 1);
+        self
+    }
+
+    /// Enables or disables discretionary ligatures (`dlig`).
+    pub fn with_discretionary_ligatures(mut self, enabled: bool) -> Self {
+        self.set_feature(ttf_parser::Tag::from_bytes(b"dlig"), enabled as u32);
+        self
+    }
+
+    /// Enables the stylistic set `ssNN`, where `set` has to be between 1 and 20.
+    pub fn with_stylistic_set(mut self, set: u8) -> Self {
+        if !(1..=20).contains(&set) {
+            warn!("ignoring stylistic set {set}, only ss01 to ss20 exist");
+            return self;
+        }
+        let tag = format!("ss{:02}", set);
+        self.set_feature(ttf_parser::Tag::from_bytes_lossy(tag.as_bytes()), 1);
+        self
+    }
+
+    /// Sets OpenType features for shaping the whole text, like `liga` = 0 to disable ligatures for
+    /// code or `calt` = 0 for contextual alternates. Values replace earlier ones of the same feature.
+    pub fn with_features(mut self, features: &[(ttf_parser::Tag, u32)]) -> Self {
+        for (tag, value) in features {
+            self.set_feature(*tag, *value);
+        }
+        self
+    }
+
+    /// Uses `direction` as the paragraph direction instead of taking it from the first strong
+    /// character. Runs of the other direction embedded in the text are still reordered.
+    pub fn with_direction(mut self, direction: harfbuzz::Direction) -> Self {
+        self.direction = Some(direction);
+        self
+    }
+
+    /// Shapes as the ISO 15924 `script`, like `Arab`, instead of guessing it from the text.
+    pub fn with_script(mut self, script: ttf_parser::Tag) -> Self {
+        self.script = Some(script);
+        self
+    }
+
+    /// Shapes with the language-specific behavior of the BCP 47 `language`, like `ar` or `tr`.
+    pub fn with_language(mut self, language: &str) -> Self {
+        self.language = Some(language.to_string());
+        self
+    }
+
+    /// Sets coordinates on variation axes of the font, like `wght` or `wdth`, for outlines and
+    /// shaping. Axes the font doesn't have are ignored with a warning.
+    ///
+    /// Glyphs of spans with variations are tessellated on every render instead of being cached, and
+    /// a shaper set with [`Span::with_shaper`] is not used for them.
+    pub fn with_variations(mut self, variations: &[(ttf_parser::Tag, f32)]) -> Self {
+        for (tag, value) in variations {
+            if !self.font_face.variation_axes().into_iter().any(|axis| axis.tag == *tag) {
+                warn!("font has no {} variation axis, ignoring it", tag);
+                continue;
+            }
+            self.variations.retain(|(t, _)| t != tag);
+            self.variations.push((*tag, *value));
+        }
+        // `set_variation` maps all coordinates through `avar` again on every call, so the instance
+        // starts from the span's face each time instead of building on the previous one. With several
+        // axes the earlier ones are still mapped more than once, which only matters for fonts with `avar`.
+        let mut face = self.font_face.clone();
+        for (tag, value) in &self.variations {
+            face.set_variation(*tag, *value);
+        }
+        self.instance = (!self.variations.is_empty()).then_some(face);
+        self
+    }
+
+    fn set_feature(&mut self, tag: ttf_parser::Tag, value: u32) {
+        self.features.retain(|(t, _)| *t != tag);
+        self.features.push((tag, value));
+    }
+
+    /// Generates the text mesh in normalized device coordinates of a `viewport` sized target.
+    pub fn generate_text_mesh(&self, color_index: u32, viewport: (u32, u32)) -> TextMesh {
+        self.build_text_mesh(color_index, None, viewport, 1.0, &mut GlyphCache::new(), &mut ShapeCache::new(), None)
+    }
+
+    /// Generates the text mesh, optionally with debug frames around the advance and ink box of every
+    /// glyph, using the color indices in `glyph_boxes`. `viewport` is the target size in physical
+    /// pixels and `pixel_scale` the device pixel ratio the mesh is rasterized at. Glyph meshes are
+    /// taken from `glyph_cache` and shaped runs from `shape_cache` where possible.
+    ///
+    /// Embedded bitmaps of glyphs without an outline are only drawn if `bitmaps` is given, as quads
+    /// referring to the images they push there, see [`Span::bitmap_glyph_mesh`]. Without it the
+    /// glyphs are left out.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn build_text_mesh(
+        &self,
+        color_index: u32,
+        glyph_boxes: Option<(u32, u32)>,
+        viewport: (u32, u32),
+        pixel_scale: f32,
+        glyph_cache: &mut GlyphCache,
+        shape_cache: &mut ShapeCache<'s>,
+        mut bitmaps: Option<&mut Vec<image::RgbaImage>>,
+    ) -> TextMesh {
+        if let Some(span) = self.fitted() {
+            return span.build_text_mesh(color_index, glyph_boxes, viewport, pixel_scale, glyph_cache, shape_cache, bitmaps);
+        }
+        if !has_outlines(self.face()) && bitmaps.is_none() {
+            warn!("font has no glyf, CFF or CFF2 table, bitmap glyphs are only drawn by the renderer");
+        }
+        let (lines, text_position) = self.layout_cached(shape_cache);
+        // Byte offset of every character, to look up glyph colors by character index
+        let char_starts = if self.glyph_colors.is_empty() {
+            vec![]
+        } else {
+            self.text.char_indices().map(|(index, _)| index).collect::<Vec<_>>()
+        };
+        let curve_tolerance = self.curve_tolerance / (self.units_to_px() * pixel_scale);
+        let colr = colr::Table::of(self.face());
+        let layer_colors = colr.as_ref().map_or(vec![], |colr| self.layer_colors_of(colr, &lines));
+        // Empty glyphs like spaces only advance the pen. The glyf table stores their bounds, other
+        // outline formats would have to be outlined to find them
+        let is_empty = |glyph_id| {
+            self.face().tables().glyf.is_some() && self.face().glyph_bounding_box(glyph_id).is_none()
+        };
+        // Tessellate all glyphs up front, in parallel with the `rayon` feature
+        if self.instance.is_none() {
+            let glyph_ids = lines.iter()
+                .flat_map(|line| &line.glyph_data)
+                .map(|data| ttf_parser::GlyphId(data.glyph_id as u16))
+                .filter(|glyph_id| !is_empty(*glyph_id))
+                .collect::<Vec<_>>();
+            glyph_cache.prepare_with_tolerance(self.font_face, &glyph_ids, curve_tolerance);
+        }
         let mut text_mesh_builder = TextMeshBuilder::new();
-        let mut width = 0.0;
-        for data in glyph_data {
-            width += data.x_advance as f32;
-            let mesh = GlyphMeshBuilder::new().build(&self.font_face, ttf_parser::GlyphId(data.glyph_id as u16));
-            text_mesh_builder.add(mesh, data);
-        }
-        // Align text
-        width = width / self.font_face.height() as f32 * <FontSize as Into<f32>>::into(self.font_size) * 1.254; // Convert width to pixels
+        for (index, line) in lines.into_iter().enumerate() {
+            if index == 0 {
+                text_mesh_builder.with_start_pen(line.offset.0, line.offset.1);
+            } else {
+                text_mesh_builder.new_line(line.offset.0, line.offset.1);
+            }
+            for data in line.glyph_data {
+                let glyph_id = ttf_parser::GlyphId(data.glyph_id as u16);
+                // Variation instances are owned by the span, so their address is no cache key
+                let mut mesh = match (self.instance.as_ref(), colr.as_ref()) {
+                    (_, Some(colr)) if colr.contains(glyph_id) => {
+                        self.color_glyph_mesh(colr, glyph_id, &layer_colors, curve_tolerance, glyph_cache)
+                    }
+                    _ if is_empty(glyph_id) => None,
+                    (Some(face), _) => GlyphMeshBuilder::new().with_curve_tolerance(curve_tolerance).build(face, glyph_id),
+                    (None, _) => glyph_cache.get_or_build_with_tolerance(self.font_face, glyph_id, curve_tolerance),
+                };
+                let char_index = char_starts.partition_point(|start| *start < data.cluster as usize);
+                if mesh.is_none() {
+                    if let Some(bitmaps) = bitmaps.as_deref_mut() {
+                        mesh = self.bitmap_glyph_mesh(glyph_id, pixel_scale, bitmaps);
+                    }
+                }
+                if let (Some(mesh), true) = (&mut mesh, char_index < self.glyph_colors.len()) {
+                    // Palette entry 0 is the span color. Color glyph layers with their own color keep it
+                    mesh.vertices.iter_mut()
+                        .filter(|v| v.color_index == 0)
+                        .for_each(|v| v.color_index = char_index as u32 + 1);
+                }
+                text_mesh_builder.add(mesh, data);
+            }
+        }
+        text_mesh_builder.with_position(text_position.0, text_position.1);
+        text_mesh_builder.with_font_size(self.font_size);
+        text_mesh_builder.with_dpi(self.dpi());
+        if let Some((width, _)) = self.stroke {
+            let stroke_color_index = self.glyph_colors.len() + layer_colors.len() + 1;
+            text_mesh_builder.with_stroke(width / self.units_to_px(), stroke_color_index as u32);
+        }
+        if let Some((matrix, translate)) = self.transform {
+            text_mesh_builder.with_transform(matrix, translate);
+        }
+        if let Some((offset, _)) = self.shadow {
+            let units_to_px = self.units_to_px();
+            // Last in the palette, which isn't built here as it would shape the text again
+            let color_index = (self.glyph_colors.len() + layer_colors.len() + 1 + self.stroke.is_some() as usize) as u32;
+            text_mesh_builder.with_shadow((offset.0 / units_to_px, offset.1 / units_to_px), color_index);
+        }
+        text_mesh_builder.with_advance_scale(self.advance_scale);
+        text_mesh_builder.with_merged_outline(self.merged_outline);
+        text_mesh_builder.with_baseline_snapping(self.baseline_snapping);
+        text_mesh_builder.with_pixel_scale(pixel_scale);
+        text_mesh_builder.with_viewport(viewport.0, viewport.1);
+        if let Some((advance_color_index, ink_color_index)) = glyph_boxes {
+            text_mesh_builder.with_glyph_boxes(advance_color_index, ink_color_index);
+        }
+        for (position, thickness) in self.decorations() {
+            text_mesh_builder.with_decoration(position, thickness);
+        }
+        text_mesh_builder.build(self.face(), color_index)
+    }
+
+    /// Bounds of everything the span draws: the ink bounds of its glyphs and its decorations.
+    ///
+    /// Returns `None` if nothing would be drawn, e.g. for whitespace-only text.
+    pub fn effective_bounds(&self) -> Option<Rect> {
+        if let Some(span) = self.fitted() {
+            return span.effective_bounds();
+        }
+        let (lines, origin) = self.layout();
+        let units_to_px = self.units_to_px();
+        let to_pixels = |x_min: f32, y_min: f32, x_max: f32, y_max: f32| Rect {
+            x_min: origin.0 as f32 + x_min * units_to_px,
+            y_min: origin.1 as f32 + y_min * units_to_px,
+            x_max: origin.0 as f32 + x_max * units_to_px,
+            y_max: origin.1 as f32 + y_max * units_to_px,
+        };
+
+        // Mitered stroke corners reach out up to the full stroke width
+        let stroke_extent = self.stroke.map_or(0.0, |(width, _)| width / units_to_px);
+
+        let mut bounds: Option<Rect> = None;
+        for line in &lines {
+            let mut cursor = line.offset;
+            for data in &line.glyph_data {
+                if let Some(ink) = self.face().glyph_bounding_box(ttf_parser::GlyphId(data.glyph_id as u16)) {
+                    let ink = to_pixels(
+                        cursor.0 + ink.x_min as f32 - stroke_extent,
+                        cursor.1 + ink.y_min as f32 - stroke_extent,
+                        cursor.0 + ink.x_max as f32 + stroke_extent,
+                        cursor.1 + ink.y_max as f32 + stroke_extent,
+                    );
+                    bounds = Some(bounds.map_or(ink, |bounds| bounds.union(ink)));
+                }
+                cursor.0 += self.x_advance(data);
+                cursor.1 += data.y_advance as f32;
+            }
+            for (position, thickness) in self.decorations() {
+                let top = line.offset.1 + position;
+                let decoration = to_pixels(line.offset.0, top - thickness, cursor.0, top);
+                bounds = Some(bounds.map_or(decoration, |bounds| bounds.union(decoration)));
+            }
+        }
+        if let (Some((offset, _)), Some(text)) = (self.shadow, bounds) {
+            let shadow = Rect {
+                x_min: text.x_min + offset.0,
+                y_min: text.y_min + offset.1,
+                x_max: text.x_max + offset.0,
+                y_max: text.y_max + offset.1,
+            };
+            bounds = Some(text.union(shadow));
+        }
+        // Bounds of the transformed corners
+        if let (Some((m, translate)), Some(text)) = (self.transform, bounds) {
+            let corners = [(text.x_min, text.y_min), (text.x_max, text.y_min), (text.x_min, text.y_max), (text.x_max, text.y_max)]
+                .map(|(x, y)| {
+                    let (x, y) = (x - origin.0 as f32, y - origin.1 as f32);
+                    (
+                        origin.0 as f32 + m[0][0] * x + m[0][1] * y + translate.0,
+                        origin.1 as f32 + m[1][0] * x + m[1][1] * y + translate.1,
+                    )
+                });
+            bounds = Some(corners.iter().fold(
+                Rect { x_min: f32::MAX, y_min: f32::MAX, x_max: f32::MIN, y_max: f32::MIN },
+                |rect, (x, y)| Rect { x_min: rect.x_min.min(*x), y_min: rect.y_min.min(*y), x_max: rect.x_max.max(*x), y_max: rect.y_max.max(*y) },
+            ));
+        }
+        bounds
+    }
+
+    /// Width and height in pixels the span's text takes up, without building any meshes: the widest
+    /// line's advance width by the ascent-to-descent height of the first line plus one line height per
+    /// following line and the paragraph spacing. Accounts for shrink-to-fit, wrapping and line breaks.
+    pub fn measure(&self) -> (f32, f32) {
+        if let Some(span) = self.fitted() {
+            return span.measure();
+        }
+        let units_to_px = self.units_to_px();
+        let lines = self.shape_lines();
+        let width = lines.iter().map(|line| self.advance_width(&line.glyph_data)).fold(0.0, f32::max);
+        let first_line = self.face().ascender() as f32 - self.face().descender() as f32;
+        let height = first_line + (lines.len() - 1) as f32 * self.line_height();
+        (width * units_to_px, height * units_to_px + self.paragraph_spacing_total(&lines))
+    }
+
+    /// Size in whole pixels of the span's line boxes, see [`Span::measure`].
+    ///
+    /// Unlike ink bounds the height doesn't depend on which glyphs are used, so "acemnr" and "bdfhkl"
+    /// produce the same height. Use it to size the output texture for consistent stacked labels.
+    pub fn line_box_size(&self) -> (u32, u32) {
+        let (width, height) = self.measure();
+        (width.ceil() as u32, height.ceil() as u32)
+    }
+
+    /// Splits the span into sub-spans of at most `max_glyphs` shaped glyphs each, and at every line
+    /// break.
+    ///
+    /// Splits only happen on cluster boundaries, so ligatures and combining sequences stay intact.
+    /// Chunks are cut in logical order, so each one covers a contiguous range of the text, and are
+    /// placed at the leftmost pen position of their glyphs. That is exact for chunks of a single
+    /// direction, mixed direction chunks are reordered on their own.
+    ///
+    /// The returned spans are already aligned, positioned and sized, so they render in the same place
+    /// and at the same fitted font size as the original span, with glyph colors moved along with
+    /// their characters. Shaping does not carry over chunk boundaries, so kerning between the last
+    /// glyph of one chunk and the first of the next is lost.
+    pub fn split_by_glyph_budget(&self, max_glyphs: usize) -> Vec<Span<'s>> {
+        if let Some(span) = self.fitted() {
+            return span.split_by_glyph_budget(max_glyphs);
+        }
+        let (lines, origin) = self.layout();
+        let units_to_px = self.units_to_px();
+
+        let mut spans = vec![];
+        for line in &lines {
+            let glyph_data = &line.glyph_data;
+            // Pen position of every glyph in visual order, in font units
+            let pens = glyph_data.iter()
+                .scan(line.offset.0, |pen, data| {
+                    let start = *pen;
+                    *pen += self.x_advance(data);
+                    Some(start)
+                })
+                .collect::<Vec<_>>();
+            let mut logical = (0..glyph_data.len()).collect::<Vec<_>>();
+            logical.sort_by_key(|index| glyph_data[*index].cluster);
+            let cluster = |position: usize| glyph_data[logical[position]].cluster as usize;
+
+            // Positions in `logical` every chunk starts at
+            let mut chunk_starts = vec![];
+            let mut chunk_start = 0usize;
+            let mut last_boundary = 0usize; // last position a chunk may end before
+            for position in 1..=logical.len() {
+                if position == logical.len() || cluster(position) != cluster(position - 1) {
+                    if position - chunk_start > max_glyphs.max(1) && last_boundary > chunk_start {
+                        chunk_starts.push(chunk_start);
+                        chunk_start = last_boundary;
+                    }
+                    last_boundary = position;
+                }
+            }
+            if chunk_start < logical.len() {
+                chunk_starts.push(chunk_start);
+            }
+
+            for (chunk, &start) in chunk_starts.iter().enumerate() {
+                let end = chunk_starts.get(chunk + 1).copied().unwrap_or(logical.len());
+                let from = cluster(start);
+                let mut to = if end < logical.len() { cluster(end) } else { line.text_range.end };
+                // Whitespace dropped at a wrap has no glyphs, it would take up space when shaped again
+                while let Some(c) = self.text[from..to].chars().next_back() {
+                    let char_start = to - c.len_utf8();
+                    if !c.is_whitespace() || glyph_data.iter().any(|data| data.cluster as usize == char_start) {
+                        break;
+                    }
+                    to = char_start;
+                }
+                let pen = logical[start..end].iter().map(|index| pens[*index]).fold(f32::INFINITY, f32::min);
+                let mut span = self.clone();
+                span.text = &self.text[from..to];
+                span.position = origin;
+                span.start_pen = (pen * units_to_px, line.offset.1 * units_to_px);
+                span.size = None;
+                span.paragraph_spacing = (0.0, 0.0);
+                span.h_align = Alignment::Start;
+                span.v_align = Alignment::Start;
+                // Keeps the paragraph direction of the line, even if the chunk starts differently
+                span.direction = Some(if line.rtl { harfbuzz::Direction::RTL } else { harfbuzz::Direction::LTR });
+                let char_offset = self.text[..from].chars().count();
+                span.glyph_colors = self.glyph_colors.get(char_offset..).map_or(vec![], <[_]>::to_vec);
+                spans.push(span);
+            }
+        }
+        spans
+    }
+
+    /// The span's face, with its variations applied
+    fn face(&self) -> &ttf_parser::Face<'s> {
+        self.instance.as_ref().unwrap_or(self.font_face)
+    }
+
+    fn dpi(&self) -> f32 {
+        self.dpi.unwrap_or(FontSize::DEFAULT_DPI)
+    }
+
+    /// Factor converting font units into pixels at the span's font size and DPI.
+    fn units_to_px(&self) -> f32 {
+        self.font_size.units_to_px(self.face(), self.dpi())
+    }
+
+    /// Sum of the horizontal advances in font units.
+    fn advance_width(&self, glyph_data: &[GlyphData]) -> f32 {
+        glyph_data.iter().map(|data| self.x_advance(data)).sum()
+    }
+
+    /// Letter spacing in unscaled font units, so it is exact after advance scaling.
+    fn letter_spacing_units(&self) -> i32 {
+        let (px, em) = self.letter_spacing;
+        let units = px / self.units_to_px() + em * self.face().units_per_em() as f32;
+        (units / self.advance_scale).round() as i32
+    }
+
+    /// Horizontal advance of a glyph in font units, after advance scaling.
+    fn x_advance(&self, data: &GlyphData) -> f32 {
+        data.x_advance as f32 * self.advance_scale
+    }
+
+    /// Offsets the span position according to its alignment inside `size`, given the text width in
+    /// pixels and the distance in pixels from the first to the last baseline of the block.
+    /// Horizontal alignment is mirrored for right-to-left text, so `Start` is the right edge.
+    fn aligned_position(&self, width: f32, block_extent: f32, rtl: bool) -> (i32, i32) {
         let mut text_position: (i32, i32) = self.position;
         if let Some(size) = self.size {
-            match self.h_align {
-                Alignment::Start => {}
+            let h_align = match (self.h_align, rtl) {
+                (Alignment::Start | Alignment::Baseline, true) => Alignment::End,
+                (Alignment::End, true) => Alignment::Start,
+                (h_align, _) => h_align,
+            };
+            match h_align {
+                Alignment::Start | Alignment::Baseline => {}
                 Alignment::Middle => {
                     text_position.0 += size.0 as i32 / 2;
                     text_position.0 -= width as i32 / 2;
@@ -113,50 +1275,306 @@ impl<'s> Span<'s> {
                     text_position.0 -= width as i32;
                 }
             }
+            // Lines stack downwards from the first baseline
             match self.v_align {
-                Alignment::Start => {}
+                Alignment::Start => {
+                    text_position.1 += block_extent as i32;
+                }
                 Alignment::Middle => {
                     text_position.1 += size.1 as i32 / 2;
-                    text_position.1 -= <FontSize as Into<i32>>::into(self.font_size) / 2;
+                    text_position.1 -= self.font_size.to_px(self.dpi()).round() as i32 / 2;
+                    text_position.1 += block_extent as i32 / 2;
                 }
                 Alignment::End => {
                     text_position.1 += size.1 as i32;
-                    text_position.1 -= <FontSize as Into<i32>>::into(self.font_size);
+                    text_position.1 -= self.font_size.to_px(self.dpi()).round() as i32;
                 }
+                // The span position already is the first baseline
+                Alignment::Baseline => {}
             }
         }
-        text_mesh_builder.with_position(text_position.0, text_position.1);
-        text_mesh_builder.with_font_size(self.font_size);
-        text_mesh_builder.build(self.font_face, color_index)
-    }
-
-    fn shape_glyph_data(&self) -> Vec<GlyphData> {
-        let mut glyph_data: Vec<GlyphData> = Vec::new();
-        {
-            let mut hb_buffer = harfbuzz::Buffer::with(self.text);
-            hb_buffer.guess_segment_properties();
-            let hb_buffer = hb_buffer.into_raw();
-            let hb_blob = harfbuzz::Blob::new_read_only(&self.font_face.raw_face().data);
-            let hb_face = unsafe { harfbuzz::sys::hb_face_create(hb_blob.as_raw(), 0) };
-            let hb_font = unsafe { harfbuzz::sys::hb_font_create(hb_face) };
-            unsafe { harfbuzz::sys::hb_shape(hb_font, hb_buffer, std::ptr::null(), 0) };
-            let mut hb_glyph_count: u32 = 0;
-            let hb_glyph_infos = unsafe { harfbuzz::sys::hb_buffer_get_glyph_infos(hb_buffer, hb_glyph_count.borrow_mut() as *mut u32) };
-            let hb_glyph_positions = unsafe { harfbuzz::sys::hb_buffer_get_glyph_positions(hb_buffer, hb_glyph_count.borrow_mut() as *mut u32) };
-            for index in 0..(hb_glyph_count as usize) {
-                unsafe {
-                    let hb_glyph_info = hb_glyph_infos.add(index);
-                    let hb_glyph_position = hb_glyph_positions.add(index);
-                    glyph_data.push(GlyphData {
-                        glyph_id: (*hb_glyph_info).codepoint as u32,
-                        x_advance: (*hb_glyph_position).x_advance as i32,
-                        y_advance: (*hb_glyph_position).y_advance as i32,
-                        x_offset: (*hb_glyph_position).x_offset as i32,
-                        y_offset: (*hb_glyph_position).y_offset as i32,
-                    })
-                }
-            }
-        }
-        glyph_data
-    }
-}
\ No newline at end of file
+        text_position
+    }
+
+    /// Distance between consecutive baselines in font units, rounded to whole pixels with
+    /// baseline snapping so every line lands on the pixel grid.
+    fn line_height(&self) -> f32 {
+        let face = self.face();
+        let line_height = match self.line_height {
+            LineHeight::Normal => face.ascender() as f32 - face.descender() as f32 + face.line_gap() as f32,
+            LineHeight::Factor(factor) => factor * face.units_per_em() as f32,
+            LineHeight::Px(px) => px / self.units_to_px(),
+        };
+        if self.baseline_snapping {
+            let units_to_px = self.units_to_px();
+            (line_height * units_to_px).round() / units_to_px
+        } else {
+            line_height
+        }
+    }
+
+    /// Space in pixels before and after every paragraph, rounded to whole pixels with baseline snapping
+    fn paragraph_spacing(&self) -> (f32, f32) {
+        let (before, after) = self.paragraph_spacing;
+        if self.baseline_snapping {
+            (before.round(), after.round())
+        } else {
+            (before, after)
+        }
+    }
+
+    /// Paragraph spacing in pixels of all paragraphs of `lines` together
+    fn paragraph_spacing_total(&self, lines: &[Line]) -> f32 {
+        let (before, after) = self.paragraph_spacing();
+        let paragraphs = lines.last().map_or(0, |line| line.paragraph + 1);
+        paragraphs as f32 * (before + after)
+    }
+
+    /// Shapes and aligns every line, returning them together with the aligned span position in pixels.
+    /// Each line is aligned horizontally on its own, the block as a whole vertically.
+    fn layout(&self) -> (Vec<Line>, (i32, i32)) {
+        self.layout_cached(&mut ShapeCache::new())
+    }
+
+    /// Like [`Span::layout`], shaping with the shapers and runs in `shape_cache`
+    fn layout_cached(&self, shape_cache: &mut ShapeCache<'s>) -> (Vec<Line>, (i32, i32)) {
+        let units_to_px = self.units_to_px();
+        let line_height = self.line_height();
+        let mut lines = self.shape_lines_cached(shape_cache);
+        let block_extent = (lines.len() - 1) as f32 * line_height * units_to_px + self.paragraph_spacing_total(&lines);
+        let line_position = |line: &Line| {
+            self.aligned_position(self.advance_width(&line.glyph_data) * units_to_px, block_extent, line.rtl)
+        };
+        let origin = line_position(&lines[0]);
+        let (before, after) = self.paragraph_spacing();
+        for (index, line) in lines.iter_mut().enumerate() {
+            // Every paragraph up to the line's own adds its space before, the previous ones their space after
+            let spacing = (before * (line.paragraph + 1) as f32 + after * line.paragraph as f32) / units_to_px;
+            line.offset = if index == 0 {
+                (self.start_pen.0 / units_to_px, self.start_pen.1 / units_to_px - spacing)
+            } else {
+                let x = (line_position(line).0 - origin.0) as f32 / units_to_px;
+                (x, self.start_pen.1 / units_to_px - index as f32 * line_height - spacing)
+            };
+        }
+        (lines, origin)
+    }
+
+    /// Applies the control character policy, returning the text to shape and the byte offset in the
+    /// original text for every byte of it, so clusters can be mapped back.
+    fn sanitized_text(&self) -> (String, Vec<u32>) {
+        let mut end = self.text.len().min(self.max_input_len);
+        while !self.text.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end < self.text.len() {
+            warn!("truncating span text from {} to {} bytes", self.text.len(), end);
+        }
+        let mut text = String::with_capacity(end);
+        let mut offsets = Vec::with_capacity(end);
+        for (index, c) in self.text[..end].char_indices() {
+            let c = match self.control_char_policy {
+                ControlCharPolicy::Passthrough => c,
+                _ if !c.is_control() || c == '\n' || c == '\t' => c,
+                ControlCharPolicy::Strip => continue,
+                ControlCharPolicy::Replace => char::REPLACEMENT_CHARACTER,
+            };
+            text.push(c);
+            offsets.extend(std::iter::repeat_n(index as u32, c.len_utf8()));
+        }
+        (text, offsets)
+    }
+
+    /// Breaks a shaped line into lines no wider than `max_width` font units, after the last whitespace
+    /// that fits or between glyphs if a word alone is too wide. Whitespace at the breaks is dropped.
+    fn wrap_line(&self, line: Line, max_width: f32) -> Vec<Line> {
+        let glyph_data = &line.glyph_data;
+        let is_space = |index: usize| {
+            self.text[glyph_data[index].cluster as usize..].chars().next().is_some_and(char::is_whitespace)
+        };
+        let cluster_byte = |index: usize| {
+            glyph_data.get(index).map_or(line.text_range.end, |data| data.cluster as usize)
+        };
+
+        let mut lines = vec![];
+        let mut start = 0; // glyph index the current line starts at
+        let mut width = 0.0; // font units from `start` up to `index`
+        let mut last_space = None;
+        let mut index = 0;
+        while index < glyph_data.len() {
+            let advance = self.x_advance(&glyph_data[index]);
+            // Whitespace may hang over the edge, it is dropped if the line breaks there
+            if is_space(index) {
+                last_space = Some(index);
+            } else if width + advance > max_width && index > start {
+                let (mut end, next) = match last_space {
+                    Some(space) => (space, space + 1),
+                    None => (index, index),
+                };
+                while end > start && is_space(end - 1) {
+                    end -= 1;
+                }
+                let mut line_glyphs = glyph_data[start..end].to_vec();
+                remove_trailing_spacing(&mut line_glyphs, &glyph_data[end], self.letter_spacing_units());
+                lines.push(Line {
+                    glyph_data: line_glyphs,
+                    text_range: if lines.is_empty() { line.text_range.start } else { cluster_byte(start) }..cluster_byte(next),
+                    offset: (0.0, 0.0),
+                    rtl: line.rtl,
+                    levels: line.levels[start..end].to_vec(),
+                    paragraph: line.paragraph,
+                });
+                start = next;
+                while start < glyph_data.len() && is_space(start) {
+                    start += 1;
+                }
+                width = glyph_data[start..index.max(start)].iter().map(|data| self.x_advance(data)).sum();
+                last_space = None;
+                index = index.max(start);
+                continue;
+            }
+            width += advance;
+            index += 1;
+        }
+        lines.push(Line {
+            glyph_data: glyph_data[start.min(glyph_data.len())..].to_vec(),
+            text_range: if lines.is_empty() { line.text_range.start } else { cluster_byte(start) }..line.text_range.end,
+            offset: (0.0, 0.0),
+            rtl: line.rtl,
+            levels: line.levels[start.min(glyph_data.len())..].to_vec(),
+            paragraph: line.paragraph,
+        });
+        lines
+    }
+
+    /// Shapes every line of the text on its own, lines are split at `\n` which isn't shaped itself.
+    /// Lines are split further into runs of one direction with the Unicode bidi algorithm, each
+    /// shaped on its own. The returned lines aren't positioned yet, their glyphs are in visual order.
+    fn shape_lines(&self) -> Vec<Line> {
+        self.shape_lines_cached(&mut ShapeCache::new())
+    }
+
+    /// Like [`Span::shape_lines`], shaping with the shapers and runs in `shape_cache`
+    fn shape_lines_cached(&self, shape_cache: &mut ShapeCache<'s>) -> Vec<Line> {
+        let (text, offsets) = self.sanitized_text();
+        // Variation instances are owned by the span, so their address is no cache key
+        let instance_shaper = self.instance.as_ref().map(Shaper::new);
+        let options = ShapingOptions {
+            features: &self.features,
+            direction: self.direction,
+            script: self.script,
+            language: self.language.as_deref(),
+            cluster_level: self.cluster_level,
+            tab_width: self.tab_width.map(|spaces| {
+                let space_advance = self.face().glyph_index(' ')
+                    .and_then(|glyph_id| self.face().glyph_hor_advance(glyph_id))
+                    .unwrap_or(self.face().units_per_em() / 4);
+                (spaces * space_advance as f32).round() as i32
+            }),
+        };
+        // Byte offset in the original text of a byte in the sanitized text
+        let original = |index: usize| offsets.get(index).map_or(self.text.len(), |offset| *offset as usize);
+
+        let letter_spacing = self.letter_spacing_units();
+
+        let mut lines = vec![];
+        let mut line_start = 0;
+        for (paragraph, line_text) in text.split('\n').enumerate() {
+            let default_level = match self.direction {
+                Some(harfbuzz::Direction::RTL) => Some(Level::rtl()),
+                Some(harfbuzz::Direction::LTR) => Some(Level::ltr()),
+                _ => None,
+            };
+            let bidi_info = BidiInfo::new(line_text, default_level);
+            let rtl = bidi_info.paragraphs.first().is_some_and(|paragraph| paragraph.level.is_rtl());
+            // Wrapping and truncation work in logical order, so runs are concatenated logically
+            let mut glyph_data = vec![];
+            let mut levels = vec![];
+            let mut run_start = 0;
+            while run_start < line_text.len() {
+                let level = bidi_info.levels[run_start];
+                let run_end = (run_start..line_text.len())
+                    .find(|index| line_text.is_char_boundary(*index) && bidi_info.levels[*index] != level)
+                    .unwrap_or(line_text.len());
+                let run_options = ShapingOptions {
+                    direction: Some(if level.is_rtl() { harfbuzz::Direction::RTL } else { harfbuzz::Direction::LTR }),
+                    ..options.clone()
+                };
+                let run_text = &line_text[run_start..run_end];
+                let (mut run_glyphs, run_rtl) = match &instance_shaper {
+                    Some(shaper) => shaper.shape_directed(run_text, &run_options),
+                    None => shape_cache.shape_directed(self.font_face, self.shaper, run_text, &run_options),
+                };
+                if run_rtl {
+                    run_glyphs.reverse();
+                }
+                for data in &mut run_glyphs {
+                    data.cluster += run_start as u32;
+                }
+                levels.extend(std::iter::repeat_n(level, run_glyphs.len()));
+                glyph_data.extend(run_glyphs);
+                run_start = run_end;
+            }
+            // Map clusters back into the unsanitized text
+            for data in &mut glyph_data {
+                data.cluster = original(line_start + data.cluster as usize) as u32;
+                if let (0, Some(fallback)) = (data.glyph_id, self.fallback_glyph) {
+                    data.glyph_id = fallback.0 as u32;
+                    data.x_advance = self.face().glyph_hor_advance(fallback).unwrap_or(0) as i32;
+                }
+            }
+            // Spacing follows the last glyph of every cluster but the line's last one, lines broken by
+            // wrapping or truncation take it off their new last glyph
+            if letter_spacing != 0 {
+                for index in 0..glyph_data.len().saturating_sub(1) {
+                    if glyph_data[index + 1].cluster != glyph_data[index].cluster {
+                        glyph_data[index].x_advance += letter_spacing;
+                    }
+                }
+            }
+            let line = Line {
+                glyph_data,
+                text_range: original(line_start)..original(line_start + line_text.len()),
+                offset: (0.0, 0.0),
+                rtl,
+                levels,
+                paragraph,
+            };
+            match self.size {
+                Some((width, _)) if self.wrap => {
+                    let max_width = width as f32 / self.units_to_px();
+                    lines.extend(self.wrap_line(line, max_width));
+                }
+                _ => lines.push(line),
+            }
+            line_start += line_text.len() + 1;
+        }
+
+        let glyph_count = lines.iter().map(|line| line.glyph_data.len()).sum::<usize>();
+        if glyph_count > self.max_glyphs {
+            warn!("truncating span from {} to {} glyphs", glyph_count, self.max_glyphs);
+            let mut budget = self.max_glyphs;
+            for line in &mut lines {
+                if budget < line.glyph_data.len() {
+                    let next = line.glyph_data[budget];
+                    line.glyph_data.truncate(budget);
+                    line.levels.truncate(budget);
+                    remove_trailing_spacing(&mut line.glyph_data, &next, letter_spacing);
+                }
+                budget -= line.glyph_data.len();
+            }
+        }
+        for line in &mut lines {
+            let visual_order = BidiInfo::reorder_visual(&line.levels);
+            line.glyph_data = visual_order.into_iter().map(|index| line.glyph_data[index]).collect();
+        }
+        lines
+    }
+}
+/// Takes letter spacing off the last glyph of a line that was cut before `next`, if it got spacing
+/// because `next` starts another cluster
+fn remove_trailing_spacing(glyph_data: &mut [GlyphData], next: &GlyphData, letter_spacing: i32) {
+    if let Some(last) = glyph_data.last_mut().filter(|last| last.cluster != next.cluster) {
+        last.x_advance -= letter_spacing;
+    }
+}
@@ -0,0 +1,158 @@
+// Each test crate only uses some of the helpers
+#![allow(dead_code)]
+
+use image::RgbaImage;
+use textrenderingstuff::{AAMode, Font, RendererError, TextureRenderer};
+
+/// Static TrueType face with 1000 units per em. 'H' has a flat top at 733 units, 'o' a hole.
+pub const FONT_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fonts/NotoSansJP-Regular.ttf");
+/// Variable version of the same face with a `wght` axis
+pub const VARIABLE_FONT_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fonts/NotoSansJP-VariableFont_wght.ttf");
+
+pub fn font() -> Font {
+    Font::from_bytes(std::fs::read(FONT_PATH).unwrap()).unwrap()
+}
+
+pub fn variable_font() -> Font {
+    Font::from_bytes(std::fs::read(VARIABLE_FONT_PATH).unwrap()).unwrap()
+}
+
+/// Renderer for GPU readback tests, `None` on machines without any graphics adapter, where the test
+/// should return early.
+pub fn renderer<'r>(width: u32, height: u32, mode: AAMode) -> Option<TextureRenderer<'r>> {
+    match TextureRenderer::new(width, height, mode) {
+        Ok(renderer) => Some(renderer),
+        Err(error @ (RendererError::NoAdapter | RendererError::NoSuitableAdapter(_))) => {
+            eprintln!("skipping GPU test: {error}");
+            None
+        }
+        Err(error) => panic!("failed to create renderer: {error}"),
+    }
+}
+
+/// Transparent image for CPU rendering with `draw_text_into`
+pub fn blank(width: u32, height: u32) -> RgbaImage {
+    RgbaImage::new(width, height)
+}
+
+/// First and last image row (top-down) with any pixel differing from `background`
+pub fn ink_rows(image: &RgbaImage, background: [u8; 4]) -> Option<(u32, u32)> {
+    let rows = (0..image.height())
+        .filter(|y| (0..image.width()).any(|x| image.get_pixel(x, *y).0 != background))
+        .collect::<Vec<_>>();
+    Some((*rows.first()?, *rows.last()?))
+}
+
+/// First and last image column with any pixel differing from `background`
+pub fn ink_columns(image: &RgbaImage, background: [u8; 4]) -> Option<(u32, u32)> {
+    let columns = (0..image.width())
+        .filter(|x| (0..image.height()).any(|y| image.get_pixel(*x, y).0 != background))
+        .collect::<Vec<_>>();
+    Some((*columns.first()?, *columns.last()?))
+}
+
+/// Asserts `actual` is within `tolerance` of `expected`
+#[track_caller]
+pub fn assert_near(actual: f32, expected: f32, tolerance: f32) {
+    assert!((actual - expected).abs() <= tolerance, "expected {expected} ± {tolerance}, got {actual}");
+}
+
+/// Copy of the font file `data` with `tables` added, replacing tables with the same tag
+pub fn with_tables(data: &[u8], tables: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+    let read_u32 = |offset: usize| u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+    let table_count = u16::from_be_bytes([data[4], data[5]]) as usize;
+    let mut all_tables = (0..table_count)
+        .map(|index| 12 + 16 * index)
+        .map(|record| (data[record..record + 4].try_into().unwrap(), data[read_u32(record + 8)..][..read_u32(record + 12)].to_vec()))
+        .filter(|(tag, _): &([u8; 4], Vec<u8>)| tables.iter().all(|(new_tag, _)| *new_tag != tag))
+        .chain(tables.iter().map(|(tag, table)| (**tag, table.clone())))
+        .collect::<Vec<_>>();
+    all_tables.sort_by_key(|(tag, _)| *tag);
+    // Search hints aren't needed by the parsers used here
+    let mut font = data[..4].to_vec();
+    font.extend_from_slice(&(all_tables.len() as u16).to_be_bytes());
+    font.extend_from_slice(&[0; 6]);
+    let mut offset = 12 + 16 * all_tables.len();
+    for (tag, table) in &all_tables {
+        font.extend_from_slice(tag);
+        font.extend_from_slice(&[0; 4]);
+        font.extend_from_slice(&(offset as u32).to_be_bytes());
+        font.extend_from_slice(&(table.len() as u32).to_be_bytes());
+        offset += table.len().next_multiple_of(4);
+    }
+    for (_, table) in &all_tables {
+        font.extend_from_slice(table);
+        font.resize(font.len().next_multiple_of(4), 0);
+    }
+    font
+}
+
+/// COLR version 1 table with a version 0 'o' in palette entry 2 and an 'H' painted by `root`.
+/// `layers` are the paints of the layer list.
+pub fn colr_v1(root: &[u8], layers: &[Vec<u8>]) -> Vec<u8> {
+    let o = 80u16;
+    let layer_list = 54 + root.len() as u32;
+    let mut colr = vec![];
+    for value in [1u16, 1] {
+        colr.extend_from_slice(&value.to_be_bytes());
+    }
+    colr.extend_from_slice(&34u32.to_be_bytes());
+    colr.extend_from_slice(&40u32.to_be_bytes());
+    colr.extend_from_slice(&1u16.to_be_bytes());
+    for offset in [44, layer_list, 0, 0, 0] {
+        colr.extend_from_slice(&offset.to_be_bytes());
+    }
+    for value in [o, 0, 1, o, 2] {
+        colr.extend_from_slice(&value.to_be_bytes());
+    }
+    colr.extend_from_slice(&1u32.to_be_bytes());
+    colr.extend_from_slice(&41u16.to_be_bytes());
+    colr.extend_from_slice(&10u32.to_be_bytes());
+    colr.extend_from_slice(root);
+    colr.extend_from_slice(&(layers.len() as u32).to_be_bytes());
+    let mut offset = 4 + 4 * layers.len() as u32;
+    for layer in layers {
+        colr.extend_from_slice(&offset.to_be_bytes());
+        offset += layer.len() as u32;
+    }
+    layers.iter().for_each(|layer| colr.extend_from_slice(layer));
+    colr
+}
+
+/// Paint with `format`, its child paint at `offset24` after `fields` and then `child`
+pub fn paint(format: u8, fields: &[i16], child: &[u8]) -> Vec<u8> {
+    let mut paint = vec![format];
+    paint.extend_from_slice(&(4 + 2 * fields.len() as u32).to_be_bytes()[1..]);
+    fields.iter().for_each(|field| paint.extend_from_slice(&field.to_be_bytes()));
+    paint.extend_from_slice(child);
+    paint
+}
+
+/// Color line from red at offset 0 to blue at offset 1
+pub fn red_to_blue() -> Vec<u8> {
+    let mut line = vec![0];
+    for value in [2u16, 0, 0, 0x4000, 0x4000, 1, 0x4000] {
+        line.extend_from_slice(&value.to_be_bytes());
+    }
+    line
+}
+
+/// The regular font with `colr` and a palette of red, blue and green
+pub fn colr_v1_font(colr: Vec<u8>) -> Font {
+    let mut cpal = vec![];
+    for value in [0u16, 3, 1, 3] {
+        cpal.extend_from_slice(&value.to_be_bytes());
+    }
+    cpal.extend_from_slice(&14u32.to_be_bytes());
+    cpal.extend_from_slice(&0u16.to_be_bytes());
+    // Blue, green, red and alpha
+    cpal.extend_from_slice(&[0, 0, 255, 255, 255, 0, 0, 255, 0, 255, 0, 255]);
+    let data = with_tables(&std::fs::read(FONT_PATH).unwrap(), &[(b"COLR", colr), (b"CPAL", cpal)]);
+    Font::from_bytes(data).unwrap()
+}
+
+/// 'H' filled with a red to blue radial gradient of radius 300 units around the crossbar center
+pub fn radial_font() -> Font {
+    let radial = paint(6, &[0, 0, 0, 0, 0, 300], &red_to_blue());
+    colr_v1_font(colr_v1(&paint(10, &[41], &paint(14, &[364, 386], &radial)), &[]))
+}
@@ -0,0 +1,130 @@
+mod common;
+
+use common::{FONT_PATH, VARIABLE_FONT_PATH};
+use textrenderingstuff::font::{count_faces, face_index};
+use textrenderingstuff::mesh::GlyphCache;
+use textrenderingstuff::shaper::{Shaper, ShapingOptions};
+use textrenderingstuff::Font;
+
+/// Packs `fonts` into a TrueType collection, moving the table offsets of every face to its place
+/// in the file
+fn collection(fonts: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = b"ttcf".to_vec();
+    data.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+    data.extend_from_slice(&(fonts.len() as u32).to_be_bytes());
+    let header_len = data.len() + 4 * fonts.len();
+    data.resize(header_len, 0);
+    for (index, font) in fonts.iter().enumerate() {
+        data.resize(data.len().next_multiple_of(4), 0);
+        let base = data.len();
+        data[12 + 4 * index..16 + 4 * index].copy_from_slice(&(base as u32).to_be_bytes());
+        data.extend_from_slice(font);
+        let table_count = u16::from_be_bytes([font[4], font[5]]) as usize;
+        for table in 0..table_count {
+            let offset = base + 12 + 16 * table + 8;
+            let table_offset = u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap());
+            data[offset..offset + 4].copy_from_slice(&(table_offset + base as u32).to_be_bytes());
+        }
+    }
+    data
+}
+
+/// Glyph ids and advances of `text` shaped with `face`
+fn shaped(face: &ttf_parser::Face, text: &str) -> Vec<(u32, i32)> {
+    Shaper::new(face).shape(text, &ShapingOptions::default()).iter().map(|data| (data.glyph_id, data.x_advance)).collect()
+}
+
+#[test]
+fn faces_of_a_collection_are_outlined_and_shaped_by_index() {
+    let (regular, variable) = (std::fs::read(FONT_PATH).unwrap(), std::fs::read(VARIABLE_FONT_PATH).unwrap());
+    let data = collection(&[regular.clone(), variable.clone()]);
+    assert_eq!(count_faces(&data), 2);
+    let faces = [Font::from_bytes_with_index(data.clone(), 0).unwrap(), Font::from_bytes_with_index(data, 1).unwrap()];
+    let standalone = [Font::from_bytes(regular).unwrap(), Font::from_bytes(variable).unwrap()];
+    for (index, (face, standalone)) in faces.iter().zip(&standalone).enumerate() {
+        assert_eq!(face_index(face.face()), index as u32);
+        // HarfBuzz is pointed at the same face as the outlines
+        assert_eq!(shaped(face.face(), "Hello"), shaped(standalone.face(), "Hello"));
+    }
+    // The variable face defaults to the thin instance, its 'H' differs from the regular one
+    let mut cache = GlyphCache::new();
+    let outlines = faces.each_ref().map(|font| {
+        let glyph_id = font.face().glyph_index('H').unwrap();
+        cache.get_or_build(font.face(), glyph_id).unwrap().contours
+    });
+    assert_ne!(outlines[0], outlines[1]);
+    assert_ne!(shaped(faces[0].face(), "H"), shaped(faces[1].face(), "H"));
+}
+
+/// WOFF 1.0 file with the tables of the SFNT `font`, zlib compressed where that makes them smaller
+#[cfg(feature = "woff")]
+fn woff(font: &[u8]) -> Vec<u8> {
+    use std::io::Write;
+
+    let face = ttf_parser::RawFace::parse(font, 0).unwrap();
+    let tables = face.table_records.into_iter().map(|record| {
+        let table = &font[record.offset as usize..][..record.length as usize];
+        let mut encoder = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(table).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let stored = if compressed.len() < table.len() { compressed } else { table.to_vec() };
+        (record, table.len(), stored)
+    }).collect::<Vec<_>>();
+    let mut data = b"wOFF".to_vec();
+    data.extend_from_slice(&font[0..4]);
+    data.extend_from_slice(&[0; 4]);
+    data.extend_from_slice(&(tables.len() as u16).to_be_bytes());
+    data.resize(44, 0);
+    let mut offset = 44 + 20 * tables.len();
+    for (record, length, stored) in &tables {
+        data.extend_from_slice(&record.tag.to_bytes());
+        for value in [offset, stored.len(), *length, record.check_sum as usize] {
+            data.extend_from_slice(&(value as u32).to_be_bytes());
+        }
+        offset += stored.len().next_multiple_of(4);
+    }
+    for (_, _, stored) in &tables {
+        data.extend_from_slice(stored);
+        data.resize(data.len().next_multiple_of(4), 0);
+    }
+    let length = data.len() as u32;
+    data[8..12].copy_from_slice(&length.to_be_bytes());
+    data
+}
+
+#[cfg(feature = "woff")]
+#[test]
+fn woff_fonts_decompress_to_the_same_tables() {
+    let font = std::fs::read(FONT_PATH).unwrap();
+    let decoded = textrenderingstuff::font::load_font(&woff(&font)).unwrap();
+    let tables = |data: &[u8]| {
+        let face = ttf_parser::RawFace::parse(data, 0).unwrap();
+        face.table_records.into_iter()
+            .map(|record| (record.tag, data[record.offset as usize..][..record.length as usize].to_vec()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(tables(&decoded), tables(&font));
+    let (original, decoded) = (Font::from_bytes(font).unwrap(), Font::from_bytes(decoded).unwrap());
+    assert_eq!(shaped(decoded.face(), "Hello"), shaped(original.face(), "Hello"));
+}
+
+#[cfg(feature = "woff")]
+#[test]
+fn malformed_woff_fonts_are_errors() {
+    use textrenderingstuff::font::{load_font, FontLoadError};
+
+    let font = std::fs::read(FONT_PATH).unwrap();
+    let data = woff(&font);
+    // Cut off in the header and in the table directory
+    for length in [6, 30, 60] {
+        assert!(matches!(load_font(&data[..length]), Err(FontLoadError::Malformed)), "{length} bytes");
+    }
+    // The first table's original length is claimed to be 4 GiB
+    let mut oversized = data.clone();
+    oversized[44 + 12..44 + 16].copy_from_slice(&u32::MAX.to_be_bytes());
+    assert!(load_font(&oversized).is_err());
+    // Compressed data running past the end of the file
+    let mut overlong = data;
+    overlong[44 + 4..44 + 12].copy_from_slice(&[0xff; 8]);
+    assert!(matches!(load_font(&overlong), Err(FontLoadError::Malformed)));
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use common::{blank, font, variable_font};
+use textrenderingstuff::mesh::{GlyphCache, GlyphMeshBuilder};
+use textrenderingstuff::raster::draw_text_into;
+use textrenderingstuff::{FontSize, Span};
+use ttf_parser::OutlineBuilder;
+
+#[test]
+fn glyph_meshes_are_cached_per_face_and_glyph() {
+    let (font, other_font) = (font(), variable_font());
+    let h = font.face().glyph_index('H').unwrap();
+    let o = font.face().glyph_index('o').unwrap();
+    let mut cache = GlyphCache::new();
+    let first = cache.get_or_build(font.face(), h).unwrap();
+    let again = cache.get_or_build(font.face(), h).unwrap();
+    assert_eq!((cache.hits(), cache.misses()), (1, 1));
+    assert_eq!(first.indices, again.indices);
+    // Another glyph or the same glyph id in another face is tessellated on its own
+    cache.get_or_build(font.face(), o);
+    cache.get_or_build(other_font.face(), h);
+    assert_eq!((cache.hits(), cache.misses()), (1, 3));
+}
+
+/// Triangulates closed `contours` given as corner points, returning the triangles as point triples
+fn triangles(contours: &[Vec<(f32, f32)>]) -> Vec<[(f32, f32); 3]> {
+    let mut builder = GlyphMeshBuilder::new();
+    for contour in contours {
+        builder.move_to(contour[0].0, contour[0].1);
+        contour[1..].iter().for_each(|(x, y)| builder.line_to(*x, *y));
+        builder.close();
+    }
+    let (vertices, indices) = builder.triangulate();
+    let point = |index: u32| (vertices[index as usize].position[0], vertices[index as usize].position[1]);
+    indices.chunks_exact(3).map(|triangle| [point(triangle[0]), point(triangle[1]), point(triangle[2])]).collect()
+}
+
+fn covers(triangle: &[(f32, f32); 3], (x, y): (f32, f32)) -> bool {
+    let side = |a: (f32, f32), b: (f32, f32)| (b.0 - a.0) * (y - a.1) - (x - a.0) * (b.1 - a.1);
+    let sides = [side(triangle[0], triangle[1]), side(triangle[1], triangle[2]), side(triangle[2], triangle[0])];
+    sides.iter().all(|side| *side > 0.0) || sides.iter().all(|side| *side < 0.0)
+}
+
+#[test]
+fn holes_are_found_by_nesting_whatever_the_winding() {
+    // Counter-clockwise squares nested three deep, like CFF outlines with inconsistent directions
+    let square = |x: f32, y: f32, size: f32| vec![(x, y), (x + size, y), (x + size, y + size), (x, y + size)];
+    for reverse_inner in [false, true] {
+        let mut hole = square(10.0, 10.0, 80.0);
+        if reverse_inner {
+            hole.reverse();
+        }
+        let triangles = triangles(&[square(0.0, 0.0, 100.0), hole, square(40.0, 40.0, 20.0)]);
+        let area = triangles.iter()
+            .map(|[a, b, c]| ((b.0 - a.0) * (c.1 - a.1) - (c.0 - a.0) * (b.1 - a.1)).abs() / 2.0)
+            .sum::<f32>();
+        assert!((area - (10000.0 - 6400.0 + 400.0)).abs() < 1e-2, "{area}");
+        // The ring and the island are filled, the hole between them isn't
+        assert!(triangles.iter().any(|triangle| covers(triangle, (5.0, 50.0))));
+        assert!(!triangles.iter().any(|triangle| covers(triangle, (25.0, 50.0))));
+        assert!(triangles.iter().any(|triangle| covers(triangle, (45.0, 52.0))));
+    }
+}
+
+#[test]
+fn counters_of_real_glyphs_stay_empty() {
+    let font = font();
+    let span = Span::new(font.face(), "o", 0, 0).with_font_size(FontSize::Px(100));
+    let mut image = blank(80, 80);
+    draw_text_into(&mut image, &span, (10, 10));
+    // 'o' spans 52 to 554 units and -13 to 557 units, its counter is around the center
+    assert_eq!(image.get_pixel(10 + 30, 80 - 10 - 27)[3], 0);
+    assert_eq!(image.get_pixel(10 + 8, 80 - 10 - 27)[3], 255);
+}
+
+#[test]
+fn prepared_glyphs_match_single_builds() {
+    let font = font();
+    let glyph_ids = "Hello, world! 日本語".chars().filter_map(|c| font.face().glyph_index(c)).collect::<Vec<_>>();
+    let mut cache = GlyphCache::new();
+    // Tessellated in parallel with the `rayon` feature, each distinct glyph once
+    cache.prepare_with_tolerance(font.face(), &glyph_ids, 0.5);
+    let distinct = glyph_ids.iter().collect::<std::collections::HashSet<_>>().len() as u64;
+    assert_eq!(cache.misses(), distinct);
+    for glyph_id in &glyph_ids {
+        let prepared = cache.get_or_build_with_tolerance(font.face(), *glyph_id, 0.5);
+        let built = GlyphMeshBuilder::new().with_curve_tolerance(0.5).build(font.face(), *glyph_id);
+        assert_eq!(prepared.map(|mesh| mesh.indices), built.map(|mesh| mesh.indices));
+    }
+    assert_eq!((cache.hits(), cache.misses()), (glyph_ids.len() as u64 - distinct, distinct));
+}
@@ -0,0 +1,230 @@
+mod common;
+
+use common::{blank, colr_v1, colr_v1_font, font, ink_columns, ink_rows, paint, radial_font, red_to_blue, with_tables, FONT_PATH};
+use image::RgbaImage;
+use textrenderingstuff::raster::draw_text_into;
+use textrenderingstuff::{Font, FontSize, Span};
+
+const RED: [f32; 4] = [1.0, 0.0, 0.0, 1.0];
+const GREEN: [f32; 4] = [0.0, 1.0, 0.0, 1.0];
+const BLUE: [f32; 4] = [0.0, 0.0, 1.0, 1.0];
+
+fn count(image: &RgbaImage, pixel: [u8; 4]) -> usize {
+    image.pixels().filter(|p| p.0 == pixel).count()
+}
+
+#[test]
+fn strokes_and_shadows_use_their_palette_colors() {
+    let font = font();
+    let text = |color, stroke| Span::new(font.face(), "H", 0, 0)
+        .with_font_size(FontSize::Px(100))
+        .with_color(color)
+        .with_stroke(6.0, stroke);
+    let mut image = blank(160, 160);
+    draw_text_into(&mut image, &text(BLUE, RED).with_shadow((2.0, -2.0), GREEN, 0.0), (20, 40));
+    assert!(count(&image, [0, 0, 255, 255]) > 100, "fill");
+    assert!(count(&image, [255, 0, 0, 255]) > 100, "stroke");
+    assert!(count(&image, [0, 255, 0, 255]) > 20, "shadow");
+
+    // With the text itself transparent only the shadow is inked, down and to the right of the text
+    let mut plain = blank(160, 160);
+    draw_text_into(&mut plain, &text(BLUE, RED), (20, 40));
+    let mut shadow = blank(160, 160);
+    draw_text_into(&mut shadow, &text([0.0; 4], [0.0; 4]).with_shadow((2.0, -2.0), GREEN, 0.0), (20, 40));
+    let (left, right) = ink_columns(&plain, [0; 4]).unwrap();
+    let (top, bottom) = ink_rows(&plain, [0; 4]).unwrap();
+    assert_eq!(ink_columns(&shadow, [0; 4]), Some((left + 2, right + 2)));
+    assert_eq!(ink_rows(&shadow, [0; 4]), Some((top + 2, bottom + 2)));
+}
+
+#[test]
+fn glyph_colors_color_their_characters() {
+    let font = font();
+    let span = Span::new(font.face(), "HH", 0, 0)
+        .with_font_size(FontSize::Px(60))
+        .with_glyph_colors(vec![RED, GREEN]);
+    let mut image = blank(120, 80);
+    draw_text_into(&mut image, &span, (10, 10));
+    // The first 'H' ends before pixel 10 + 0.728 * 60
+    let is_red = |p: &image::Rgba<u8>| p.0 == [255, 0, 0, 255];
+    let is_green = |p: &image::Rgba<u8>| p.0 == [0, 255, 0, 255];
+    assert!(image.enumerate_pixels().filter(|(_, _, p)| is_red(p)).all(|(x, _, _)| x < 54));
+    assert!(image.enumerate_pixels().filter(|(_, _, p)| is_green(p)).all(|(x, _, _)| x >= 54));
+    assert!(count(&image, [255, 0, 0, 255]) > 50 && count(&image, [0, 255, 0, 255]) > 50);
+    assert_eq!(count(&image, [0, 0, 0, 255]), 0);
+}
+
+#[test]
+fn strokes_without_fill_leave_the_glyph_hollow() {
+    let font = font();
+    let span = Span::new(font.face(), "O", 0, 0)
+        .with_font_size(FontSize::Px(100))
+        .with_color([0.0; 4])
+        .with_stroke(3.0, RED);
+    let mut image = blank(140, 140);
+    draw_text_into(&mut image, &span, (20, 20));
+    // 'O' spans 58 to 684 units horizontally and -13 to 746 vertically, its center is at (57, 56.7)
+    // from the bottom-left corner
+    assert_eq!(image.get_pixel(57, 140 - 57).0, [0; 4]);
+    // Only the stroke is drawn, along the outer edge at the top and the counter's edge below it
+    assert!(image.pixels().all(|p| p[3] == 0 || p.0[..3] == [255, 0, 0]));
+    let column = (0..140).filter(|y| image.get_pixel(57, *y)[3] != 0).collect::<Vec<_>>();
+    assert!(column.first().is_some_and(|top| top.abs_diff(140 - 95) <= 2), "{column:?}");
+    // Two stroked edges on the top and two on the bottom of the ring, each about 3 pixels thick
+    let runs = column.windows(2).filter(|pair| pair[1] != pair[0] + 1).count() + 1;
+    assert_eq!(runs, 4, "{column:?}");
+    assert!(count(&image, [255, 0, 0, 255]) > 500);
+}
+
+#[test]
+fn color_glyph_layers_use_their_palette_colors() {
+    // Turns 'H' into a color glyph: a red 'o' with an 'l' in the text color on top
+    let (h, o, l) = (41u16, 80u16, 77u16);
+    let mut colr = vec![];
+    for value in [0u16, 1] {
+        colr.extend_from_slice(&value.to_be_bytes());
+    }
+    colr.extend_from_slice(&14u32.to_be_bytes());
+    colr.extend_from_slice(&20u32.to_be_bytes());
+    colr.extend_from_slice(&2u16.to_be_bytes());
+    for value in [h, 0, 2, o, 0, l, 0xffff] {
+        colr.extend_from_slice(&value.to_be_bytes());
+    }
+    let mut cpal = vec![];
+    for value in [0u16, 1, 1, 1] {
+        cpal.extend_from_slice(&value.to_be_bytes());
+    }
+    cpal.extend_from_slice(&14u32.to_be_bytes());
+    cpal.extend_from_slice(&0u16.to_be_bytes());
+    // Blue, green, red and alpha
+    cpal.extend_from_slice(&[0, 0, 255, 255]);
+    let data = with_tables(&std::fs::read(FONT_PATH).unwrap(), &[(b"COLR", colr), (b"CPAL", cpal)]);
+    let font = Font::from_bytes(data).unwrap();
+    assert!(font.face().is_color_glyph(ttf_parser::GlyphId(h)));
+
+    let span = Span::new(font.face(), "H", 0, 0).with_font_size(FontSize::Px(100)).with_color(GREEN);
+    let mut image = blank(120, 120);
+    draw_text_into(&mut image, &span, (10, 20));
+    assert!(count(&image, [255, 0, 0, 255]) > 200, "palette layer");
+    assert!(count(&image, [0, 255, 0, 255]) > 200, "foreground layer");
+    // The 'H' outline itself isn't drawn, the top of its right stem at 535 to 628 units is clear of
+    // both layers
+    assert_eq!(image.get_pixel(10 + 58, 120 - 20 - 65).0, [0; 4]);
+}
+
+#[test]
+fn opacity_fades_the_alpha_of_everything_drawn() {
+    let font = font();
+    let span = Span::new(font.face(), "H", 0, 0).with_font_size(FontSize::Px(60)).with_color(RED);
+    let mut opaque = blank(80, 80);
+    draw_text_into(&mut opaque, &span, (10, 10));
+    let covered = count(&opaque, [255, 0, 0, 255]);
+    assert!(covered > 100);
+
+    let mut half = blank(80, 80);
+    draw_text_into(&mut half, &span.clone().with_opacity(0.5), (10, 10));
+    // Fully covered pixels keep their color at half the alpha, nothing gets more opaque than that
+    assert_eq!(count(&half, [255, 0, 0, 128]), covered);
+    assert!(half.pixels().all(|p| p.0[3] <= 128));
+
+    let mut hidden = blank(80, 80);
+    draw_text_into(&mut hidden, &span.with_opacity(0.0), (10, 10));
+    assert!(hidden.pixels().all(|p| p.0 == [0; 4]));
+}
+
+/// Runs of inked rows in column `x`, top-down, with the summed coverage of each run in pixels
+fn column_runs(image: &RgbaImage, x: u32) -> Vec<(u32, u32, f32)> {
+    let mut runs: Vec<(u32, u32, f32)> = vec![];
+    for y in 0..image.height() {
+        let alpha = image.get_pixel(x, y)[3] as f32 / 255.0;
+        match runs.last_mut() {
+            _ if alpha == 0.0 => {}
+            Some((_, last, coverage)) if *last + 1 == y => {
+                *last = y;
+                *coverage += alpha;
+            }
+            _ => runs.push((y, y, alpha)),
+        }
+    }
+    runs
+}
+
+#[test]
+fn decorations_use_the_font_metrics_on_every_line() {
+    let font = font();
+    let underline = font.face().underline_metrics().unwrap();
+    let strikeout = font.face().strikeout_metrics().unwrap();
+    // 0.1 pixels per unit, the baseline at row 120 from the top
+    let scale = 0.1;
+    let span = Span::new(font.face(), "H H", 0, 0)
+        .with_font_size(FontSize::Px(100))
+        .with_underline(true)
+        .with_strikethrough(true);
+    let mut image = blank(240, 160);
+    draw_text_into(&mut image, &span, (10, 40));
+    // The space between the glyphs only has the decorations, the strikethrough above the underline
+    let runs = column_runs(&image, 95);
+    assert_eq!(runs.len(), 2, "{runs:?}");
+    let ((strike_top, _, strike_coverage), (underline_top, _, underline_coverage)) = (runs[0], runs[1]);
+    let row = |position: i16| 120.0 - position as f32 * scale;
+    assert!(underline_top > 120, "underline at row {underline_top}");
+    assert!((underline_top as f32 - row(underline.position)).abs() <= 1.0, "underline at row {underline_top}");
+    assert!((underline_coverage - underline.thickness as f32 * scale).abs() <= 1.0, "{underline_coverage}");
+    assert!((strike_top as f32 - row(strikeout.position)).abs() <= 1.0, "strikethrough at row {strike_top}");
+    assert!((strike_coverage - strikeout.thickness as f32 * scale).abs() <= 1.0, "{strike_coverage}");
+
+    // Past the 'H' ink but before the end of the line, every line has its own underline
+    let lines = Span::new(font.face(), "H\nH", 0, 0).with_font_size(FontSize::Px(40)).with_underline(true);
+    let mut image = blank(60, 160);
+    draw_text_into(&mut image, &lines, (10, 100));
+    assert_eq!(column_runs(&image, 37).len(), 2, "{:?}", column_runs(&image, 37));
+}
+
+#[test]
+fn colr_v1_linear_gradients_run_across_the_glyph() {
+    // Red at the left edge of the 'H' to blue at its right edge, constant vertically
+    let linear = paint(4, &[101, 0, 628, 0, 101, 100], &red_to_blue());
+    let font = colr_v1_font(colr_v1(&paint(10, &[41], &linear), &[]));
+    let span = Span::new(font.face(), "Ho", 0, 0).with_font_size(FontSize::Px(100));
+    let mut image = blank(200, 120);
+    draw_text_into(&mut image, &span, (10, 20));
+    // Both stems, at 101 to 193 and 535 to 628 units, away from the crossbar
+    let left = image.get_pixel(25, 80).0;
+    let right = image.get_pixel(68, 80).0;
+    assert!(left[0] > 200 && left[2] < 60 && left[3] == 255, "{left:?}");
+    assert!(right[2] > 200 && right[0] < 60 && right[3] == 255, "{right:?}");
+    // Version 0 layers of a version 1 table are still drawn
+    assert!(count(&image, [0, 255, 0, 255]) > 100, "version 0 layer");
+}
+
+#[test]
+fn colr_v1_radial_gradients_follow_their_transform() {
+    let font = radial_font();
+    let span = Span::new(font.face(), "H", 0, 0).with_font_size(FontSize::Px(100));
+    let mut image = blank(120, 120);
+    draw_text_into(&mut image, &span, (10, 20));
+    // Red at the translated center on the crossbar, padded with blue past the outer circle
+    let center = image.get_pixel(46, 61).0;
+    assert!(center[0] > 200 && center[2] < 60, "{center:?}");
+    assert_eq!(image.get_pixel(25, 98).0, [0, 0, 255, 255]);
+    let between = image.get_pixel(25, 61).0;
+    assert!(between[0] > 40 && between[0] < 120 && between[2] > 140, "{between:?}");
+}
+
+#[test]
+fn colr_v1_layers_sweep_around_their_center() {
+    // One layer sweeping from red at the positive x axis counter-clockwise to blue a turn later
+    let sweep = paint(8, &[364, 386, 0, 0x7fff], &red_to_blue());
+    let layers = [paint(10, &[41], &sweep)];
+    let mut root = vec![1, 1];
+    root.extend_from_slice(&0u32.to_be_bytes());
+    let font = colr_v1_font(colr_v1(&root, &layers));
+    let span = Span::new(font.face(), "H", 0, 0).with_font_size(FontSize::Px(100));
+    let mut image = blank(120, 120);
+    draw_text_into(&mut image, &span, (10, 20));
+    // Just above and below the center on the right stem
+    let above = image.get_pixel(68, 58).0;
+    let below = image.get_pixel(68, 66).0;
+    assert!(above[0] > 200 && above[2] < 60, "{above:?}");
+    assert!(below[2] > 200 && below[0] < 60, "{below:?}");
+}
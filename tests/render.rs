@@ -0,0 +1,397 @@
+//! Readback tests, skipped on machines without a graphics adapter
+
+mod common;
+
+use common::{font, radial_font, renderer};
+use image::GenericImageView;
+use textrenderingstuff::renderer::{AlphaMode, DownsampleFilter};
+use textrenderingstuff::{AAMode, FontSize, Span};
+
+#[test]
+fn per_span_aa_modes_share_one_render() {
+    let font = font();
+    let Some(mut renderer) = renderer(200, 100, AAMode::MSAAx4) else { return };
+    // The second render reuses the pipelines created by the first
+    for _ in 0..2 {
+        renderer.add_span(Span::new(font.face(), "o", 10, 20).with_font_size(FontSize::Px(60)).with_aa_mode(AAMode::Disabled));
+        renderer.add_span(Span::new(font.face(), "o", 110, 20).with_font_size(FontSize::Px(60)));
+        let image = renderer.render_image();
+        let is_partial = |x: u32, y: u32| {
+            let pixel = image.get_pixel(x, y).0;
+            pixel[0] != 0 && pixel[0] != 255
+        };
+        // Aliased pixels are either fully covered or not at all, multisampled edges blend
+        assert!((0..100).all(|x| (0..100).all(|y| !is_partial(x, y))));
+        assert!((100..200).any(|x| (0..100).any(|y| is_partial(x, y))));
+    }
+}
+
+#[test]
+fn png_output_is_tagged_srgb_only_for_srgb_textures() {
+    let font = font();
+    for srgb in [false, true] {
+        let Some(renderer) = renderer(64, 32, AAMode::Disabled) else { return };
+        let mut renderer = renderer.with_srgb(srgb);
+        renderer.add_span(Span::new(font.face(), "Hi", 2, 8).with_font_size(FontSize::Px(20)));
+        let path = std::env::temp_dir().join(format!("textrenderingstuff-srgb-{srgb}-{}.png", std::process::id()));
+        renderer.render_to_png(&path).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let has_chunk = |name: &[u8]| data.windows(4).any(|window| window == name);
+        assert_eq!(has_chunk(b"sRGB"), srgb);
+        assert_eq!(has_chunk(b"gAMA"), srgb);
+        assert_eq!(image::load_from_memory(&data).unwrap().dimensions(), (64, 32));
+    }
+}
+
+#[test]
+fn inner_shadows_stay_inside_the_glyphs() {
+    let font = font();
+    let Some(mut renderer) = renderer(160, 140, AAMode::MSAAx4) else { return };
+    let span = Span::new(font.face(), "H", 20, 20).with_font_size(FontSize::Px(120)).with_color([0.0, 0.0, 1.0, 1.0]);
+    renderer.add_span(span.clone());
+    let plain = renderer.render_image();
+    renderer.add_span(span.with_inner_shadow((6.0, -6.0), 0.0, [1.0, 0.0, 0.0, 1.0]));
+    let shadowed = renderer.render_image();
+    // The shadow is composited over the text only, its coverage stays the same
+    for (plain, shadowed) in plain.pixels().zip(shadowed.pixels()) {
+        assert!(plain[3].abs_diff(shadowed[3]) <= 1);
+        if plain[3] == 0 {
+            assert_eq!(shadowed[0], 0);
+        }
+    }
+    // Along the left and top edges of both stems and the bar, facing away from the offset
+    let red = shadowed.pixels().filter(|p| p[0] > 200 && p[3] == 255).count();
+    assert!(red > 500, "{red} shadowed pixels");
+    // The edges facing the offset stay lit
+    let blue = shadowed.pixels().filter(|p| p[2] > 200 && p[3] == 255).count();
+    assert!(blue > 500, "{blue} lit pixels");
+}
+
+#[test]
+fn inner_shadows_leave_strokes_alone() {
+    let font = font();
+    let Some(renderer) = renderer(160, 140, AAMode::MSAAx4) else { return };
+    let mut renderer = renderer.with_background([0.0; 4]);
+    let green = [0.0, 1.0, 0.0, 1.0];
+    let span = Span::new(font.face(), "H", 20, 20).with_font_size(FontSize::Px(120)).with_color([0.0, 0.0, 1.0, 1.0]);
+    renderer.add_span(span.clone());
+    let fill = renderer.render_image();
+    renderer.add_span(span.with_stroke(12.0, green).with_inner_shadow((6.0, -6.0), 0.0, [1.0, 0.0, 0.0, 1.0]));
+    let shadowed = renderer.render_image();
+    // Only the fill is shadowed, the outer half of the stroke keeps its color
+    let stroke = fill.pixels().zip(shadowed.pixels()).filter(|(fill, shadowed)| fill[3] == 0 && shadowed.0 == [0, 255, 0, 255]).collect::<Vec<_>>();
+    assert!(stroke.len() > 500, "{} stroke pixels", stroke.len());
+    assert!(fill.pixels().zip(shadowed.pixels()).all(|(fill, shadowed)| fill[3] != 0 || shadowed[0] == 0));
+    assert!(shadowed.pixels().any(|p| p[0] > 200));
+}
+
+#[test]
+fn semi_transparent_glyphs_read_back_in_the_requested_alpha_mode() {
+    let font = font();
+    for (alpha_mode, expected) in [(AlphaMode::Straight, [255, 128, 0]), (AlphaMode::Premultiplied, [128, 64, 0])] {
+        let Some(renderer) = renderer(120, 120, AAMode::Disabled) else { return };
+        let mut renderer = renderer.with_alpha_mode(alpha_mode).with_background([0.0; 4]);
+        renderer.add_span(Span::new(font.face(), "H", 10, 10).with_font_size(FontSize::Px(100)).with_color([1.0, 0.5, 0.0, 0.5]));
+        let data = renderer.render();
+        // Pixels inside the glyph, the aliased edges are fully covered as well
+        let covered = data.chunks_exact(4).filter(|pixel| pixel[3] != 0).collect::<Vec<_>>();
+        assert!(covered.len() > 1000);
+        for pixel in covered {
+            assert!(pixel[3].abs_diff(128) <= 1, "{alpha_mode:?} {pixel:?}");
+            for (channel, expected) in pixel[..3].iter().zip(expected) {
+                assert!(channel.abs_diff(expected) <= 2, "{alpha_mode:?} {pixel:?}");
+            }
+        }
+    }
+}
+
+#[test]
+fn downsample_filters_keep_the_ink_and_soften_edges() {
+    let font = font();
+    let mut results = Vec::new();
+    for filter in [DownsampleFilter::Box, DownsampleFilter::Tent, DownsampleFilter::Lanczos] {
+        let Some(renderer) = renderer(100, 100, AAMode::SSAAx4) else { return };
+        let mut renderer = renderer.with_downsample_filter(filter);
+        renderer.add_span(Span::new(font.face(), "o", 20, 20).with_font_size(FontSize::Px(80)).with_color([0.0, 0.0, 0.0, 1.0]));
+        let image = renderer.render_image();
+        // Black text on white, the red channel is the inverse coverage
+        let ink = image.pixels().map(|p| 1.0 - p[0] as f32 / 255.0).sum::<f32>();
+        let partial = image.pixels().filter(|p| p[0] != 0 && p[0] != 255).count();
+        results.push((ink, partial));
+    }
+    let (box_ink, box_partial) = results[0];
+    // Every filter is normalized, only clamping the Lanczos overshoot changes the amount of ink
+    assert!((results[1].0 - box_ink).abs() < box_ink * 0.02, "{results:?}");
+    assert!((results[2].0 - box_ink).abs() < box_ink * 0.04, "{results:?}");
+    // The tent reaches into the neighbouring pixels, spreading edges over more of them
+    assert!(results[1].1 > box_partial, "{results:?}");
+}
+
+#[test]
+fn subpixel_aa_is_limited_to_displayed_output() {
+    let font = font();
+    let has_fringes = |image: &image::RgbaImage| image.pixels().any(|p| p[0].abs_diff(p[2]) > 8);
+    let render = |background: [f32; 4], subpixel_aa: Option<bool>, color: [f32; 4]| {
+        let mut renderer = renderer(100, 100, AAMode::SubpixelLCD)?.with_background(background);
+        if let Some(enabled) = subpixel_aa {
+            renderer = renderer.with_subpixel_aa(enabled);
+        }
+        renderer.add_span(Span::new(font.face(), "o", 20, 20).with_font_size(FontSize::Px(60)).with_color(color));
+        Some(renderer.render_image())
+    };
+    let black = [0.0, 0.0, 0.0, 1.0];
+    let white = [1.0, 1.0, 1.0, 1.0];
+    // On the default opaque background every channel gets its own coverage
+    let Some(opaque) = render(white, None, black) else { return };
+    assert!(has_fringes(&opaque));
+    // Transparent renders are masks, drawn in grayscale unless forced
+    let transparent = render([0.0; 4], None, white).unwrap();
+    assert!(!has_fringes(&transparent));
+    assert!(transparent.pixels().any(|p| p[3] != 0 && p[3] != 255));
+    let forced_off = render(white, Some(false), black).unwrap();
+    assert!(!has_fringes(&forced_off));
+}
+
+#[test]
+fn spans_land_on_the_same_pixels_at_any_texture_size() {
+    let font = font();
+    let mut bounds = vec![];
+    for (width, height) in [(120, 100), (800, 600)] {
+        let Some(renderer) = renderer(width, height, AAMode::Disabled) else { return };
+        let mut renderer = renderer.with_background([0.0; 4]);
+        renderer.add_span(Span::new(font.face(), "H", 10, 20).with_font_size(FontSize::Px(50)));
+        let image = renderer.render_image();
+        let covered = image.enumerate_pixels().filter(|(_, _, p)| p[3] != 0).map(|(x, y, _)| (x, height - 1 - y)).collect::<Vec<_>>();
+        // Measured from the bottom-left corner
+        let x = covered.iter().map(|p| p.0);
+        let y = covered.iter().map(|p| p.1);
+        bounds.push((x.clone().min(), x.max(), y.clone().min(), y.max()));
+    }
+    assert_eq!(bounds[0], bounds[1]);
+    // 'H' starts 5.05 pixels right of the origin and is 36.65 pixels tall
+    assert_eq!(bounds[0].0, Some(15));
+    assert_eq!(bounds[0].2, Some(20));
+}
+
+#[test]
+fn backgrounds_fill_the_corners_in_the_requested_alpha_mode() {
+    let font = font();
+    let cases = [
+        ([0.0; 4], AlphaMode::Straight, [0, 0, 0, 0]),
+        ([1.0, 0.5, 0.0, 0.5], AlphaMode::Straight, [255, 128, 0, 128]),
+        ([1.0, 0.5, 0.0, 0.5], AlphaMode::Premultiplied, [128, 64, 0, 128]),
+    ];
+    for (background, alpha_mode, expected) in cases {
+        let Some(renderer) = renderer(80, 60, AAMode::MSAAx4) else { return };
+        let mut renderer = renderer.with_background(background).with_alpha_mode(alpha_mode);
+        renderer.add_span(Span::new(font.face(), "H", 20, 15).with_font_size(FontSize::Px(40)).with_color([0.0, 0.0, 0.0, 1.0]));
+        let image = renderer.render_image();
+        for (x, y) in [(0, 0), (79, 0), (0, 59), (79, 59)] {
+            let pixel = image.get_pixel(x, y).0;
+            assert!(pixel.iter().zip(expected).all(|(channel, expected)| channel.abs_diff(expected) <= 1), "{background:?} {alpha_mode:?} {pixel:?}");
+        }
+        // The glyph itself is drawn over the background
+        assert!(image.pixels().any(|p| p[3] == 255 && p[0] == 0));
+    }
+}
+
+#[test]
+fn unaligned_widths_read_back_tightly_packed() {
+    let font = font();
+    for (width, height) in [(100, 100), (1900, 1080), (61, 33)] {
+        let Some(renderer) = renderer(width, height, AAMode::Disabled) else { return };
+        let mut renderer = renderer.with_background([0.0; 4]);
+        renderer.add_span(Span::new(font.face(), "H", 4, 4).with_font_size(FontSize::Px(30)));
+        let data = renderer.render();
+        assert_eq!(data.len(), (width * height * 4) as usize);
+        // Leftover row padding would shear the stem of 'H', every covered row starts in the same column
+        let starts = data.chunks_exact(width as usize * 4)
+            .filter_map(|row| row.chunks_exact(4).position(|pixel| pixel[3] != 0))
+            .collect::<Vec<_>>();
+        assert!(starts.len() > 15, "{width}x{height}");
+        assert!(starts.iter().all(|start| *start == starts[0]), "{width}x{height} {starts:?}");
+    }
+}
+
+#[test]
+fn sequential_renders_are_independent() {
+    let font = font();
+    let Some(renderer) = renderer(120, 60, AAMode::MSAAx4) else { return };
+    let mut renderer = renderer.with_background([0.0; 4]);
+    let is_inked = |image: &image::RgbaImage, columns: std::ops::Range<u32>| {
+        image.enumerate_pixels().any(|(x, _, p)| columns.contains(&x) && p[3] != 0)
+    };
+    renderer.add_span(Span::new(font.face(), "H", 5, 10).with_font_size(FontSize::Px(40)));
+    let first = renderer.render_image();
+    renderer.add_span(Span::new(font.face(), "H", 65, 10).with_font_size(FontSize::Px(40)));
+    let second = renderer.render_image();
+    assert!(is_inked(&first, 0..60) && !is_inked(&first, 60..120));
+    assert!(!is_inked(&second, 0..60) && is_inked(&second, 60..120));
+    // The queue is empty after every render
+    let empty = renderer.render_image();
+    assert!(empty.pixels().all(|p| p[3] == 0));
+}
+
+#[test]
+fn repeated_glyphs_are_tessellated_once() {
+    let font = font();
+    let Some(mut renderer) = renderer(200, 60, AAMode::Disabled) else { return };
+    renderer.add_span(Span::new(font.face(), "HHHH", 5, 10).with_font_size(FontSize::Px(40)));
+    renderer.render();
+    let stats = renderer.stats();
+    assert_eq!((stats.glyph_hits, stats.glyph_misses), (3, 1));
+    // The cache outlives the render
+    renderer.add_span(Span::new(font.face(), "HHHH", 5, 10).with_font_size(FontSize::Px(40)));
+    renderer.render();
+    let stats = renderer.stats();
+    assert_eq!((stats.glyph_hits, stats.glyph_misses), (7, 1));
+}
+
+#[test]
+fn srgb_targets_blend_edges_in_linear_light() {
+    let font = font();
+    let mut edge_means = vec![];
+    for srgb in [false, true] {
+        let Some(renderer) = renderer(120, 80, AAMode::MSAAx4) else { return };
+        let mut renderer = renderer.with_srgb(srgb);
+        renderer.add_span(Span::new(font.face(), "o", 10, 15).with_font_size(FontSize::Px(60)).with_color([0.0, 0.0, 0.0, 1.0]));
+        // Span colors are sRGB encoded either way, so a solid gray reads back unchanged
+        renderer.add_span(Span::new(font.face(), "H", 60, 15).with_font_size(FontSize::Px(60)).with_color([0.5, 0.5, 0.5, 1.0]));
+        let image = renderer.render_image();
+        assert!(image.enumerate_pixels().filter(|(x, _, _)| *x >= 60).any(|(_, _, p)| p[0].abs_diff(128) <= 1));
+        // Edge pixels of the black 'o' on white
+        let edges = image.enumerate_pixels()
+            .filter(|(x, _, p)| *x < 60 && p[0] != 0 && p[0] != 255)
+            .map(|(_, _, p)| p[0] as f32)
+            .collect::<Vec<_>>();
+        assert!(edges.len() > 50);
+        edge_means.push(edges.iter().sum::<f32>() / edges.len() as f32);
+    }
+    // Half covered pixels are encoded around 188 instead of 128, the ramp no longer looks too dark
+    assert!(edge_means[1] > edge_means[0] + 20.0, "{edge_means:?}");
+}
+
+#[test]
+fn spans_draw_their_own_vertices() {
+    let font = font();
+    let Some(renderer) = renderer(300, 100, AAMode::Disabled) else { return };
+    let mut renderer = renderer.with_background([0.0; 4]);
+    let colors = [[1.0, 0.0, 0.0, 1.0], [0.0, 1.0, 0.0, 1.0], [0.0, 0.0, 1.0, 1.0]];
+    // Different texts, so the spans have different vertex counts
+    for (index, (text, color)) in ["Hg", "ogH", "Hello"].iter().zip(colors).enumerate() {
+        renderer.add_span(Span::new(font.face(), text, 100 * index as i32 + 5, 30).with_font_size(FontSize::Px(25)).with_color(color));
+    }
+    let image = renderer.render_image();
+    for (index, color) in colors.iter().enumerate() {
+        let expected = color.map(|channel| (channel * 255.0) as u8);
+        let columns = image.enumerate_pixels().filter(|(_, _, p)| p[3] != 0 && p.0[..3] == expected[..3]).map(|(x, _, _)| x).collect::<Vec<_>>();
+        assert!(columns.len() > 50, "span {index}");
+        assert!(columns.iter().all(|x| (100 * index as u32..100 * index as u32 + 100).contains(x)), "span {index}");
+    }
+}
+
+#[test]
+fn renderers_move_to_other_threads() {
+    fn assert_send<T: Send>() {}
+    assert_send::<textrenderingstuff::TextureRenderer>();
+
+    let font = font();
+    let Some(mut renderer) = renderer(80, 40, AAMode::Disabled) else { return };
+    renderer.add_span(Span::new(font.face(), "H", 5, 5).with_font_size(FontSize::Px(30)));
+    let image = std::thread::scope(|scope| scope.spawn(move || renderer.render_image()).join().unwrap());
+    assert!(image.pixels().any(|p| p[0] == 0));
+}
+
+#[test]
+fn identical_runs_are_shaped_once() {
+    let font = font();
+    let Some(mut renderer) = renderer(200, 200, AAMode::Disabled) else { return };
+    // Table cells repeating the same strings
+    for row in 0..3 {
+        renderer.add_span(Span::new(font.face(), "cell", 5, 10 + 50 * row).with_font_size(FontSize::Px(20)));
+        renderer.add_span(Span::new(font.face(), "total", 105, 10 + 50 * row).with_font_size(FontSize::Px(20)));
+    }
+    renderer.render();
+    let stats = renderer.stats();
+    assert_eq!((stats.run_hits, stats.run_misses), (4, 2));
+    // One HarfBuzz font for the face, shared by every span
+    assert_eq!(stats.shapers, 1);
+}
+
+#[test]
+fn bitmap_glyphs_cast_shadows_of_their_alpha() {
+    // Gives the space a 20 pixel sbix bitmap, opaque blue on the left half and transparent on the right
+    let data = std::fs::read(common::FONT_PATH).unwrap();
+    let face = ttf_parser::Face::parse(&data, 0).unwrap();
+    let space = face.glyph_index(' ').unwrap().0 as usize;
+    let bitmap = image::RgbaImage::from_fn(20, 20, |x, _| image::Rgba(if x < 10 { [0, 0, 255, 255] } else { [0; 4] }));
+    let mut png = std::io::Cursor::new(vec![]);
+    bitmap.write_to(&mut png, image::ImageFormat::Png).unwrap();
+    let mut glyph_data = vec![0; 4];
+    glyph_data.extend_from_slice(b"png ");
+    glyph_data.extend_from_slice(png.get_ref());
+    let glyph_count = face.number_of_glyphs() as usize;
+    let mut sbix = vec![0, 1, 0, 1, 0, 0, 0, 1, 0, 0, 0, 12];
+    sbix.extend_from_slice(&20u16.to_be_bytes());
+    sbix.extend_from_slice(&72u16.to_be_bytes());
+    let data_start = 4 + 4 * (glyph_count + 1);
+    for glyph in 0..=glyph_count {
+        let offset = data_start + if glyph > space { glyph_data.len() } else { 0 };
+        sbix.extend_from_slice(&(offset as u32).to_be_bytes());
+    }
+    sbix.extend_from_slice(&glyph_data);
+    let font = textrenderingstuff::Font::from_bytes(common::with_tables(&data, &[(b"sbix", sbix)])).unwrap();
+
+    let Some(renderer) = renderer(160, 100, AAMode::Disabled) else { return };
+    let mut renderer = renderer.with_background([0.0, 0.0, 0.0, 1.0]);
+    // One em at 40 pixels shows the bitmap from 10 to 50 pixels, its shadow from 70 to 110
+    renderer.add_span(
+        Span::new(font.face(), " ", 10, 30)
+            .with_font_size(FontSize::Px(40))
+            .with_shadow((60.0, 0.0), [0.0, 1.0, 0.0, 1.0], 0.0),
+    );
+    let image = renderer.render_image();
+    assert_eq!(image.get_pixel(20, 50).0, [0, 0, 255, 255]);
+    assert_eq!(image.get_pixel(40, 50).0, [0, 0, 0, 255]);
+    // The shadow is the bitmap's alpha in the shadow color, not a copy of its pixels
+    assert_eq!(image.get_pixel(80, 50).0, [0, 255, 0, 255]);
+    assert_eq!(image.get_pixel(100, 50).0, [0, 0, 0, 255]);
+    assert!(image.enumerate_pixels().all(|(x, _, p)| x < 60 || p.0[2] == 0));
+}
+
+#[test]
+fn higher_layers_draw_over_later_spans_in_the_same_pass() {
+    let font = font();
+    let Some(renderer) = renderer(120, 100, AAMode::MSAAx4) else { return };
+    let mut renderer = renderer.with_background([0.0; 4]);
+    let red = [1.0, 0.0, 0.0, 1.0];
+    let green = [0.0, 1.0, 0.0, 1.0];
+    let span = |color| Span::new(font.face(), "H", 10, 10).with_font_size(FontSize::Px(100)).with_color(color);
+    // The inside of the left stem is covered by both spans
+    let stem = |image: &image::RgbaImage| image.get_pixel(10 + 15, 100 - 10 - 40).0;
+    renderer.add_span(span(red).with_z(1.0));
+    renderer.add_span(span(green).with_z(0.0));
+    assert_eq!(stem(&renderer.render_image()), [255, 0, 0, 255]);
+
+    // An aliased span is drawn in a pass after the multisampled ones, its lower layer doesn't matter
+    renderer.add_span(span(red).with_z(1.0));
+    renderer.add_span(span(green).with_z(0.0).with_aa_mode(AAMode::Disabled));
+    assert_eq!(stem(&renderer.render_image()), [0, 255, 0, 255]);
+}
+
+#[test]
+fn colr_v1_gradients_are_evaluated_per_fragment() {
+    let font = radial_font();
+    let Some(renderer) = renderer(120, 120, AAMode::Disabled) else { return };
+    let mut renderer = renderer.with_background([0.0; 4]);
+    renderer.add_span(Span::new(font.face(), "H", 10, 20).with_font_size(FontSize::Px(100)));
+    let image = renderer.render_image();
+    // Red at the gradient's center on the crossbar, padded with blue past the outer circle
+    let center = image.get_pixel(46, 61).0;
+    assert!(center[0] > 200 && center[2] < 60, "{center:?}");
+    assert_eq!(image.get_pixel(25, 98).0, [0, 0, 255, 255]);
+    // On the left stem level with the center, 217 units out, mostly blue with some red left
+    let between = image.get_pixel(25, 61).0;
+    assert!(between[0] > 40 && between[0] < 120 && between[2] > 140, "{between:?}");
+}
@@ -0,0 +1,104 @@
+mod common;
+
+use common::{assert_near, font};
+use textrenderingstuff::shaper::{measure_runs, MeasureRun, Shaper, ShapingOptions};
+use textrenderingstuff::FontSize;
+
+#[test]
+fn runs_are_measured_with_their_own_dpi_and_options() {
+    let font = font();
+    let metrics = measure_runs(&[
+        MeasureRun::new("H", font.face(), FontSize::Px(100)),
+        // 72pt at 300 DPI are 300 pixels
+        MeasureRun::new("H", font.face(), FontSize::Pt(72)).with_dpi(300.0),
+        // The tab advances to the stop a full em from the run's start
+        MeasureRun::new("\t", font.face(), FontSize::Px(100))
+            .with_options(ShapingOptions { tab_width: Some(1000), ..Default::default() }),
+    ]);
+    let widths = metrics.runs.iter().map(|run| run.width).collect::<Vec<_>>();
+    assert_near(widths[0], 72.8, 1e-3);
+    assert_near(widths[1], 218.4, 1e-3);
+    assert_near(widths[2], 100.0, 1e-3);
+    assert_near(metrics.width, 72.8 + 218.4 + 100.0, 1e-2);
+    // The tallest run sets the height
+    assert_near(metrics.height, (1160.0 + 288.0) * 0.3, 1e-2);
+}
+
+#[test]
+fn tabs_advance_to_the_next_stop() {
+    let font = font();
+    let shaper = Shaper::new(font.face());
+    let options = ShapingOptions { tab_width: Some(1000), ..Default::default() };
+    // 'a' is 563 units wide, so the first tab only fills up the rest of the first stop
+    let glyphs = shaper.shape("a\t\tl", &options);
+    let advances = glyphs.iter().map(|data| data.x_advance).collect::<Vec<_>>();
+    assert_eq!(advances, [563, 437, 1000, 284]);
+    // The font has no tab glyph, tabs are drawn as spaces instead of .notdef
+    assert!(glyphs[1..3].iter().all(|data| data.glyph_id != 0));
+
+    // Without a tab width the font's own tab advance is kept
+    let glyphs = shaper.shape("a\tl", &ShapingOptions::default());
+    assert_ne!(glyphs[1].x_advance, 437);
+}
+
+/// Glyph ids, advances and clusters of `text` shaped with the default options
+fn shaped(shaper: &Shaper, text: &str) -> Vec<(u32, i32, u32)> {
+    shaper.shape(text, &ShapingOptions::default()).iter().map(|data| (data.glyph_id, data.x_advance, data.cluster)).collect()
+}
+
+#[test]
+fn shapers_are_shared_across_threads() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Shaper>();
+
+    let font = font();
+    let shaper = Shaper::new(font.face());
+    let expected = shaped(&shaper, "Hello");
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for _ in 0..100 {
+                    assert_eq!(shaped(&shaper, "Hello"), expected);
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn repeated_shapers_shape_alike() {
+    let font = font();
+    let expected = shaped(&Shaper::new(font.face()), "Hello");
+    // Every shaper destroys its HarfBuzz face and font when dropped
+    for _ in 0..1000 {
+        assert_eq!(shaped(&Shaper::new(font.face()), "Hello"), expected);
+    }
+}
+
+#[test]
+fn features_toggle_ligatures() {
+    let font = font();
+    let shaper = Shaper::new(font.face());
+    let liga = ttf_parser::Tag::from_bytes(b"liga");
+    let glyph_count = |value| shaper.shape("ffi", &ShapingOptions { features: &[(liga, value)], ..Default::default() }).len();
+    assert_eq!(glyph_count(1), 1);
+    assert_eq!(glyph_count(0), 3);
+}
+
+#[test]
+fn forced_directions_reverse_the_glyph_order() {
+    let font = font();
+    let shaper = Shaper::new(font.face());
+    let clusters = |options: &ShapingOptions| {
+        let (glyphs, rtl) = shaper.shape_directed("abc", options);
+        (glyphs.iter().map(|data| data.cluster).collect::<Vec<_>>(), rtl)
+    };
+    // Latin is guessed left-to-right
+    assert_eq!(clusters(&ShapingOptions::default()), (vec![0, 1, 2], false));
+    let rtl = ShapingOptions { direction: Some(harfbuzz::Direction::RTL), ..Default::default() };
+    assert_eq!(clusters(&rtl), (vec![2, 1, 0], true));
+    // Script and language don't override an explicit direction
+    let latin = ttf_parser::Tag::from_bytes(b"Latn");
+    let tagged = ShapingOptions { script: Some(latin), language: Some("en"), ..rtl };
+    assert_eq!(clusters(&tagged), (vec![2, 1, 0], true));
+}
@@ -0,0 +1,429 @@
+mod common;
+
+use common::{assert_near, blank, font, ink_columns, ink_rows, variable_font};
+use textrenderingstuff::raster::{draw_text_into, rasterize};
+use textrenderingstuff::{Alignment, FontSize, Span};
+
+#[test]
+fn large_font_sizes_scale_to_one_em() {
+    let font = font();
+    for px in [200, 600, 1000] {
+        let span = Span::new(font.face(), "H", 0, 0).with_font_size(FontSize::Px(px));
+        let scale = px as f32 / 1000.0;
+        let (width, height) = span.measure();
+        assert_near(width, 728.0 * scale, 1e-3 * px as f32);
+        assert_near(height, (1160.0 + 288.0) * scale, 1e-3 * px as f32);
+
+        // The flat top of 'H' sits at 733 units above the baseline
+        let mut image = blank(px as u32 + 40, px as u32 + 40);
+        draw_text_into(&mut image, &span, (20, 20));
+        let (top, bottom) = ink_rows(&image, [0; 4]).unwrap();
+        assert_eq!(bottom, image.height() - 1 - 20);
+        assert_near((bottom - top + 1) as f32, 733.0 * scale, 1.5);
+    }
+}
+
+#[test]
+fn point_sizes_follow_the_span_dpi() {
+    let font = font();
+    // 72pt are one inch, 300 pixels at 300 DPI
+    let span = Span::new(font.face(), "H", 0, 0).with_font_size(FontSize::Pt(72)).with_dpi(300.0);
+    assert_near(span.measure().0, 728.0 * 0.3, 1e-2);
+    let mut image = blank(300, 300);
+    draw_text_into(&mut image, &span, (10, 10));
+    let (top, bottom) = ink_rows(&image, [0; 4]).unwrap();
+    assert_near((bottom - top + 1) as f32, 733.0 * 0.3, 1.5);
+}
+
+#[test]
+fn lines_without_a_size_stack_below_the_position() {
+    let font = font();
+    let span = Span::new(font.face(), "H\nH", 0, 0).with_font_size(FontSize::Px(50));
+    let mut image = blank(100, 200);
+    draw_text_into(&mut image, &span, (10, 120));
+    // The first baseline is on the position, the second one a line height of 72.4 pixels below
+    let (top, bottom) = ink_rows(&image, [0; 4]).unwrap();
+    assert_near(top as f32, 200.0 - 120.0 - 36.65, 1.0);
+    assert_near(bottom as f32, 200.0 - 1.0 - 120.0 + 72.4, 1.0);
+    // Alignment only applies inside a size
+    let mut aligned = blank(100, 200);
+    draw_text_into(&mut aligned, &span.with_v_align(Alignment::End), (10, 120));
+    assert_eq!(ink_rows(&aligned, [0; 4]), Some((top, bottom)));
+}
+
+#[test]
+fn meshes_map_pixels_into_the_given_viewport() {
+    let font = font();
+    let span = Span::new(font.face(), "H", 50, 40).with_font_size(FontSize::Px(100));
+    for (width, height) in [(200, 100), (800, 600)] {
+        let mesh = span.generate_text_mesh(0, (width, height));
+        let min = |axis: usize| mesh.vertices.iter().map(|v| v.position[axis]).fold(f32::MAX, f32::min);
+        let max = |axis: usize| mesh.vertices.iter().map(|v| v.position[axis]).fold(f32::MIN, f32::max);
+        // The stems of 'H' span 101 to 628 units, its bottom sits on the baseline and its top at 733
+        assert_near(min(0), 60.1 / width as f32 * 2.0 - 1.0, 1e-4);
+        assert_near(max(0), 112.8 / width as f32 * 2.0 - 1.0, 1e-4);
+        assert_near(min(1), 40.0 / height as f32 * 2.0 - 1.0, 1e-4);
+        assert_near(max(1), 113.3 / height as f32 * 2.0 - 1.0, 1e-4);
+    }
+}
+
+#[test]
+fn rotated_spans_turn_their_bounding_box() {
+    let font = font();
+    let span = Span::new(font.face(), "TEST", 0, 0).with_font_size(FontSize::Px(40));
+    let bounds = |span: &Span| {
+        let mut image = blank(200, 200);
+        draw_text_into(&mut image, span, (100, 100));
+        (ink_columns(&image, [0; 4]).unwrap(), ink_rows(&image, [0; 4]).unwrap())
+    };
+    let ((left, right), (top, bottom)) = bounds(&span);
+    assert!(right - left > 2 * (bottom - top));
+    // A quarter turn counter-clockwise around the position, the text runs upwards left of it
+    let rotated = span.with_transform([[0.0, -1.0], [1.0, 0.0]], (0.0, 0.0));
+    let ((rotated_left, rotated_right), (rotated_top, rotated_bottom)) = bounds(&rotated);
+    assert!((rotated_right - rotated_left).abs_diff(bottom - top) <= 1);
+    assert!((rotated_bottom - rotated_top).abs_diff(right - left) <= 1);
+    assert!(rotated_right.abs_diff(100) <= 1 && rotated_bottom < 100);
+}
+
+#[test]
+fn variations_change_the_stroke_weight() {
+    let font = variable_font();
+    let wght = ttf_parser::Tag::from_bytes(b"wght");
+    let ink = |span: &Span| {
+        let mut image = blank(200, 120);
+        draw_text_into(&mut image, span, (10, 20));
+        image.pixels().map(|p| p[3] as f32 / 255.0).sum::<f32>()
+    };
+    let span = Span::new(font.face(), "Hl", 0, 0).with_font_size(FontSize::Px(80));
+    let regular = span.clone().with_variations(&[(wght, 400.0)]);
+    let black = span.clone().with_variations(&[(wght, 900.0)]);
+    assert!(ink(&black) > ink(&regular) * 1.5, "{} {}", ink(&black), ink(&regular));
+    // Shaping uses the same instance, heavier glyphs advance further
+    assert!(black.measure().0 > regular.measure().0);
+    // Axes the font doesn't have are ignored
+    let ignored = regular.clone().with_variations(&[(ttf_parser::Tag::from_bytes(b"wdth"), 50.0)]);
+    assert_eq!(ink(&ignored), ink(&regular));
+    assert_eq!(ignored.measure(), regular.measure());
+}
+
+#[test]
+fn span_features_reach_the_shaper() {
+    let font = font();
+    let liga = ttf_parser::Tag::from_bytes(b"liga");
+    let span = Span::new(font.face(), "ffi", 0, 0);
+    // One chunk per glyph
+    assert_eq!(span.split_by_glyph_budget(1).len(), 1);
+    assert_eq!(span.with_features(&[(liga, 0)]).split_by_glyph_budget(1).len(), 3);
+}
+
+#[test]
+fn right_to_left_words_start_at_the_right() {
+    let font = font();
+    let (red, green) = ([1.0, 0.0, 0.0, 1.0], [0.0, 1.0, 0.0, 1.0]);
+    // The font has no Hebrew, the letters are drawn as .notdef boxes in their colors
+    let span = Span::new(font.face(), "\u{5e9}\u{5dc}\u{5d5}\u{5dd}", 0, 0)
+        .with_font_size(FontSize::Px(40))
+        .with_glyph_colors(vec![red, green, green, green]);
+    let columns = |image: &image::RgbaImage, color: [u8; 4]| {
+        let columns = image.enumerate_pixels().filter(|(_, _, p)| p.0 == color).map(|(x, _, _)| x).collect::<Vec<_>>();
+        (*columns.iter().min().unwrap(), *columns.iter().max().unwrap())
+    };
+    let mut image = blank(400, 80);
+    draw_text_into(&mut image, &span, (10, 20));
+    // The first letter is rightmost
+    assert!(columns(&image, [0, 255, 0, 255]).1 < columns(&image, [255, 0, 0, 255]).0);
+    // Start alignment is mirrored onto the right edge of the size
+    let mut aligned = blank(400, 80);
+    draw_text_into(&mut aligned, &span.with_size(380, 60).with_h_align(Alignment::Start), (10, 20));
+    let (_, right) = columns(&aligned, [255, 0, 0, 255]);
+    assert!((380..=390).contains(&right), "{right}");
+}
+
+#[test]
+fn embedded_right_to_left_runs_are_reordered() {
+    let font = font();
+    let (red, green, blue) = ([1.0, 0.0, 0.0, 1.0], [0.0, 1.0, 0.0, 1.0], [0.0, 0.0, 1.0, 1.0]);
+    // "ab <alef><bet> cd", the Hebrew run is shown reversed between the Latin ones
+    let span = Span::new(font.face(), "ab \u{5d0}\u{5d1} cd", 0, 0)
+        .with_font_size(FontSize::Px(40))
+        .with_glyph_colors(vec![blue, blue, blue, red, green, blue, [0.0, 0.0, 0.5, 1.0], [0.0, 0.0, 0.5, 1.0]]);
+    let mut image = blank(300, 80);
+    draw_text_into(&mut image, &span, (10, 20));
+    // Glyph colors are looked up through the clusters, so every color marks its own character
+    let columns = |color: [u8; 4]| {
+        let columns = image.enumerate_pixels().filter(|(_, _, p)| p.0 == color).map(|(x, _, _)| x).collect::<Vec<_>>();
+        (*columns.iter().min().unwrap(), *columns.iter().max().unwrap())
+    };
+    let (ab, bet, alef, cd) = (columns([0, 0, 255, 255]), columns([0, 255, 0, 255]), columns([255, 0, 0, 255]), columns([0, 0, 128, 255]));
+    assert!(ab.1 < bet.0 && bet.1 < alef.0 && alef.1 < cd.0, "{ab:?} {bet:?} {alef:?} {cd:?}");
+}
+
+#[test]
+fn long_texts_index_past_u16() {
+    let font = font();
+    let text = "Hello world ".repeat(1500);
+    let span = Span::new(font.face(), &text, 0, 0).with_font_size(FontSize::Px(10)).with_size(780, 580).with_wrap(true);
+    let mesh = span.generate_text_mesh(0, (800, 600));
+    assert!(mesh.vertices.len() > 1 << 16, "{} vertices", mesh.vertices.len());
+    assert!(mesh.indices.iter().all(|index| (*index as usize) < mesh.vertices.len()));
+    // Every triangle stays within a glyph, wrapped indices would connect distant glyphs
+    let extent = |values: &[f32]| values.iter().copied().fold(f32::MIN, f32::max) - values.iter().copied().fold(f32::MAX, f32::min);
+    for triangle in mesh.indices.chunks_exact(3) {
+        let (xs, ys): (Vec<f32>, Vec<f32>) = triangle.iter()
+            .map(|index| mesh.vertices[*index as usize].position)
+            .map(|position| (position[0] * 400.0, position[1] * 300.0))
+            .unzip();
+        assert!(extent(&xs) < 12.0 && extent(&ys) < 12.0);
+    }
+}
+
+#[test]
+fn split_chunks_render_in_place() {
+    let font = font();
+    let span = Span::new(font.face(), "Hello\nworld", 10, 10).with_font_size(FontSize::Px(40));
+    let chunks = span.split_by_glyph_budget(2);
+    // "He", "ll", "o" and "wo", "rl", "d"
+    assert_eq!(chunks.len(), 6);
+    let mut whole = blank(300, 120);
+    draw_text_into(&mut whole, &span, (10, 60));
+    let mut pieces = blank(300, 120);
+    for chunk in &chunks {
+        draw_text_into(&mut pieces, chunk, (10, 60));
+    }
+    let (whole_columns, pieces_columns) = (ink_columns(&whole, [0; 4]).unwrap(), ink_columns(&pieces, [0; 4]).unwrap());
+    assert!(whole_columns.0.abs_diff(pieces_columns.0) <= 1 && whole_columns.1.abs_diff(pieces_columns.1) <= 1);
+    assert_eq!(ink_rows(&whole, [0; 4]), ink_rows(&pieces, [0; 4]));
+}
+
+#[test]
+fn split_chunks_keep_the_fitted_size() {
+    let font = font();
+    let span = Span::new(font.face(), "abcdefghij", 0, 0)
+        .with_font_size(FontSize::Px(100))
+        .with_fit_width(200.0, FontSize::Px(4));
+    let fitted_width = span.measure().0;
+    assert!(fitted_width <= 200.0);
+    // Fitting each chunk on its own would blow every chunk up to the full 100 pixels
+    let chunks = span.split_by_glyph_budget(3);
+    assert_eq!(chunks.len(), 4);
+    for chunk in &chunks {
+        assert!(matches!(chunk.fitted_font_size(), FontSize::Px(px) if px < 100));
+    }
+    let chunk_widths = chunks.iter().map(|chunk| chunk.measure().0).sum::<f32>();
+    assert_near(chunk_widths, fitted_width, 2.0);
+}
+
+#[test]
+fn split_chunks_rebase_glyph_colors() {
+    let font = font();
+    let colors = (0..6).map(|index| [index as f32 / 6.0, 0.0, 0.0, 1.0]).collect::<Vec<_>>();
+    let span = Span::new(font.face(), "abcdef", 0, 0).with_glyph_colors(colors.clone());
+    let chunks = span.split_by_glyph_budget(2);
+    assert_eq!(chunks.len(), 3);
+    for (index, chunk) in chunks.iter().enumerate() {
+        // Palette entry 0 is the span color, followed by the glyph colors from the chunk's first character
+        assert_eq!(chunk.palette()[1], colors[index * 2]);
+    }
+}
+
+#[test]
+fn tab_stops_are_measured_and_rendered_alike() {
+    let font = font();
+    // Stops every 4 spaces of 224 units, the tab after 'a' ends at 896 units
+    let span = Span::new(font.face(), "a\tl", 0, 0).with_font_size(FontSize::Px(100)).with_tab_width(4.0);
+    assert_near(span.measure().0, 89.6 + 28.4, 1e-3);
+    let mut image = blank(160, 160);
+    draw_text_into(&mut image, &span, (10, 30));
+    // 'a' ends before pixel 60, the stem of 'l' starts 9.2 pixels after the tab stop
+    let (left, right) = ink_columns(&image, [0; 4]).unwrap();
+    assert!(left < 20);
+    assert!((100..=10 + 118).contains(&right), "{right}");
+    let l_left = (70..right).find(|x| (0..160).any(|y| image.get_pixel(*x, y)[3] != 0)).unwrap();
+    assert!((106..=110).contains(&l_left), "{l_left}");
+}
+
+#[test]
+fn right_to_left_glyphs_are_layered_in_logical_order() {
+    let font = font();
+    // Hebrew falls back to .notdef boxes, laid out right to left with bet to the left of alef
+    let span = Span::new(font.face(), "\u{5d0}\u{5d1}", 0, 0).with_font_size(FontSize::Px(100));
+    let mesh = span.generate_text_mesh(0, (400, 200));
+    let middle = mesh.vertices.iter().map(|v| v.position[0]).sum::<f32>() / mesh.vertices.len() as f32;
+    let depths = |left: bool| {
+        let mut depths = mesh.vertices.iter().filter(|v| (v.position[0] < middle) == left).map(|v| v.position[2]).collect::<Vec<_>>();
+        depths.dedup();
+        depths
+    };
+    // The order depth is derived from, the logically later bet goes on top
+    assert_eq!(depths(true), [1.0]);
+    assert_eq!(depths(false), [0.0]);
+}
+
+#[test]
+fn fit_width_shrinks_long_labels_only() {
+    let font = font();
+    let label = |text| Span::new(font.face(), text, 0, 0).with_font_size(FontSize::Px(40)).with_fit_width(150.0, FontSize::Px(8));
+    let px = |span: &Span| match span.fitted_font_size() {
+        FontSize::Px(px) => px,
+        size => panic!("{size:?}"),
+    };
+    // "OK" already fits at the configured size
+    let short = label("OK");
+    assert_eq!(px(&short), 40);
+    let long = label("Save all changes");
+    assert!(px(&long) < 40);
+    assert!(long.measure().0 <= 150.0);
+    // One pixel larger wouldn't fit any more
+    let larger = Span::new(font.face(), "Save all changes", 0, 0).with_font_size(FontSize::Px(px(&long) + 1));
+    assert!(larger.measure().0 > 150.0);
+    // Never below the floor, even if the text then overflows
+    let floored = label("A label far too long to fit into a button at any readable size");
+    assert_eq!(px(&floored), 8);
+    assert!(floored.measure().0 > 150.0);
+}
+
+#[test]
+fn end_pen_includes_the_alignment_offset() {
+    let font = font();
+    let span = Span::new(font.face(), "H", 30, 10)
+        .with_font_size(FontSize::Px(100))
+        .with_size(400, 200)
+        .with_h_align(Alignment::End);
+    // The aligned origin is truncated to 30 + 400 - 72 pixels, the pen ends 72.8 pixels after it
+    let (x, y) = span.end_pen();
+    assert_near(x, 400.0 - 72.0 + 72.8, 1e-3);
+    assert_near(y, 0.0, 1e-3);
+}
+
+#[test]
+fn end_pen_uses_the_fitted_size() {
+    let font = font();
+    let span = Span::new(font.face(), "abcdefghij", 0, 0)
+        .with_font_size(FontSize::Px(100))
+        .with_fit_width(200.0, FontSize::Px(4));
+    assert_near(span.end_pen().0, span.measure().0, 1e-3);
+}
+
+#[test]
+fn chained_runs_continue_the_line() {
+    let font = font();
+    let first = Span::new(font.face(), "H", 0, 0).with_font_size(FontSize::Px(50));
+    let second = Span::new(font.face(), "H", 0, 0).with_font_size(FontSize::Px(50)).with_start_pen(first.end_pen());
+    let mut chained = blank(120, 60);
+    draw_text_into(&mut chained, &first, (5, 5));
+    draw_text_into(&mut chained, &second, (5, 5));
+    let mut whole = blank(120, 60);
+    draw_text_into(&mut whole, &Span::new(font.face(), "HH", 0, 0).with_font_size(FontSize::Px(50)), (5, 5));
+    assert_eq!(ink_columns(&chained, [0; 4]), ink_columns(&whole, [0; 4]));
+    assert_eq!(ink_rows(&chained, [0; 4]), ink_rows(&whole, [0; 4]));
+}
+
+#[test]
+fn paragraph_spacing_adds_to_the_height() {
+    let font = font();
+    let span = Span::new(font.face(), "H\nH H", 0, 0).with_font_size(FontSize::Px(50));
+    let plain_height = span.measure().1;
+    // Two paragraphs, wrapping the second one doesn't add spacing
+    let spaced = span.clone().with_paragraph_spacing(8.0, 4.0);
+    assert_near(spaced.measure().1, plain_height + 2.0 * 12.0, 1e-3);
+    let wrapped = spaced.clone().with_size(60, 400).with_wrap(true);
+    assert_near(wrapped.measure().1, plain_height + 50.0 * 1.448 + 2.0 * 12.0, 1e-2);
+}
+
+#[test]
+fn paragraph_spacing_moves_the_lines() {
+    let font = font();
+    let render = |span: Span| {
+        let mut image = blank(100, 200);
+        draw_text_into(&mut image, &span, (10, 20));
+        ink_rows(&image, [0; 4]).unwrap()
+    };
+    let span = Span::new(font.face(), "H\nH", 0, 0).with_font_size(FontSize::Px(50)).with_start_pen((0.0, 120.0));
+    let (top, bottom) = render(span.clone());
+    // The first line moves down by its space before, the second also by the space around the break
+    let (spaced_top, spaced_bottom) = render(span.clone().with_paragraph_spacing(8.0, 4.0));
+    assert_near(spaced_top as f32, top as f32 + 8.0, 1.0);
+    assert_near(spaced_bottom as f32, bottom as f32 + 20.0, 1.0);
+
+    // Aligned to the bottom of its size, the block rises by the space after the last paragraph
+    let aligned = span.with_start_pen((0.0, 0.0)).with_size(100, 200).with_v_align(Alignment::Start);
+    let (_, bottom) = render(aligned.clone());
+    let (_, spaced_bottom) = render(aligned.with_paragraph_spacing(8.0, 4.0));
+    assert_near(spaced_bottom as f32, bottom as f32 - 4.0, 1.0);
+}
+
+#[test]
+fn baseline_snapping_lands_every_line_on_a_pixel() {
+    let font = font();
+    let span = Span::new(font.face(), "H\nH", 0, 0).with_font_size(FontSize::Px(30)).with_start_pen((0.4, 100.3));
+    // Bottom edge of the 'H' of each line in pixels, which sits on the baseline
+    let baselines = |span: &Span| {
+        let mesh = span.generate_text_mesh(0, (200, 200));
+        let pixels = mesh.vertices.iter().map(|v| ((v.position[0] + 1.0) * 100.0, (v.position[1] + 1.0) * 100.0)).collect::<Vec<_>>();
+        let bottom = |range: std::ops::Range<f32>| pixels.iter().map(|p| p.1).filter(|y| range.contains(y)).fold(f32::MAX, f32::min);
+        let left = pixels.iter().map(|p| p.0).fold(f32::MAX, f32::min);
+        (bottom(80.0..200.0), bottom(0.0..80.0), left)
+    };
+    let (first, second, left) = baselines(&span);
+    assert_near(first, 100.3, 1e-3);
+    // Only the first line starts at the pen, the second one at the span origin
+    assert_near(left, 101.0 * 0.03, 0.05);
+
+    let (first, second_snapped, snapped_left) = baselines(&span.clone().with_baseline_snapping(true));
+    assert_near(first, 100.0, 1e-3);
+    assert_near(second_snapped, second_snapped.round(), 1e-3);
+    assert_near(second_snapped, second, 1.0);
+    // Horizontal positions stay subpixel
+    assert_near(snapped_left, left, 1e-3);
+}
+
+#[test]
+fn letter_spacing_goes_between_glyphs_only() {
+    let font = font();
+    let span = Span::new(font.face(), "HHHH", 0, 0).with_font_size(FontSize::Px(100));
+    let width = span.measure().0;
+    assert_near(width, 4.0 * 72.8, 1e-3);
+    // Three gaps between four glyphs, an em is the font size
+    assert_near(span.clone().with_letter_spacing_px(10.0).measure().0, width + 3.0 * 10.0, 1e-3);
+    assert_near(span.clone().with_letter_spacing_em(0.1).measure().0, width + 3.0 * 10.0, 1e-3);
+    assert_near(span.with_font_size(FontSize::Px(50)).with_letter_spacing_em(0.1).measure().0, width / 2.0 + 3.0 * 5.0, 1e-3);
+}
+
+#[test]
+fn truncation_inside_a_cluster_keeps_the_spacing_before_it() {
+    let font = font();
+    let spaced = |text| Span::new(font.face(), text, 0, 0).with_font_size(FontSize::Px(100)).with_letter_spacing_px(10.0);
+    // 'q' and the combining acute share a cluster, the cut after 'q' leaves 'a' spaced and 'q' without any
+    let truncated = spaced("aq\u{301}b").with_max_glyphs(2);
+    assert_near(truncated.measure().0, spaced("aq").measure().0, 1e-3);
+    // A cut between clusters takes the spacing off the new last glyph
+    let truncated = spaced("aqb").with_max_glyphs(2);
+    assert_near(truncated.measure().0, spaced("aq").measure().0, 1e-3);
+}
+
+#[test]
+fn merged_outlines_have_no_overlapping_triangles() {
+    let font = font();
+    // The bar of each 'H' runs into a stem of the other
+    let span = Span::new(font.face(), "HH", 0, 0).with_font_size(FontSize::Px(100)).with_letter_spacing_px(-50.0);
+    // Triangle area in pixels against the area actually covered
+    let areas = |span: &Span| {
+        let mesh = span.generate_text_mesh(0, (200, 120));
+        let pixel = |index: u32| {
+            let v = mesh.vertices[index as usize].position;
+            ((v[0] + 1.0) * 100.0, (v[1] + 1.0) * 60.0)
+        };
+        let triangle_area = mesh.indices.chunks_exact(3).map(|triangle| {
+            let [a, b, c] = [pixel(triangle[0]), pixel(triangle[1]), pixel(triangle[2])];
+            ((b.0 - a.0) * (c.1 - a.1) - (c.0 - a.0) * (b.1 - a.1)).abs() / 2.0
+        }).sum::<f32>();
+        (triangle_area, rasterize(&mesh, 200, 120).iter().sum::<f32>())
+    };
+    let (triangles, covered) = areas(&span.clone().with_position(10, 20));
+    // The overlaps are about 4.5% of the ink
+    assert!(triangles > covered * 1.03, "{triangles} {covered}");
+    let (triangles, merged_covered) = areas(&span.with_position(10, 20).with_merged_outline(true));
+    assert_near(triangles, merged_covered, merged_covered * 0.02);
+    assert_near(merged_covered, covered, covered * 0.01);
+}